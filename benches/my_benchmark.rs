@@ -1,5 +1,7 @@
 use criterion::Criterion;
 use criterion::{black_box, criterion_group, criterion_main};
+use needletail::parser::{write_fastq, LineEnding};
+use std::io::Write;
 use triple_accel::*;
 
 fn bc_cmp(bc: &[u8], seq: &[u8]) -> bool {
@@ -31,6 +33,79 @@ fn triple_bench(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, cmp_bench, triple_bench,);
+fn fastq_data() -> Vec<u8> {
+    let mut data = Vec::new();
+    for i in 0..10_000 {
+        data.extend_from_slice(format!("@read{} 1:N:0:1\n", i).as_bytes());
+        data.extend_from_slice(&[b'A'; 150]);
+        data.extend_from_slice(b"\n+\n");
+        data.extend_from_slice(&[b'I'; 150]);
+        data.push(b'\n');
+    }
+    data
+}
+
+// Write each record back from its parsed id, sequence and qualities
+fn reformat(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut reader = needletail::parse_fastx_reader(data).unwrap();
+    while let Some(record) = reader.next() {
+        let record = record.unwrap();
+        write_fastq(
+            record.id(),
+            &record.seq(),
+            record.qual(),
+            &mut out,
+            LineEnding::Unix,
+        )
+        .unwrap();
+    }
+    out
+}
+
+// Copy each record as found in the input
+fn copy_through(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut reader = needletail::parse_fastx_reader(data).unwrap();
+    while let Some(record) = reader.next() {
+        out.extend_from_slice(record.unwrap().all());
+        out.push(b'\n');
+    }
+    out
+}
+
+fn write_bench(c: &mut Criterion) {
+    let data = fastq_data();
+    assert_eq!(copy_through(&data), data);
+    c.bench_function("reformat records", |b| {
+        b.iter(|| reformat(black_box(&data)))
+    });
+    c.bench_function("copy-through records", |b| {
+        b.iter(|| copy_through(black_box(&data)))
+    });
+}
+
+// Write the records of the sample getting most reads of a skewed panel
+// through an output buffer of `capacity` bytes
+fn buffered_write(data: &[u8], capacity: usize) {
+    let file = tempfile::tempfile().unwrap();
+    let mut out = std::io::BufWriter::with_capacity(capacity, file);
+    for record in data.split_inclusive(|c| *c == b'\n') {
+        out.write_all(record).unwrap();
+    }
+    out.flush().unwrap();
+}
+
+fn buffer_bench(c: &mut Criterion) {
+    let data = fastq_data();
+    c.bench_function("default output buffer", |b| {
+        b.iter(|| buffered_write(black_box(&data), sabreur::utils::DEFAULT_BUFFER))
+    });
+    c.bench_function("expected-counts output buffer", |b| {
+        b.iter(|| buffered_write(black_box(&data), sabreur::utils::MAX_BUFFER))
+    });
+}
+
+criterion_group!(benches, cmp_bench, triple_bench, write_bench, buffer_bench,);
 
 criterion_main!(benches);
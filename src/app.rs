@@ -37,30 +37,95 @@ pub fn build_app() -> Command {
                          `...`\n \
                         for single-end data",
                 )
-                .required(true)
+                .required_unless_present_any(["count_barcodes", "version_json"])
                 .index(1)
-                .value_parser(is_file),
+                .value_parser(is_input),
         )
         .arg(
             Arg::new("FORWARD")
                 .help("input forward fastx file\n")
                 .long_help(
                     "Input fasta or fastq forward file if demultiplexing paired-end\n \
-                        data or to the single file in demultiplexing single-end data",
+                        data or to the single file in demultiplexing single-end data.\n \
+                        Use - to read it from stdin, only the forward file can be\n \
+                        read from stdin in paired-end mode. A directory or a\n \
+                        quoted glob pattern demultiplexes all the matching files,\n \
+                        in sorted order, into the same outputs",
                 )
-                .required(true)
+                .required_unless_present_any([
+                    "compare_panels",
+                    "count_barcodes",
+                    "barcode_seq",
+                    "version_json",
+                ])
                 .index(2)
-                .value_parser(is_file),
+                .value_parser(is_input_or_stdin),
         )
         .arg(
             Arg::new("REVERSE")
                 .help("input reverse fastx file\n")
                 .long_help(
                     "Input fasta or fastq reverse file if demultiplexing paired-end\n \
-                        data. Should be ommited in single-end mode",
+                        data. Should be ommited in single-end mode. A directory or\n \
+                        a quoted glob pattern gives as many files as FORWARD, the\n \
+                        mates of its files in sorted order, e.g. of several lanes.\n \
+                        Paired names may only differ by their 1/2 or R1/R2 token",
                 )
                 .index(3)
-                .value_parser(is_file),
+                .value_parser(is_inputs),
+        )
+        .arg(
+            Arg::new("interleaved")
+                .help("read pairs from alternating records of FORWARD")
+                .long_help(
+                    "Reads paired-end data from a single interleaved file, each\n \
+                    forward record being followed by its reverse mate, instead\n \
+                    of a REVERSE file. The barcode is searched on the forward\n \
+                    records. An odd number of records is an error.",
+                )
+                .long("interleaved")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["REVERSE", "sanity_check"]),
+        )
+        .arg(
+            Arg::new("index")
+                .help("read barcodes from an index (I1) file")
+                .long_help(
+                    "Reads barcodes from a separate index (I1) file, read in\n \
+                    step with the input files, instead of the start of the\n \
+                    forward reads. Cannot be used with --trim.",
+                )
+                .long("index")
+                .value_name("FILE")
+                .value_parser(is_input)
+                .conflicts_with("trim"),
+        )
+        .arg(
+            Arg::new("header_field")
+                .help("read barcodes from the INT-th field of read headers")
+                .long_help(
+                    "Reads barcodes from the read headers instead of the start\n \
+                    of the forward reads. The header is split on colons and\n \
+                    spaces and the INT-th field, counting from 1, is matched\n \
+                    against the barcodes.",
+                )
+                .long("header-field")
+                .value_name("INT")
+                .value_parser(value_parser!(usize))
+                .conflicts_with_all(["index", "header_regex", "trim"]),
+        )
+        .arg(
+            Arg::new("header_regex")
+                .help("read barcodes from read headers with a regex")
+                .long_help(
+                    "Reads barcodes from the read headers instead of the start\n \
+                    of the forward reads. The first capture group of the\n \
+                    regular expression, or the whole match when it has none,\n \
+                    is matched against the barcodes.",
+                )
+                .long("header-regex")
+                .value_name("REGEX")
+                .conflicts_with_all(["index", "trim"]),
         )
         .arg(
             Arg::new("mismatch")
@@ -72,6 +137,300 @@ pub fn build_app() -> Command {
                 .value_parser(value_parser!(u8))
                 .default_value("0"),
         )
+        .arg(
+            Arg::new("edit_distance")
+                .help("count indels as mismatches (Levenshtein distance)")
+                .long_help(
+                    "Matches barcodes using the Levenshtein (edit) distance instead\n \
+                    of the Hamming distance, so that insertions and deletions in\n \
+                    the barcode region count as mismatches. Reads are then compared\n \
+                    on up to --mismatch bases past the barcode length.",
+                )
+                .long("edit-distance")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("min_exact_run")
+                .help("minimum run of barcode bases matching exactly")
+                .long_help(
+                    "Only assigns a read to a barcode when at least this many\n \
+                    consecutive barcode bases match exactly, even within the\n \
+                    mismatch budget. With --edit-distance the run can sit at\n \
+                    any offset of the read start.",
+                )
+                .long("min-exact-run")
+                .value_name("INT")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("rc")
+                .help("also match the reverse complement of barcodes")
+                .long_help(
+                    "Also matches the reverse complement of each barcode, a\n \
+                    read matching either orientation going to the barcode\n \
+                    files. The number of reads matched in reverse complement\n \
+                    is reported at the end of the run.",
+                )
+                .long("rc")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tie_break")
+                .help("how to pick among equally close barcodes")
+                .long_help(
+                    "Chooses the barcode assigned to a read matching several\n \
+                    barcodes at the same distance:\n \
+                        unknown: none, the read is written to the unknown\n \
+                        files and counted as ambiguous\n \
+                        lexical: the first barcode in alphabetical order\n \
+                        random: a barcode drawn at random, see --seed",
+                )
+                .long("tie-break")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new([
+                    "unknown", "lexical", "random",
+                ]))
+                .default_value("unknown"),
+        )
+        .arg(
+            Arg::new("seed")
+                .help("seed of --tie-break random and --shuffle")
+                .long_help(
+                    "Seed of the random generator used by --tie-break random\n \
+                    and --shuffle. The same seed gives the same assignments\n \
+                    and read orders. If not given, a seed is drawn and logged.",
+                )
+                .long("seed")
+                .value_name("INT")
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("position")
+                .help("end of the reads holding the barcode")
+                .long_help(
+                    "End of the reads holding the barcode: 5 for their start,\n \
+                    the default, or 3 for their end. With 3, --trim removes\n \
+                    the barcode from the end of the reads, and reads shorter\n \
+                    than a barcode are unknown.",
+                )
+                .long("position")
+                .value_name("INT")
+                .value_parser(clap::builder::PossibleValuesParser::new(["5", "3"]))
+                .default_value("5"),
+        )
+        .arg(
+            Arg::new("offset")
+                .help("skip INT bases before the barcode")
+                .long_help(
+                    "Number of bases, e.g. a constant spacer or primer,\n \
+                    preceding the barcode in the reads (following it with\n \
+                    --position 3). They are skipped when matching and trimmed\n \
+                    with the barcode by --trim.",
+                )
+                .long("offset")
+                .value_name("INT")
+                .value_parser(value_parser!(usize))
+                .default_value("0"),
+        )
+        .arg(
+            Arg::new("trim")
+                .help("trim barcode from reads")
+                .long_help(
+                    "Removes the matched barcode bases (and qualities for fastq)\n \
+                    from the written reads. In paired-end mode only the forward\n \
+                    read is trimmed. Unknown reads are written untrimmed.",
+                )
+                .short('t')
+                .long("trim")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("trim_length")
+                .help("number of bases to trim instead of the barcode length")
+                .long_help(
+                    "Trims INT bases from the matched reads instead of the\n \
+                    length of their barcode, e.g. to also remove a constant\n \
+                    spacer following the barcode. Reads shorter than INT\n \
+                    are written empty.",
+                )
+                .long("trim-length")
+                .value_name("INT")
+                .value_parser(value_parser!(usize))
+                .requires("trim"),
+        )
+        .arg(
+            Arg::new("umi_len")
+                .help("move INT bases following the barcode to the read id")
+                .long_help(
+                    "Removes the INT bases following the barcode (or the\n \
+                    trimmed bases with --trim) of matched reads and appends\n \
+                    them to the read name as :UMI=<bases>. In paired-end mode\n \
+                    the UMI is taken from the forward read and tags both mates.",
+                )
+                .long("umi-len")
+                .value_name("INT")
+                .value_parser(value_parser!(usize))
+                .conflicts_with_all(["index", "header_field", "header_regex"]),
+        )
+        .arg(
+            Arg::new("trim_polyg")
+                .help("trim trailing poly-G runs of at least MINLEN [default: 10]")
+                .long_help(
+                    "Removes trailing runs of at least MINLEN G, and their\n \
+                    qualities, from written reads. These poly-G tails come\n \
+                    from read-through on two-color chemistry (NovaSeq,\n \
+                    NextSeq). MINLEN defaults to 10.",
+                )
+                .long("trim-polyg")
+                .value_name("MINLEN")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("10")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("min_len")
+                .help("drop reads shorter than INT once trimmed")
+                .long_help(
+                    "Drops the reads shorter than INT bases once trimmed, they\n \
+                    are counted as too_short instead of being written. In\n \
+                    paired-end mode a pair is dropped when either mate is\n \
+                    too short.",
+                )
+                .long("min-len")
+                .value_name("INT")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("expect_length")
+                .help("drop reads not of length N, give or take TOL")
+                .long_help(
+                    "Drops the reads whose length is not N, or not within TOL\n \
+                    bases of N with N±TOL (or N+-TOL), before matching them.\n \
+                    They are counted as length_anomaly instead of being\n \
+                    written. In paired-end mode a pair is dropped when either\n \
+                    mate has an unexpected length.",
+                )
+                .long("expect-length")
+                .value_name("N[±TOL]")
+                .value_parser(|s: &str| {
+                    sabreur::utils::parse_expected_length(s).map_err(|e| e.to_string())
+                }),
+        )
+        .arg(
+            Arg::new("min_qual")
+                .help("drop matched reads with a mean quality below INT")
+                .long_help(
+                    "Drops the matched reads whose mean phred quality is below\n \
+                    INT, they are counted as low_quality instead of being\n \
+                    written. In paired-end mode a pair is dropped when either\n \
+                    mate is below INT. Needs fastq input.",
+                )
+                .long("min-qual")
+                .value_name("INT")
+                .value_parser(value_parser!(u8)),
+        )
+        .arg(
+            Arg::new("normalize_mate_ids")
+                .help("write the same read id for both mates")
+                .long_help(
+                    "Rewrites the id of written reads to the read name only,\n \
+                    dropping its description and any /1 or /2 mate suffix,\n \
+                    so that both mates of a pair share the same id.",
+                )
+                .long("normalize-mate-ids")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("keep_barcode_in_id")
+                .help("append the assigned barcode to read ids")
+                .long_help(
+                    "Appends the barcode a read is assigned to, as found in the\n \
+                    barcode file, to the id of written reads as a SAM-style\n \
+                    tag following a tab, e.g. `@read1<TAB>BC:Z:ACCGTA`. Both mates\n \
+                    are tagged in paired-end mode, unknown reads are not.",
+                )
+                .long("keep-barcode-in-id")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("annotate_distance")
+                .help("append the distance to the barcode to read ids")
+                .long_help(
+                    "Appends the distance of a read to its barcode, the number\n \
+                    of mismatches or edits, to the id of written reads as a\n \
+                    SAM-style tag following a tab, e.g. `@read1<TAB>mm:i:1`,\n \
+                    to debug barcode designs. Both mates are tagged in\n \
+                    paired-end mode, unknown reads are not.",
+                )
+                .long("annotate-distance")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sanity_check")
+                .help("check on the first pairs that the inputs look paired")
+                .long_help(
+                    "Reads the first 1000 pairs before demultiplexing and stops\n \
+                    if most of them have different read names in the forward\n \
+                    and reverse files, or if barcodes are found in the reverse\n \
+                    reads rather than the forward ones. Inputs read from\n \
+                    stdin or a pipe cannot be checked.",
+                )
+                .long("sanity-check")
+                .action(ArgAction::SetTrue)
+                .requires("REVERSE"),
+        )
+        .arg(
+            Arg::new("barcode_seq")
+                .help("barcode given on the command line, repeatable")
+                .long_help(
+                    "Demultiplexes this barcode without a barcode file, its\n \
+                    output file being given by the --out-name of the same rank.\n \
+                    Repeat both options for several barcodes. The file\n \
+                    arguments are then only the reads, forward then reverse.",
+                )
+                .long("barcode-seq")
+                .value_name("SEQ")
+                .action(ArgAction::Append)
+                .requires("out_name"),
+        )
+        .arg(
+            Arg::new("out_name")
+                .help("output file of the --barcode-seq of the same rank")
+                .long_help(
+                    "Output file of the --barcode-seq of the same rank. In\n \
+                    paired-end mode both output files are given as R1,R2, e.g.\n \
+                    sample_R1.fq,sample_R2.fq.",
+                )
+                .long("out-name")
+                .value_name("NAME")
+                .action(ArgAction::Append)
+                .requires("barcode_seq"),
+        )
+        .arg(
+            Arg::new("delimiter")
+                .help("barcode file field delimiter")
+                .long_help(
+                    "Field delimiter of the barcode file. By default fields are\n \
+                    split at tabs, or at commas for a file without any tab,\n \
+                    e.g. a sheet saved as CSV.",
+                )
+                .long("delimiter")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new(["tab", "comma"])),
+        )
+        .arg(
+            Arg::new("split_outputs")
+                .help("read paired-end outputs from a single R1,R2 field")
+                .long_help(
+                    "Reads the forward and reverse output file names of a\n \
+                    paired-end barcode file from a single comma-joined\n \
+                    field, e.g. sampleA_R1.fq,sampleA_R2.fq.",
+                )
+                .long("split-outputs")
+                .requires("REVERSE")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("output")
                 .help("ouput directory")
@@ -100,6 +459,77 @@ pub fn build_app() -> Command {
                 .value_parser(clap::builder::PossibleValuesParser::new(["gz", "xz", "bz2", "zst"]))
                 .hide_possible_values(true),
         )
+        .arg(
+            Arg::new("reverse_format")
+                .help("reverse output files compression format")
+                .long_help(
+                    "Specifies the compression format of the reverse output\n \
+                    files in paired-end mode, taking gz, xz, bz2, zst or none.\n \
+                    Without it reverse outputs take the --format compression,\n \
+                    or the one sniffed from the reverse input when --format is\n \
+                    not given either, independently of the forward outputs.",
+                )
+                .long("reverse-format")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new([
+                    "gz", "xz", "bz2", "zst", "none",
+                ]))
+                .hide_possible_values(true),
+        )
+        .arg(
+            Arg::new("ubam")
+                .help("write unaligned BAM outputs")
+                .long_help(
+                    "Writes each sample, and the unknown reads, as an unaligned\n \
+                    BAM file named after its barcode file name with a .bam\n \
+                    extension. Records carry their barcode in the BC tag.\n \
+                    Requires sabreur to be built with the `ubam` feature.",
+                )
+                .long("ubam")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["format", "reverse_format", "min_reads"]),
+        )
+        .arg(
+            Arg::new("s3")
+                .help("upload outputs to an S3 bucket")
+                .long_help(
+                    "Uploads each output to the BUCKET object store, named after\n \
+                    its file under the optional PREFIX, instead of writing it\n \
+                    to the output directory. Credentials are read from\n \
+                    AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY and\n \
+                    AWS_SESSION_TOKEN, the region from AWS_REGION and an\n \
+                    S3-compatible endpoint from AWS_ENDPOINT_URL. Empty\n \
+                    outputs are uploaded too. Requires sabreur to be built\n \
+                    with the `s3` feature.",
+                )
+                .long("s3")
+                .value_name("BUCKET/PREFIX")
+                .conflicts_with_all(["ubam", "append", "grouped_output", "shuffle", "min_reads"]),
+        )
+        .arg(
+            Arg::new("trust_ext")
+                .help("take input compression from file extensions")
+                .long_help(
+                    "Takes the compression of the input files from their\n \
+                    extension (.gz, .bz2, .xz, .zst, or .fa, .fasta, .fq,\n \
+                    .fastq for none) instead of reading their first bytes.\n \
+                    Files without such an extension are still sniffed.",
+                )
+                .long("trust-ext")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("input_format")
+                .help("parse the input reads as fasta or fastq")
+                .long_help(
+                    "Parses the input reads as this format instead of the one\n \
+                    of their first record, stopping on records of the other\n \
+                    format. The unknown files get its extension.",
+                )
+                .long("input-format")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new(["fasta", "fastq"])),
+        )
         .arg(
             Arg::new("level")
                 .help("compression level")
@@ -118,27 +548,559 @@ pub fn build_app() -> Command {
                 .long("level")
                 .short('l')
                 .value_name("INT")
-                .value_parser(value_parser!(u8))
+                .value_parser(value_parser!(u8).range(1..=9))
                 .hide_possible_values(true)
                 .default_value("1"),
         )
+        .arg(
+            Arg::new("zstd_level")
+                .help("compression level of zstd outputs, up to 22")
+                .long_help(
+                    "Compression level of the zstd output files, from 1 to 22,\n \
+                    instead of --level. Levels above 19 need much more memory.",
+                )
+                .long("zstd-level")
+                .value_name("INT")
+                .value_parser(value_parser!(i32).range(1..=22)),
+        )
+        .arg(
+            Arg::new("zstd_long")
+                .help("enable zstd long distance matching")
+                .long_help(
+                    "Enables the long distance matching of zstd on the zstd\n \
+                    output files, finding repeats up to 128 MiB apart. Large\n \
+                    files of similar reads compress better, at the cost of\n \
+                    memory.",
+                )
+                .long("zstd-long")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("gzip_threads")
+                .help("number of threads compressing gzip outputs")
+                .long_help(
+                    "Number of threads compressing the gzip output files,\n \
+                    shared by all of them. Above 1, outputs are deflated by\n \
+                    blocks in parallel into a single gzip member, independently\n \
+                    of --threads.",
+                )
+                .long("gzip-threads")
+                .value_name("INT")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("expected_counts")
+                .help("size output buffers from expected read counts")
+                .long_help(
+                    "Reads the expected number of reads of each barcode from a\n \
+                    tab-separated file, a barcode and a count on each line\n \
+                    (XXX for the unknown reads). Outputs expected to get more\n \
+                    reads are given larger write buffers, up to 1 MiB, which\n \
+                    spares writes on skewed panels. Outputs are unchanged.",
+                )
+                .long("expected-counts")
+                .value_name("FILE")
+                .value_parser(is_input),
+        )
+        .arg(
+            Arg::new("flush_policy")
+                .help("when output buffers are written out")
+                .long_help(
+                    "Sets when the output buffers are written to the files:\n \
+                        lazy: once full\n \
+                        eager: after each read, through the compression, which\n \
+                        keeps little in memory but compresses less\n \
+                        lru: once full, or the least recently written first\n \
+                        once all buffers hold more than --max-memory bytes,\n \
+                        16 MiB by default",
+                )
+                .long("flush-policy")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new([
+                    "lazy", "eager", "lru",
+                ]))
+                .default_value("lazy"),
+        )
+        .arg(
+            Arg::new("grouped_output")
+                .help("write all sample reads to a single FILE")
+                .long_help(
+                    "Writes the reads of all samples to FILE in the output\n \
+                    directory, grouped by sample in barcode file order, instead\n \
+                    of one file per sample. Reads are spilled to a temporary\n \
+                    file per sample and merged at the end, which bounds memory.\n \
+                    Unknown reads keep their own file. Single-end mode only.",
+                )
+                .long("grouped-output")
+                .value_name("FILE")
+                .conflicts_with_all(["REVERSE", "interleaved", "ubam", "min_reads"]),
+        )
+        .arg(
+            Arg::new("shuffle")
+                .help("shuffle the reads of each sample")
+                .long_help(
+                    "Writes the reads of each sample in a random order, the\n \
+                    same for both mates, seeded by --seed. Reads are shuffled\n \
+                    in memory, or spilled to temporary files first when a\n \
+                    sample output holds more than --max-memory bytes.",
+                )
+                .long("shuffle")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("ubam"),
+        )
+        .arg(
+            Arg::new("tmpdir")
+                .help("directory of temporary files")
+                .long_help(
+                    "Directory of the temporary files of --grouped-output and\n \
+                    --shuffle, removed once used. Defaults to the system\n \
+                    temporary directory.",
+                )
+                .long("tmpdir")
+                .value_name("DIR")
+                .value_parser(value_parser!(PathBuf)),
+        )
         .arg(
             Arg::new("force")
                 .help("force reuse of output directory")
                 .long_help(
                     "Reuse the default output directory (sabreur_out).\n \
-                    This will erase existing directory before creating it.",
+                    This removes the whole existing directory, with all its\n \
+                    files, before creating it again.",
                 )
                 .action(ArgAction::SetTrue)
                 .long("force")
         )
+        .arg(
+            Arg::new("append")
+                .help("append to the files of an existing output directory")
+                .long_help(
+                    "Writes into the output directory if it already exists,\n \
+                    appending the reads to its files of the same name, e.g. to\n \
+                    gather several runs. Without it, an existing directory is\n \
+                    an error unless --force is given. Empty unknown files are\n \
+                    kept.",
+                )
+                .long("append")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["force", "grouped_output", "min_reads", "ubam"]),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .help("check inputs and print the planned outputs")
+                .long_help(
+                    "Reads and validates the barcode file, sniffs the input\n \
+                    formats and prints the output files that would be written\n \
+                    for each barcode with their compression, then exits without\n \
+                    creating the output directory.",
+                )
+                .long("dry-run")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("count_only")
+                .help("count reads per barcode without writing them")
+                .long_help(
+                    "Matches and counts all reads, logging the counts and\n \
+                    writing --stats-tsv and --report-json, but writes no read\n \
+                    and does not create the output directory. Handy to check\n \
+                    the barcode balance of a subsample.",
+                )
+                .long("count-only")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "dry_run",
+                    "grouped_output",
+                    "shuffle",
+                    "min_reads",
+                    "unknown_split",
+                ]),
+        )
+        .arg(
+            Arg::new("keep_empty_outputs")
+                .help("keep output files which received no read")
+                .long_help(
+                    "Keeps every declared output file on disk, even when no\n \
+                    read was written to it. By default empty unknown files\n \
+                    are removed.",
+                )
+                .long("keep-empty-outputs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("unknown_prefix")
+                .help("base name of the unknown output files")
+                .long_help(
+                    "Base name of the unknown output files, followed by _R1 and\n \
+                    _R2 in paired-end mode and by the extension of the input\n \
+                    format, .fa or .fq.",
+                )
+                .long("unknown-prefix")
+                .value_name("NAME")
+                .default_value("unknown"),
+        )
+        .arg(
+            Arg::new("interleaved_unknown")
+                .help("write unknown pairs to a single interleaved file")
+                .long_help(
+                    "Writes the unknown pairs to a single unknown file\n \
+                    alternating forward and reverse mates, instead of\n \
+                    unknown_R1 and unknown_R2 files. Paired-end mode only.",
+                )
+                .long("interleaved-unknown")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["no_unknown", "ubam"]),
+        )
+        .arg(
+            Arg::new("only")
+                .help("demultiplex only the named samples")
+                .long_help(
+                    "Writes outputs only for the named samples, given by\n \
+                    barcode or output file name, with or without extension,\n \
+                    e.g. --only sample1,sample2. The reads of the other\n \
+                    samples are written as unknown. The whole input is read.",
+                )
+                .long("only")
+                .value_name("SAMPLES")
+                .value_delimiter(','),
+        )
+        .arg(
+            Arg::new("discard_others")
+                .help("discard the reads of samples left out by --only")
+                .long_help(
+                    "Counts the reads of the samples left out by --only\n \
+                    without writing them, instead of writing them as unknown.",
+                )
+                .long("discard-others")
+                .requires("only")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("no_unknown")
+                .help("count unknown reads without writing them")
+                .long_help(
+                    "Discards the reads matching no barcode: no unknown file\n \
+                    is written, the discarded reads are still counted in the\n \
+                    logs and reports.",
+                )
+                .long("no-unknown")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["unknown_split", "unknown_ids"]),
+        )
+        .arg(
+            Arg::new("head")
+                .help("only process the first INT reads")
+                .long_help(
+                    "Stops after the first INT reads, or pairs in paired-end\n \
+                    mode, of each forward file, e.g. for a quick check of a\n \
+                    run. Counts and reports cover the processed reads only.",
+                )
+                .long("head")
+                .value_name("INT")
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("threads")
+                .help("number of threads matching reads")
+                .long_help(
+                    "Number of threads matching single-end reads against the\n \
+                    barcodes. Reads are parsed and written by their own\n \
+                    threads and outputs are the same as with a single one.",
+                )
+                .long("threads")
+                .value_name("INT")
+                .value_parser(value_parser!(u16).range(1..))
+                .default_value("1"),
+        )
+        .arg(
+            Arg::new("max_memory")
+                .help("bound reads buffered between threads to SIZE")
+                .long_help(
+                    "Bounds the reads buffered between the --threads threads to\n \
+                    SIZE bytes, with an optional K, M or G suffix. Reading\n \
+                    waits for the outputs to catch up when over it, e.g. on\n \
+                    panels where one sample gets most reads. Also bounds the\n \
+                    output buffers with --flush-policy lru.",
+                )
+                .long("max-memory")
+                .value_name("SIZE")
+                .value_parser(|s: &str| sabreur::utils::parse_size(s).map_err(|e| e.to_string())),
+        )
+        .arg(
+            Arg::new("compare_panels")
+                .help("compare BARCODE with another barcode file and exit")
+                .long_help(
+                    "Compares the BARCODE file with the given barcode file and\n \
+                    reports barcodes added, removed, or whose output files\n \
+                    changed in it, then exits without demultiplexing.",
+                )
+                .long("compare-panels")
+                .value_name("FILE")
+                .value_parser(is_file),
+        )
+        .arg(
+            Arg::new("count_barcodes")
+                .help("count the LEN-base read prefixes and exit")
+                .long_help(
+                    "Counts the first LEN bases of every read and prints the 20\n \
+                    most frequent ones with their number of reads, then exits\n \
+                    without demultiplexing. This helps building a barcode file\n \
+                    for an unknown input: no barcode file is needed and the\n \
+                    reads are given as the only file argument.",
+                )
+                .long("count-barcodes")
+                .value_name("LEN")
+                .value_parser(value_parser!(usize))
+                .conflicts_with("compare_panels"),
+        )
+        .arg(
+            Arg::new("count_bounds")
+                .help("fail if sample read counts are out of bounds")
+                .long_help(
+                    "Reads the allowed read count range of barcodes from a\n \
+                    tab-separated file, a barcode, a minimum and a maximum on\n \
+                    each line (- for no bound). Once demultiplexed, sabreur\n \
+                    exits with an error listing the samples whose read count\n \
+                    is out of its range. Outputs are kept.",
+                )
+                .long("count-bounds")
+                .value_name("FILE")
+                .value_parser(is_input),
+        )
+        .arg(
+            Arg::new("min_reads")
+                .help("pool samples with less than INT reads")
+                .long_help(
+                    "After demultiplexing, samples with less than INT reads are\n \
+                    moved to a low_count_pool file (low_count_pool_R1 and\n \
+                    low_count_pool_R2 in paired-end mode) instead of keeping\n \
+                    tiny per-sample files.",
+                )
+                .long("min-reads")
+                .value_name("INT")
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            Arg::new("stats_tsv")
+                .help("write per-barcode read counts to a TSV file")
+                .long_help(
+                    "Writes a tab-separated table with the columns barcode,\n \
+                    forward_file, reverse_file, read_count and percent_of_total,\n \
+                    sorted by decreasing count with unknown reads (XXX) last.",
+                )
+                .long("stats-tsv")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("unknown_split")
+                .help("split unknown reads by format or length")
+                .long_help(
+                    "Writes the unknown reads to one file per bucket instead of\n \
+                    the unknown files, pairs going by their forward mate:\n \
+                        format: unknown_fasta and unknown_fastq\n \
+                        length: unknown_len0-19, unknown_len20-49,\n \
+                        unknown_len50-99, unknown_len100-199 and\n \
+                        unknown_len200+",
+                )
+                .long("unknown-split")
+                .value_name("STR")
+                .value_parser(clap::builder::PossibleValuesParser::new(["format", "length"])),
+        )
+        .arg(
+            Arg::new("unknown_ids")
+                .help("write the ids of unknown reads to a file")
+                .long_help(
+                    "Writes the id of each read written to the unknown files,\n \
+                    one per line. The file is compressed according to its\n \
+                    extension (.gz, .bz2, .xz or .zst).",
+                )
+                .long("unknown-ids")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("confidence")
+                .help("write the confidence of each read assignment to a file")
+                .long_help(
+                    "Writes one tab-separated line per matched read with its id,\n \
+                    barcode, distance, margin to the next closest barcode and\n \
+                    confidence: the margin over the barcode length, from 0 for\n \
+                    a tie to 1.",
+                )
+                .long("confidence")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("tee_decompressed")
+                .help("write a decompressed copy of the input to a file")
+                .long_help(
+                    "Writes the decompressed content of FORWARD to a file as it\n \
+                    is read for demultiplexing, sparing a separate\n \
+                    decompression. Several forward files are concatenated.\n \
+                    Not available with a REVERSE file.",
+                )
+                .long("tee-decompressed")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf))
+                .conflicts_with("REVERSE"),
+        )
+        .arg(
+            Arg::new("panel_hash")
+                .help("write the hash of the panel to a file")
+                .long_help(
+                    "Writes a hash of the resolved panel, its barcodes and\n \
+                    output names, to a file. Two runs with the same hash used\n \
+                    the same panel. The order of the barcode file lines does\n \
+                    not change the hash. The hash is also logged.",
+                )
+                .long("panel-hash")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("run_info")
+                .help("write a JSON summary of the run to a file")
+                .long_help(
+                    "Writes a JSON summary of the run including the time spent\n \
+                    in each phase: panel_parsing, output_setup,\n \
+                    demultiplexing and finalization.",
+                )
+                .long("run-info")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("report_json")
+                .help("write a JSON report of the run to a file")
+                .long_help(
+                    "Writes a single JSON document with a schema version, the\n \
+                    run metadata and phase timings, the read count and output\n \
+                    files (path and size in bytes) of each sample, and the\n \
+                    unknown reads with their ambiguous part.",
+                )
+                .long("report-json")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("manifest")
+                .help("write the paths of the output files to a file")
+                .long_help(
+                    "Writes a TSV file of the barcode, forward and reverse\n \
+                    output files of each sample, as absolute paths, for use by\n \
+                    pipelines. The unknown files are on the last row, with the\n \
+                    XXX barcode. Discarded outputs and those removed at the\n \
+                    end of the run, empty unknown files or pooled samples, are\n \
+                    not listed.",
+                )
+                .long("manifest")
+                .value_name("FILE")
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            Arg::new("live_stats")
+                .help("emit running counts as NDJSON every INT records")
+                .long_help(
+                    "Periodically emits the current per-barcode read counts as\n \
+                    one NDJSON line every INT processed records. Lines are\n \
+                    written to stdout unless --live-socket is given.",
+                )
+                .long("live-stats")
+                .value_name("INT")
+                .value_parser(value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("live_socket")
+                .help("unix socket receiving --live-stats lines")
+                .long("live-socket")
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .requires("live_stats"),
+        )
+        .arg(
+            Arg::new("log")
+                .help("log file")
+                .long("log")
+                .value_name("PATH")
+                .value_parser(value_parser!(PathBuf))
+                .default_value("sabreur.log"),
+        )
+        .arg(
+            Arg::new("no_log_file")
+                .help("do not write a log file")
+                .long_help("Only log to stdout, and syslog with --syslog.")
+                .long("no-log-file")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("syslog")
+                .help("also send logs to syslog")
+                .long_help(
+                    "Also send log messages to the local syslog daemon.\n \
+                    Requires sabreur to be built with the `syslog` feature.",
+                )
+                .long("syslog")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("quiet")
-                .long_help("decrease program verbosity")
+                .long_help(
+                    "decrease program verbosity, also hides the progress bar\n \
+                    drawn when stderr is a terminal",
+                )
                 .short('q')
                 .long("quiet")
                 .action(ArgAction::SetTrue)
         )
+        .arg(
+            Arg::new("summary")
+                .help("stay quiet but print the final counts table")
+                .long_help(
+                    "Runs as quietly as --quiet, then prints the table of the\n \
+                    read count of each barcode, as written by --stats-tsv, to\n \
+                    stdout.",
+                )
+                .long("summary")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("version_json")
+                .help("print version and build features as JSON and exit")
+                .long("version-json")
+                .action(ArgAction::SetTrue)
+                .hide(true),
+        )
+}
+
+// Input files can also be http(s) urls when built with the http feature,
+// or named pipes, e.g. from a process substitution
+fn is_input(s: &str) -> Result<String, String> {
+    let is_pipe = s != sabreur::utils::STDIN && sabreur::utils::is_stream(s);
+    if (cfg!(feature = "http") && sabreur::utils::is_url(s)) || is_pipe {
+        Ok(s.to_string())
+    } else {
+        is_file(s)
+    }
+}
+
+// Several input files can be given by a directory or a glob pattern
+fn is_inputs(s: &str) -> Result<String, String> {
+    if sabreur::utils::is_glob(s) || Path::new(s).is_dir() {
+        Ok(s.to_string())
+    } else {
+        is_input(s)
+    }
+}
+
+fn is_input_or_stdin(s: &str) -> Result<String, String> {
+    if s == sabreur::utils::STDIN {
+        Ok(s.to_string())
+    } else {
+        is_inputs(s)
+    }
 }
 
 fn is_file(s: &str) -> Result<String, String> {
@@ -157,4 +1119,64 @@ mod tests {
     fn verify_cmd() {
         build_app().debug_assert();
     }
+
+    #[test]
+    fn forward_from_stdin() {
+        let app = build_app();
+        assert!(app
+            .clone()
+            .try_get_matches_from(["sabreur", "tests/bc_se.txt", "-"])
+            .is_ok());
+        assert!(app
+            .try_get_matches_from(["sabreur", "tests/bc_pe_fa.txt", "tests/reads_1.fa", "-"])
+            .is_err());
+    }
+
+    #[test]
+    fn version_json_alone() {
+        assert!(build_app()
+            .try_get_matches_from(["sabreur", "--version-json"])
+            .is_ok());
+    }
+
+    #[test]
+    fn count_barcodes_without_panel() {
+        assert!(build_app()
+            .try_get_matches_from(["sabreur", "--count-barcodes", "8", "tests/test.fq"])
+            .is_ok());
+    }
+
+    #[test]
+    fn level_out_of_range() {
+        let app = build_app();
+        assert!(app
+            .clone()
+            .try_get_matches_from(["sabreur", "-l", "9", "tests/bc_se.txt", "tests/test.fq"])
+            .is_ok());
+        assert!(app
+            .try_get_matches_from(["sabreur", "-l", "12", "tests/bc_se.txt", "tests/test.fq"])
+            .is_err());
+    }
+
+    #[test]
+    fn log_file() {
+        let matches = build_app()
+            .try_get_matches_from(["sabreur", "tests/bc_se.txt", "tests/test.fq"])
+            .unwrap();
+        assert_eq!(
+            matches.get_one::<PathBuf>("log"),
+            Some(&PathBuf::from("sabreur.log"))
+        );
+        assert!(!matches.get_flag("no_log_file"));
+
+        let matches = build_app()
+            .try_get_matches_from([
+                "sabreur",
+                "--no-log-file",
+                "tests/bc_se.txt",
+                "tests/test.fq",
+            ])
+            .unwrap();
+        assert!(matches.get_flag("no_log_file"));
+    }
 }
@@ -3,336 +3,3836 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
+use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::thread;
 
-use crate::utils::{bc_cmp, write_seqs};
+use anyhow::{anyhow, Context};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-pub type Barcode<'a> = HashMap<&'a [u8], Vec<std::fs::File>>;
+use crate::utils::{
+    hamming_distance, kept_range, levenshtein_distance, longest_common_run, longest_exact_run,
+    mean_quality, normalize_mate_id, open_counted_input, open_input, write_seqs, ByteCounter,
+    FastxRecord, LiveStats, MemoryBudget, OpenedInputs, Output, TeeSink,
+};
+
+/// Output writers of each barcode, kept open for the whole run so that
+/// compressed outputs are a single stream. The unknown (`XXX`) writers can
+/// be followed by one more writer receiving the ids of unknown reads. A
+/// [`CONFIDENCE`] entry can hold a writer receiving the confidence of each
+/// assignment.
+pub type Barcode<'a> = HashMap<&'a [u8], Vec<Box<dyn Output>>>;
+
+/// Matching and output settings shared by the demultiplexing functions
+pub struct DemuxOptions {
+    /// Maximum number of mismatches allowed in a barcode
+    pub mismatch: u8,
+    /// Barcodes with their own maximum number of mismatches
+    pub barcode_mismatch: HashMap<Vec<u8>, u8>,
+    /// Barcodes whose reads lose their 3' bases of quality below a minimum
+    pub barcode_min_quality: HashMap<Vec<u8>, u8>,
+    /// Count insertions and deletions, not only substitutions, as mismatches
+    pub edit_distance: bool,
+    /// Only assign reads matching at least this many consecutive barcode
+    /// bases exactly
+    pub min_exact_run: Option<usize>,
+    /// Reverse complement of each barcode, also matched against the reads
+    pub revcomp_barcodes: HashMap<Vec<u8>, Vec<u8>>,
+    /// Match barcodes against the end (3') of the reads rather than their
+    /// start (5'), trimming them from the end too
+    pub three_prime: bool,
+    /// Number of bases, e.g. a constant spacer, preceding the barcode in
+    /// the reads. They are trimmed along with the barcode.
+    pub offset: usize,
+    /// Remove the matched barcode from the written reads
+    pub trim: bool,
+    /// Number of bases to trim instead of the matched barcode length
+    pub trim_length: Option<usize>,
+    /// Move this many bases following the barcode to the read id as a UMI
+    pub umi_len: Option<usize>,
+    /// Drop the reads shorter than this once trimmed
+    pub min_len: Option<usize>,
+    /// Drop the matched reads with a lower mean phred quality
+    pub min_mean_quality: Option<u8>,
+    /// Drop the reads, or pairs, whose length is outside this range before
+    /// matching them
+    pub expect_length: Option<RangeInclusive<usize>>,
+    /// Write the unknown reads to one file per bucket instead of a single
+    /// one
+    pub unknown_split: Option<UnknownSplit>,
+    /// Remove trailing poly-G runs of at least this length from written reads
+    pub trim_polyg: Option<usize>,
+    /// Write the same read id, without mate suffix, for both mates
+    pub normalize_ids: bool,
+    /// Append the assigned barcode to the id of written reads as a SAM-style
+    /// `BC:Z:` tag
+    pub keep_barcode_in_id: bool,
+    /// Append the distance of written reads to their barcode to their id
+    /// as a SAM-style `mm:i:` tag
+    pub annotate_distance: bool,
+    /// How to choose among barcodes tied at the smallest distance
+    pub tie_break: TieBreak,
+    /// Parse the reads as this format rather than as the format of their
+    /// first record
+    pub input_format: Option<needletail::parser::Format>,
+    /// Index (I1) file holding the barcodes, read in step with the reads,
+    /// instead of the start of the forward reads
+    pub index: Option<String>,
+    /// Read paired-end mates from alternating records of the forward file
+    /// rather than from the reverse file
+    pub interleaved: bool,
+    /// Stop after this many reads, or pairs, of each input
+    pub head: Option<usize>,
+    /// Number of threads matching single-end reads against barcodes
+    pub threads: usize,
+    /// Bound on the bytes of reads buffered between threads, reading
+    /// waits for writing to catch up when over it
+    pub max_memory: Option<usize>,
+    /// Where to find the barcode in the read header, instead of the start
+    /// of the forward reads
+    pub header_barcode: Option<HeaderBarcode>,
+    /// Counter of the bytes read from the forward input, e.g. for a
+    /// progress bar
+    pub progress: Option<ByteCounter>,
+    /// Copy of the decompressed forward input, written as it is read
+    pub tee: Option<TeeSink>,
+    /// Inputs already opened, e.g. to sniff their compression, read
+    /// instead of opening their path again
+    pub opened: OpenedInputs,
+}
+
+impl Default for DemuxOptions {
+    fn default() -> Self {
+        DemuxOptions {
+            mismatch: 0,
+            barcode_mismatch: HashMap::new(),
+            barcode_min_quality: HashMap::new(),
+            edit_distance: false,
+            min_exact_run: None,
+            revcomp_barcodes: HashMap::new(),
+            three_prime: false,
+            offset: 0,
+            trim: false,
+            trim_length: None,
+            umi_len: None,
+            min_len: None,
+            min_mean_quality: None,
+            expect_length: None,
+            unknown_split: None,
+            trim_polyg: None,
+            normalize_ids: false,
+            keep_barcode_in_id: false,
+            annotate_distance: false,
+            tie_break: TieBreak::Unknown,
+            input_format: None,
+            index: None,
+            interleaved: false,
+            head: None,
+            threads: 1,
+            max_memory: None,
+            header_barcode: None,
+            progress: None,
+            tee: None,
+            opened: OpenedInputs::default(),
+        }
+    }
+}
+
+impl DemuxOptions {
+    // Maximum number of mismatches allowed in this barcode
+    fn max_mismatch(&self, bc: &[u8]) -> u8 {
+        self.barcode_mismatch
+            .get(bc)
+            .copied()
+            .unwrap_or(self.mismatch)
+    }
+
+    // Minimum quality of the 3' bases of the reads of this barcode
+    fn min_quality(&self, bc: &[u8]) -> Option<u8> {
+        self.barcode_min_quality.get(bc).copied()
+    }
+
+    // Number of bases trimmed from the reads matching this barcode
+    fn trim_len(&self, bc: &[u8]) -> usize {
+        match (self.trim, self.trim_length) {
+            (false, _) => 0,
+            (true, Some(len)) => len,
+            (true, None) => self.offset + bc.len(),
+        }
+    }
+}
+
+/// Part of the read header holding the barcode
+#[derive(Clone, Debug)]
+pub enum HeaderBarcode {
+    /// 1-based index of a token of the header split on colons and spaces
+    Field(usize),
+    /// First capture group, or whole match, of a regular expression
+    Regex(regex::bytes::Regex),
+}
+
+impl HeaderBarcode {
+    /// Extract the barcode from a read header, None when it is not found
+    pub fn extract<'r>(&self, id: &'r [u8]) -> Option<&'r [u8]> {
+        match self {
+            HeaderBarcode::Field(n) => id
+                .split(|c| *c == b':' || c.is_ascii_whitespace())
+                .nth(n.checked_sub(1)?),
+            HeaderBarcode::Regex(re) => {
+                let caps = re.captures(id)?;
+                caps.get(1).or_else(|| caps.get(0)).map(|m| m.as_bytes())
+            }
+        }
+    }
+}
+
+// Sequence the barcode is searched in: the index read, the barcode in
+// the header or the forward read itself
+fn barcode_seq<'r>(
+    opts: &DemuxOptions,
+    id: &'r [u8],
+    seq: &'r [u8],
+    index_seq: Option<&'r [u8]>,
+) -> Option<&'r [u8]> {
+    match (index_seq, &opts.header_barcode) {
+        (Some(index_seq), _) => Some(index_seq),
+        (None, Some(header)) => header.extract(id),
+        (None, None) => Some(seq),
+    }
+}
+
+/// Key of the read counts holding the number of reads tied between
+/// several barcodes and left unassigned, these are also counted as unknown
+pub const AMBIGUOUS: &[u8] = b"ambiguous";
+
+/// Key of the read counts holding the number of reads matched by the
+/// reverse complement of their barcode
+pub const REVCOMP: &[u8] = b"revcomp";
+
+/// Key of the read counts holding the number of reads, or pairs, dropped
+/// for being shorter than the minimum length once trimmed
+pub const TOO_SHORT: &[u8] = b"too_short";
+
+/// Key of the read counts holding the number of matched reads, or pairs,
+/// dropped for a mean quality below the minimum
+pub const LOW_QUALITY: &[u8] = b"low_quality";
+
+/// Key of the read counts holding the number of reads, or pairs, dropped
+/// for a length outside the expected one
+pub const LENGTH_ANOMALY: &[u8] = b"length_anomaly";
+
+/// Key of the writer receiving one tab-separated line per matched read:
+/// read id, barcode, distance, margin to the next closest barcode and
+/// confidence, the margin over the barcode length
+pub const CONFIDENCE: &[u8] = b"confidence";
+
+/// Keys of the read counts which are not barcodes
+pub const EXTRA_COUNTS: [&[u8]; 5] = [AMBIGUOUS, REVCOMP, TOO_SHORT, LOW_QUALITY, LENGTH_ANOMALY];
+
+/// Is a read count key one of the extra counters or an unknown reads
+/// bucket rather than a barcode?
+pub fn is_extra_count(key: &[u8]) -> bool {
+    EXTRA_COUNTS.contains(&key)
+        || FORMAT_BUCKETS.contains(&key)
+        || LENGTH_BUCKETS.iter().any(|(_, bucket)| *bucket == key)
+}
+
+/// How to split the unknown reads into several files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownSplit {
+    /// One file per record format, fasta or fastq
+    Format,
+    /// One file per read length bucket
+    Length,
+}
+
+// Buckets of unknown reads by format, used as file stems and count keys
+const FORMAT_BUCKETS: [&[u8]; 2] = [b"unknown_fasta", b"unknown_fastq"];
+
+// Buckets of unknown reads by length, each holding the reads shorter than
+// its bound and not in a previous one
+const LENGTH_BUCKETS: [(usize, &[u8]); 5] = [
+    (20, b"unknown_len0-19"),
+    (50, b"unknown_len20-49"),
+    (100, b"unknown_len50-99"),
+    (200, b"unknown_len100-199"),
+    (usize::MAX, b"unknown_len200+"),
+];
+
+impl UnknownSplit {
+    /// All the buckets of this split, each one getting its own files
+    pub fn buckets(self) -> Vec<&'static [u8]> {
+        match self {
+            UnknownSplit::Format => FORMAT_BUCKETS.to_vec(),
+            UnknownSplit::Length => LENGTH_BUCKETS.iter().map(|(_, bucket)| *bucket).collect(),
+        }
+    }
+
+    // Bucket of an unknown read
+    fn bucket<R: FastxRecord + ?Sized>(self, record: &R) -> &'static [u8] {
+        match self {
+            UnknownSplit::Format => match record.format() {
+                needletail::parser::Format::Fasta => FORMAT_BUCKETS[0],
+                needletail::parser::Format::Fastq => FORMAT_BUCKETS[1],
+            },
+            UnknownSplit::Length => {
+                let len = record.seq().len();
+                LENGTH_BUCKETS
+                    .iter()
+                    .find(|(bound, _)| len < *bound)
+                    .map_or(LENGTH_BUCKETS[4].1, |(_, bucket)| *bucket)
+            }
+        }
+    }
+}
+
+// Writers of an unknown read: the ones of its bucket, counted as such,
+// when unknown reads are split, else the single unknown ones. None when
+// unknown reads are discarded, `barcode_data` having no `XXX` entry.
+fn unknown_writers<'b, R: FastxRecord + ?Sized>(
+    record: &R,
+    barcode_data: &'b mut Barcode<'_>,
+    opts: &DemuxOptions,
+    nb_records: &mut HashMap<&[u8], u32>,
+) -> Option<&'b mut Vec<Box<dyn Output>>> {
+    let key = match opts.unknown_split {
+        Some(split) => {
+            let bucket = split.bucket(record);
+            count_record(nb_records, bucket);
+            bucket
+        }
+        None => b"XXX",
+    };
+    barcode_data.get_mut(key)
+}
+
+/// Policy to pick a barcode among several at the same smallest distance
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TieBreak {
+    /// Assign none of them, the read goes to the unknown files
+    Unknown,
+    /// Keep the first barcode in lexical order
+    Lexical,
+    /// Draw one of the tied barcodes with a generator seeded by the value
+    Random(u64),
+}
+
+impl TieBreak {
+    // Generator used by the random policy, seeded for reproducibility
+    fn rng(&self) -> StdRng {
+        match self {
+            TieBreak::Random(seed) => StdRng::seed_from_u64(*seed),
+            TieBreak::Unknown | TieBreak::Lexical => StdRng::seed_from_u64(0),
+        }
+    }
+}
+
+// Barcodes to match reads against, longest first so that the most
+// specific barcode wins when barcodes of different lengths match
+fn candidate_barcodes<'a>(barcode_data: &Barcode<'a>) -> Vec<&'a [u8]> {
+    let mut barcodes = barcode_data
+        .keys()
+        .filter(|bc| **bc != b"XXX" && **bc != CONFIDENCE && !is_extra_count(bc))
+        .cloned()
+        .collect::<Vec<_>>();
+    barcodes.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+
+    barcodes
+}
+
+// Distance between a barcode and the start of the sequence, or its end
+// with `opts.three_prime`, past the `opts.offset` first (or last) bases.
+// None when the sequence is too short to hold the barcode or lacks an
+// exact run of `opts.min_exact_run` barcode bases.
+fn barcode_distance(bc: &[u8], seq: &[u8], opts: &DemuxOptions) -> Option<u32> {
+    let max_mismatch = opts.max_mismatch(bc);
+    let offset = opts.offset.min(seq.len());
+    let seq = match opts.three_prime {
+        true => &seq[..seq.len() - offset],
+        false => &seq[offset..],
+    };
+    if !opts.three_prime {
+        return start_distance(bc, seq, max_mismatch, opts);
+    }
+
+    // Distances are the same between both sequences reversed, the end of
+    // the read becoming its start
+    let window = bc.len() + max_mismatch as usize;
+    let bc = bc.iter().rev().copied().collect::<Vec<_>>();
+    let seq = seq[seq.len().saturating_sub(window)..]
+        .iter()
+        .rev()
+        .copied()
+        .collect::<Vec<_>>();
+    start_distance(&bc, &seq, max_mismatch, opts)
+}
+
+// Distance between a barcode allowing `max_mismatch` mismatches and the
+// start of the sequence, see barcode_distance
+fn start_distance(bc: &[u8], seq: &[u8], max_mismatch: u8, opts: &DemuxOptions) -> Option<u32> {
+    let dist = if opts.edit_distance {
+        levenshtein_distance(bc, seq, max_mismatch)
+    } else if bc.len() <= seq.len() {
+        hamming_distance(bc, &seq[..bc.len()])
+    } else {
+        return None;
+    };
+
+    let Some(min_run) = opts.min_exact_run else {
+        return Some(dist);
+    };
+    // Indels move the barcode bases in the read, runs are then searched
+    // at any offset of the compared read start
+    let run = if opts.edit_distance {
+        let end = (bc.len() + max_mismatch as usize).min(seq.len());
+        longest_common_run(bc, &seq[..end])
+    } else {
+        longest_exact_run(bc, seq)
+    };
+
+    (run >= min_run).then_some(dist)
+}
+
+// Find the barcode closest to the start of the sequence, each barcode
+// being compared on its own length. At the same distance the longest,
+// most specific, barcode wins and remaining ties are resolved with the
+// tie-break policy.
+#[cfg(test)]
+fn match_barcode<'a>(
+    barcodes: &[&'a [u8]],
+    seq: &[u8],
+    opts: &DemuxOptions,
+    rng: &mut StdRng,
+) -> Option<&'a [u8]> {
+    pick_barcode(&closest_barcodes(barcodes, seq, opts), opts, rng)
+}
+
+// Distance of the reverse complement of a barcode to the start of the
+// sequence, None when reverse complements are not matched
+fn revcomp_distance(bc: &[u8], seq: &[u8], opts: &DemuxOptions) -> Option<u32> {
+    opts.revcomp_barcodes
+        .get(bc)
+        .and_then(|rc| barcode_distance(rc, seq, opts))
+}
+
+// Distance of a barcode, or of its reverse complement when closer, to
+// the start of the sequence
+fn min_distance(bc: &[u8], seq: &[u8], opts: &DemuxOptions) -> Option<u32> {
+    match (
+        barcode_distance(bc, seq, opts),
+        revcomp_distance(bc, seq, opts),
+    ) {
+        (Some(fwd), Some(rc)) => Some(fwd.min(rc)),
+        (fwd, rc) => fwd.or(rc),
+    }
+}
+
+// Barcodes tied at the smallest distance to the start of the sequence
+fn closest_barcodes<'a>(barcodes: &[&'a [u8]], seq: &[u8], opts: &DemuxOptions) -> Vec<&'a [u8]> {
+    let mut best_key = None;
+    let mut best = Vec::new();
+
+    for bc in barcodes.iter() {
+        let dist = match min_distance(bc, seq, opts) {
+            Some(dist) if dist <= opts.max_mismatch(bc) as u32 => dist,
+            _ => continue,
+        };
+        let key = Some((dist, Reverse(bc.len())));
+        if key < best_key || best_key.is_none() {
+            best_key = key;
+            best.clear();
+        }
+        if key == best_key {
+            best.push(*bc);
+        }
+    }
+
+    best
+}
+
+// Distance of the barcode assigned to a sequence and its margin to the
+// next closest barcode, at most the barcode length: 0 when another barcode
+// is as close, the barcode length when no other one can be compared
+fn assignment_margin(barcodes: &[&[u8]], bc: &[u8], seq: &[u8], opts: &DemuxOptions) -> (u32, u32) {
+    let dist = min_distance(bc, seq, opts).unwrap_or(u32::MAX);
+    let runner_up = barcodes
+        .iter()
+        .filter(|other| **other != bc)
+        .filter_map(|other| min_distance(other, seq, opts))
+        .min();
+    let margin = runner_up.map_or(u32::MAX, |other| other.saturating_sub(dist));
+
+    (dist, margin.min(bc.len() as u32))
+}
+
+// Write the confidence of the assignment of a read when a confidence
+// writer is given
+fn write_confidence(
+    barcode_data: &mut Barcode,
+    barcodes: &[&[u8]],
+    matched_barcode: Option<&[u8]>,
+    bc_seq: Option<&[u8]>,
+    id: &[u8],
+    opts: &DemuxOptions,
+) -> io::Result<()> {
+    let (Some(bc), Some(seq)) = (matched_barcode, bc_seq) else {
+        return Ok(());
+    };
+    let Some(out) = barcode_data
+        .get_mut(CONFIDENCE)
+        .and_then(|files| files.first_mut())
+    else {
+        return Ok(());
+    };
+
+    let (dist, margin) = assignment_margin(barcodes, bc, seq, opts);
+    // Read ids are copied as bytes, they need not be valid UTF-8
+    out.write_all(id)?;
+    writeln!(
+        out,
+        "\t{}\t{}\t{}\t{:.3}",
+        String::from_utf8_lossy(bc),
+        dist,
+        margin,
+        margin as f64 / bc.len() as f64
+    )
+}
+
+// Resolve a tie between equally close barcodes with the tie-break policy
+fn pick_barcode<'a>(best: &[&'a [u8]], opts: &DemuxOptions, rng: &mut StdRng) -> Option<&'a [u8]> {
+    match (best.len(), opts.tie_break) {
+        (0, _) => None,
+        (1, _) | (_, TieBreak::Lexical) => Some(best[0]),
+        (_, TieBreak::Unknown) => None,
+        (n, TieBreak::Random(_)) => Some(best[rng.gen_range(0..n)]),
+    }
+}
+
+// Count a read matched by the reverse complement of its barcode, closer
+// to the read than the barcode itself
+fn count_revcomp(
+    matched_barcode: Option<&[u8]>,
+    bc_seq: Option<&[u8]>,
+    opts: &DemuxOptions,
+    nb_records: &mut HashMap<&[u8], u32>,
+) {
+    if let (Some(bc), Some(seq)) = (matched_barcode, bc_seq) {
+        if let Some(rc) = revcomp_distance(bc, seq, opts) {
+            if !matches!(barcode_distance(bc, seq, opts), Some(fwd) if fwd <= rc) {
+                nb_records
+                    .entry(REVCOMP)
+                    .and_modify(|e| *e += 1)
+                    .or_insert(1);
+            }
+        }
+    }
+}
+
+// Count a read left unassigned because it is tied between barcodes
+fn count_ambiguous(
+    best: &[&[u8]],
+    matched_barcode: Option<&[u8]>,
+    nb_records: &mut HashMap<&[u8], u32>,
+) {
+    if matched_barcode.is_none() && best.len() > 1 {
+        nb_records
+            .entry(AMBIGUOUS)
+            .and_modify(|e| *e += 1)
+            .or_insert(1);
+    }
+}
+
+// Open the index file, if any, refusing to trim reads which do not
+// carry the barcode
+fn open_index(opts: &DemuxOptions) -> anyhow::Result<Option<Box<dyn needletail::FastxReader>>> {
+    match &opts.index {
+        Some(_) if opts.trim => Err(anyhow!(
+            "barcodes are read from an index file, forward reads cannot be trimmed"
+        )),
+        Some(index) => {
+            let (reader, _compression) = open_input(index)?;
+            Ok(Some(needletail::parse_fastx_reader(reader)?))
+        }
+        None => Ok(None),
+    }
+}
+
+// Sequence of the next index read, failing when the index file ends
+// before the reads
+fn next_index_seq(
+    index_reader: Option<&mut Box<dyn needletail::FastxReader>>,
+    opts: &DemuxOptions,
+    nb_reads: usize,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let (reader, index) = match (index_reader, &opts.index) {
+        (Some(reader), Some(index)) => (reader, index),
+        _ => return Ok(None),
+    };
+    match reader.next() {
+        Some(r) => {
+            let record = r.map_err(|err| record_error(err, "index file", index, nb_reads - 1))?;
+            Ok(Some(record.seq().into_owned()))
+        }
+        None => Err(anyhow!(
+            "index file '{}' has less records than the reads: it ended after {} records",
+            index,
+            nb_reads - 1
+        )),
+    }
+}
+
+// Error of a record which could not be read from `file` after
+// `nb_records` others. Failing to read the file itself, as with a cut
+// compressed stream, tells a truncated file rather than a malformed record.
+fn record_error(
+    err: needletail::errors::ParseError,
+    what: &str,
+    file: &str,
+    nb_records: usize,
+) -> anyhow::Error {
+    let context = match err.kind {
+        needletail::errors::ParseErrorKind::Io => format!(
+            "{} '{}' appears truncated after {} records",
+            what, file, nb_records
+        ),
+        _ => format!("malformed record {} in {} '{}'", nb_records + 1, what, file),
+    };
+
+    anyhow::Error::new(err).context(context)
+}
+
+// Have `--head` reads, or pairs, been processed?
+fn reached_head(nb_reads: usize, opts: &DemuxOptions) -> bool {
+    opts.head.is_some_and(|head| nb_reads >= head)
+}
+
+// Fail when the index file has records left once the reads are done
+fn check_index_end(
+    index_reader: Option<&mut Box<dyn needletail::FastxReader>>,
+    opts: &DemuxOptions,
+    nb_reads: usize,
+) -> anyhow::Result<()> {
+    // Reads were left unread past the head
+    if reached_head(nb_reads, opts) {
+        return Ok(());
+    }
+    if let (Some(reader), Some(index)) = (index_reader, &opts.index) {
+        if reader.next().is_some() {
+            return Err(anyhow!(
+                "index file '{}' has more records than the reads: they ended after {} records",
+                index,
+                nb_reads
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Flush every output writer and the copy of the input, compressed
+// streams are finished when the writers are dropped
+fn flush_outputs(barcode_data: &mut Barcode, opts: &DemuxOptions) -> anyhow::Result<()> {
+    for writer in barcode_data.values_mut().flatten() {
+        writer
+            .flush()
+            .with_context(|| anyhow!("Could not flush output files"))?;
+    }
+    if let Some(tee) = &opts.tee {
+        tee.flush()
+            .with_context(|| anyhow!("Could not flush the decompressed input copy"))?;
+    }
+
+    Ok(())
+}
+
+// Count a single-end record under its barcode, or as unknown, and write
+// it to the matching file
+fn write_se_record<'a, R: FastxRecord>(
+    record: &R,
+    matched_barcode: Option<&'a [u8]>,
+    dist: Option<u32>,
+    barcode_data: &mut Barcode,
+    opts: &DemuxOptions,
+    nb_records: &mut HashMap<&'a [u8], u32>,
+) -> anyhow::Result<bool> {
+    let Some(i) = matched_barcode else {
+        if is_too_short(record, 0, None, opts) {
+            count_record(nb_records, TOO_SHORT);
+            return Ok(false);
+        }
+        count_record(nb_records, b"XXX");
+        let Some(files) = unknown_writers(record, barcode_data, opts, nb_records) else {
+            return Ok(false);
+        };
+        write_seqs(
+            &mut files[0],
+            record,
+            &output_id(record, None, None, opts),
+            0,
+            opts.trim_polyg,
+            None,
+        )?;
+        if let Some(files) = barcode_data.get_mut(&"XXX".as_bytes()) {
+            write_unknown_id(&mut files[1..], record.id())?;
+        }
+        return Ok(opts.unknown_split.is_none());
+    };
+
+    let trim = opts.trim_len(i);
+    let min_qual = opts.min_quality(i);
+    let tagged = umi_range(record.seq().len(), i, opts)
+        .map(|umi| tag_umi(record, &record.seq()[umi.clone()], umi));
+    let out: &dyn FastxRecord = match &tagged {
+        Some(tagged) => tagged,
+        None => record,
+    };
+    let cut = end_trimmed(out, trim, opts);
+    let (out, trim): (&dyn FastxRecord, usize) = match &cut {
+        Some(cut) => (cut, 0),
+        None => (out, trim),
+    };
+    if is_too_short(out, trim, min_qual, opts) {
+        count_record(nb_records, TOO_SHORT);
+        return Ok(false);
+    }
+    if is_low_quality(record, opts) {
+        count_record(nb_records, LOW_QUALITY);
+        return Ok(false);
+    }
+
+    count_record(nb_records, i);
+    // Discarded barcodes have no output file
+    if let Some(file) = barcode_data.get_mut(i).unwrap().first_mut() {
+        write_seqs(
+            file,
+            out,
+            &output_id(out, Some(i), dist, opts),
+            trim,
+            opts.trim_polyg,
+            min_qual,
+        )?;
+    }
+
+    Ok(false)
+}
+
+// Add a record to the count of a barcode or counter
+fn count_record<'a>(nb_records: &mut HashMap<&'a [u8], u32>, key: &'a [u8]) {
+    nb_records.entry(key).and_modify(|e| *e += 1).or_insert(1);
+}
+
+// Is a read shorter than the minimum length once trimmed as when written?
+fn is_too_short<R: FastxRecord + ?Sized>(
+    record: &R,
+    trim: usize,
+    min_qual: Option<u8>,
+    opts: &DemuxOptions,
+) -> bool {
+    opts.min_len
+        .is_some_and(|min_len| kept_range(record, trim, opts.trim_polyg, min_qual).len() < min_len)
+}
+
+// Is the length of a read outside the expected one?
+fn is_length_anomaly<R: FastxRecord + ?Sized>(record: &R, opts: &DemuxOptions) -> bool {
+    opts.expect_length
+        .as_ref()
+        .is_some_and(|expected| !expected.contains(&record.seq().len()))
+}
+
+// Is the mean quality of a read below the minimum? Fasta reads are
+// refused before demultiplexing
+fn is_low_quality<R: FastxRecord + ?Sized>(record: &R, opts: &DemuxOptions) -> bool {
+    match (opts.min_mean_quality, record.qual()) {
+        (Some(min_qual), Some(qual)) => mean_quality(qual) < f64::from(min_qual),
+        _ => false,
+    }
+}
+
+// Bases of a matched read holding its UMI: the ones following the barcode,
+// or the trimmed bases when the read is trimmed
+fn umi_range(seq_len: usize, bc: &[u8], opts: &DemuxOptions) -> Option<Range<usize>> {
+    let len = opts.umi_len?;
+    let start = if opts.trim {
+        opts.trim_len(bc)
+    } else {
+        opts.offset + bc.len()
+    };
+    let start = start.min(seq_len);
+
+    Some(start..(start + len).min(seq_len))
+}
+
+// Copy of a record with the `cut` bases removed and `umi` appended to the
+// read name, before its `/1` or `/2` mate suffix if any
+fn tag_umi<R: FastxRecord + ?Sized>(record: &R, umi: &[u8], cut: Range<usize>) -> OwnedRecord {
+    let old_id = record.id();
+    let name_len = old_id
+        .iter()
+        .position(|c| c.is_ascii_whitespace())
+        .unwrap_or(old_id.len());
+    let name_end = match &old_id[..name_len] {
+        [.., b'/', b'1' | b'2'] => name_len - 2,
+        _ => name_len,
+    };
+    let mut id = old_id[..name_end].to_vec();
+    id.extend_from_slice(b":UMI=");
+    id.extend_from_slice(umi);
+    id.extend_from_slice(&old_id[name_end..]);
+
+    OwnedRecord {
+        id,
+        ..without_bases(record, cut)
+    }
+}
+
+// Copy of a record with the `cut` bases removed
+fn without_bases<R: FastxRecord + ?Sized>(record: &R, cut: Range<usize>) -> OwnedRecord {
+    let without_cut = |bytes: &[u8]| [&bytes[..cut.start], &bytes[cut.end..]].concat();
+    OwnedRecord {
+        id: record.id().to_vec(),
+        seq: without_cut(&record.seq()),
+        qual: record.qual().map(without_cut),
+        format: record.format(),
+        raw: None,
+        line_ending: needletail::parser::LineEnding::Unix,
+        index_seq: None,
+    }
+}
+
+// Copy of a matched read without its `trim` last bases when barcodes are
+// at the 3' end, the read being then written untrimmed from its start
+fn end_trimmed<R: FastxRecord + ?Sized>(
+    record: &R,
+    trim: usize,
+    opts: &DemuxOptions,
+) -> Option<OwnedRecord> {
+    (opts.three_prime && trim > 0).then(|| {
+        let len = record.seq().len();
+        without_bases(record, len.saturating_sub(trim)..len)
+    })
+}
+
+// Id written for a record: its read name only when ids are normalized,
+// followed by a tab and the barcode it is assigned to as a `BC:Z:` tag
+// when barcodes are kept in ids, and by its distance to the barcode as a
+// `mm:i:` tag when annotated
+fn output_id<'r, R: FastxRecord + ?Sized>(
+    record: &'r R,
+    barcode: Option<&[u8]>,
+    dist: Option<u32>,
+    opts: &DemuxOptions,
+) -> Cow<'r, [u8]> {
+    let id = if opts.normalize_ids {
+        normalize_mate_id(record.id())
+    } else {
+        record.id()
+    };
+
+    let mut tags = Vec::new();
+    if let Some(bc) = barcode.filter(|_| opts.keep_barcode_in_id) {
+        tags.extend_from_slice(b"\tBC:Z:");
+        tags.extend_from_slice(bc);
+    }
+    if let Some(dist) = dist {
+        tags.extend_from_slice(format!("\tmm:i:{}", dist).as_bytes());
+    }
+    match tags.is_empty() {
+        true => Cow::Borrowed(id),
+        false => Cow::Owned([id, &tags].concat()),
+    }
+}
+
+// Distance of a read to its assigned barcode, when annotated in the ids
+fn annotated_distance(
+    matched_barcode: Option<&[u8]>,
+    bc_seq: Option<&[u8]>,
+    opts: &DemuxOptions,
+) -> Option<u32> {
+    match (matched_barcode, bc_seq) {
+        (Some(bc), Some(seq)) if opts.annotate_distance => min_distance(bc, seq, opts),
+        _ => None,
+    }
+}
+
+// Write the id of an unknown read if the unknown writers are followed by
+// an id writer
+fn write_unknown_id(extra: &mut [Box<dyn Output>], id: &[u8]) -> io::Result<()> {
+    if let Some(ids) = extra.first_mut() {
+        ids.write_all(id)?;
+        ids.write_all(b"\n")?;
+    }
+
+    Ok(())
+}
+
+// Parse the reads of an input as the format the options force, or else as
+// the format of its first record
+fn parse_reads<'a, R: 'a + Read + Send>(
+    reader: R,
+    opts: &DemuxOptions,
+) -> anyhow::Result<Box<dyn needletail::FastxReader + 'a>> {
+    Ok(match opts.input_format {
+        Some(needletail::parser::Format::Fasta) => {
+            Box::new(needletail::parser::FastaReader::new(reader))
+        }
+        Some(needletail::parser::Format::Fastq) => {
+            Box::new(needletail::parser::FastqReader::new(reader))
+        }
+        None => needletail::parse_fastx_reader(reader)?,
+    })
+}
+
+// Open the forward input, unless already opened, counting the bytes read
+// and copying its decompressed content as the options ask
+fn open_forward(path: &str, opts: &DemuxOptions) -> anyhow::Result<Box<dyn Read + Send>> {
+    let reader = match opts.opened.take(path) {
+        Some(reader) => reader,
+        None => open_counted_input(path, opts.progress.as_ref())?.0,
+    };
+
+    Ok(match &opts.tee {
+        Some(tee) => Box::new(tee.reader(reader)),
+        None => reader,
+    })
+}
 
 /// A function to demultiplex a FASTA/FASTQ file
-pub fn se_demux<'a>(
-    file: &'a str,
-    format: niffler::send::compression::Format,
-    level: niffler::Level,
-    barcode_data: &'a Barcode,
-    mismatch: u8,
-    nb_records: &'a mut HashMap<&'a [u8], u32>,
-) -> anyhow::Result<(&'a mut HashMap<&'a [u8], u32>, bool)> {
-    // Get fasta file reader and compression mode
-    let (reader, mut compression) = niffler::send::from_path(file)?;
+pub fn se_demux<'a, 'b>(
+    file: &str,
+    barcode_data: &mut Barcode<'a>,
+    opts: &DemuxOptions,
+    nb_records: &'b mut HashMap<&'a [u8], u32>,
+    mut live: Option<&mut LiveStats>,
+) -> anyhow::Result<(&'b mut HashMap<&'a [u8], u32>, bool)> {
+    if opts.threads > 1 {
+        let budget = MemoryBudget::new(opts.max_memory);
+        return se_demux_parallel(file, barcode_data, opts, nb_records, live, &budget);
+    }
+
+    // Get fasta file reader
+    let reader = open_forward(file, opts)?;
+
+    // Get records
+    let mut fastx_reader = parse_reads(reader, opts)?;
+    let mut index_reader = open_index(opts)?;
+
+    // Clone barcode values in barcode_data structure for future iteration
+    let my_vec = candidate_barcodes(barcode_data);
+    let mut rng = opts.tie_break.rng();
+
+    // Initialize unknown file as empty
+    let mut is_unk_empty = true;
+
+    let mut nb_reads = 0;
+    while !reached_head(nb_reads, opts) {
+        let Some(r) = fastx_reader.next() else {
+            break;
+        };
+        let record = r.map_err(|err| record_error(err, "file", file, nb_reads))?;
+        nb_reads += 1;
+
+        // Match sequence and barcode with mismatch
+        // and return matched barcode
+        let index_seq = next_index_seq(index_reader.as_mut(), opts, nb_reads)?;
+        if is_length_anomaly(&record, opts) {
+            count_record(nb_records, LENGTH_ANOMALY);
+            if let Some(l) = live.as_deref_mut() {
+                l.tick(nb_records)?;
+            }
+            continue;
+        }
+        let seq = record.seq();
+        let bc_seq = barcode_seq(opts, record.id(), &seq, index_seq.as_deref());
+        let best = bc_seq
+            .map(|bc_seq| closest_barcodes(&my_vec, bc_seq, opts))
+            .unwrap_or_default();
+        let matched_barcode = pick_barcode(&best, opts, &mut rng);
+        count_ambiguous(&best, matched_barcode, nb_records);
+        count_revcomp(matched_barcode, bc_seq, opts, nb_records);
+        write_confidence(
+            barcode_data,
+            &my_vec,
+            matched_barcode,
+            bc_seq,
+            record.id(),
+            opts,
+        )?;
+
+        let dist = annotated_distance(matched_barcode, bc_seq, opts);
+        let wrote_unknown = write_se_record(
+            &record,
+            matched_barcode,
+            dist,
+            barcode_data,
+            opts,
+            nb_records,
+        )
+        .with_context(|| anyhow!("Could not write record {} of file '{}'", nb_reads, file))?;
+        is_unk_empty &= !wrote_unknown;
+
+        if let Some(l) = live.as_deref_mut() {
+            l.tick(nb_records)?;
+        }
+    }
+    check_index_end(index_reader.as_mut(), opts, nb_reads)?;
+    flush_outputs(barcode_data, opts)?;
+
+    Ok((nb_records, is_unk_empty))
+}
+
+// Number of records handed to a matcher thread at once
+const BATCH_SIZE: usize = 1024;
+
+// Bytes held by the records of a batch
+fn batch_size(batch: &[OwnedRecord]) -> usize {
+    batch
+        .iter()
+        .map(|record| {
+            record.id.len()
+                + record.seq.len()
+                + record.qual.as_ref().map_or(0, Vec::len)
+                + record.raw.as_ref().map_or(0, Vec::len)
+                + record.index_seq.as_ref().map_or(0, Vec::len)
+        })
+        .sum()
+}
+
+// A record copied out of the parser to be matched on another thread
+struct OwnedRecord {
+    id: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Option<Vec<u8>>,
+    format: needletail::parser::Format,
+    raw: Option<Vec<u8>>,
+    line_ending: needletail::parser::LineEnding,
+    index_seq: Option<Vec<u8>>,
+}
+
+impl OwnedRecord {
+    // Copy of a parsed record, as found in the input
+    fn from_record(record: &needletail::parser::SequenceRecord) -> Self {
+        OwnedRecord {
+            id: record.id().to_vec(),
+            seq: record.seq().into_owned(),
+            qual: record.qual().map(|qual| qual.to_vec()),
+            format: record.format(),
+            raw: Some(record.all().to_vec()),
+            line_ending: record.line_ending(),
+            index_seq: None,
+        }
+    }
+}
+
+impl FastxRecord for OwnedRecord {
+    fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    fn seq(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.seq)
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        self.qual.as_deref()
+    }
+
+    fn format(&self) -> needletail::parser::Format {
+        self.format
+    }
+
+    fn raw(&self) -> Option<(&[u8], needletail::parser::LineEnding)> {
+        self.raw.as_deref().map(|raw| (raw, self.line_ending))
+    }
+}
+
+// Multi-threaded se_demux: a reader thread parses batches of records,
+// matcher threads look for their closest barcodes and the calling thread
+// resolves ties and writes the batches back in input order, so that
+// counts and output files are the same as with a single thread.
+fn se_demux_parallel<'a, 'b>(
+    file: &str,
+    barcode_data: &mut Barcode<'a>,
+    opts: &DemuxOptions,
+    nb_records: &'b mut HashMap<&'a [u8], u32>,
+    mut live: Option<&mut LiveStats>,
+    budget: &MemoryBudget,
+) -> anyhow::Result<(&'b mut HashMap<&'a [u8], u32>, bool)> {
+    let reader = open_forward(file, opts)?;
+
+    let my_vec = candidate_barcodes(barcode_data);
+    let mut rng = opts.tie_break.rng();
+    let mut is_unk_empty = true;
+
+    let (batch_tx, batch_rx) = crossbeam_channel::bounded(opts.threads * 2);
+    let (match_tx, match_rx) = crossbeam_channel::bounded(opts.threads * 2);
+
+    let (read_result, write_result) = thread::scope(|scope| {
+        let reader_thread = scope.spawn(move || -> anyhow::Result<()> {
+            let mut fastx_reader = parse_reads(reader, opts)?;
+            let mut index_reader = open_index(opts)?;
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut nb_batches = 0;
+            let mut nb_reads = 0;
+
+            while !reached_head(nb_reads, opts) {
+                let Some(r) = fastx_reader.next() else {
+                    break;
+                };
+                let record = r.map_err(|err| record_error(err, "file", file, nb_reads))?;
+                nb_reads += 1;
+                batch.push(OwnedRecord {
+                    index_seq: next_index_seq(index_reader.as_mut(), opts, nb_reads)?,
+                    ..OwnedRecord::from_record(&record)
+                });
+
+                if batch.len() == BATCH_SIZE {
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE));
+                    // Writing stopped on an error, which is reported there
+                    if !budget.acquire(batch_size(&full))
+                        || batch_tx.send((nb_batches, full)).is_err()
+                    {
+                        return Ok(());
+                    }
+                    nb_batches += 1;
+                }
+            }
+            if !batch.is_empty()
+                && (!budget.acquire(batch_size(&batch))
+                    || batch_tx.send((nb_batches, batch)).is_err())
+            {
+                return Ok(());
+            }
+
+            check_index_end(index_reader.as_mut(), opts, nb_reads)
+        });
+
+        for _ in 0..opts.threads {
+            let batch_rx = batch_rx.clone();
+            let match_tx = match_tx.clone();
+            let my_vec = &my_vec;
+            scope.spawn(move || {
+                for (n, batch) in batch_rx {
+                    let matches = batch
+                        .iter()
+                        .map(|record: &OwnedRecord| {
+                            let seq = &record.seq;
+                            match barcode_seq(opts, &record.id, seq, record.index_seq.as_deref()) {
+                                Some(bc_seq) => closest_barcodes(my_vec, bc_seq, opts),
+                                None => Vec::new(),
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    if match_tx.send((n, batch, matches)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(batch_rx);
+        drop(match_tx);
+
+        let write_result = (|| -> anyhow::Result<()> {
+            let mut pending = HashMap::new();
+            let mut next_batch = 0;
+            let mut nb_reads = 0;
+            for (n, batch, matches) in match_rx {
+                pending.insert(n, (batch, matches));
+                while let Some((batch, matches)) = pending.remove(&next_batch) {
+                    next_batch += 1;
+                    for (record, best) in batch.iter().zip(matches) {
+                        nb_reads += 1;
+                        if is_length_anomaly(record, opts) {
+                            count_record(nb_records, LENGTH_ANOMALY);
+                            if let Some(l) = live.as_deref_mut() {
+                                l.tick(nb_records)?;
+                            }
+                            continue;
+                        }
+                        let matched_barcode = pick_barcode(&best, opts, &mut rng);
+                        count_ambiguous(&best, matched_barcode, nb_records);
+                        let bc_seq =
+                            barcode_seq(opts, &record.id, &record.seq, record.index_seq.as_deref());
+                        count_revcomp(matched_barcode, bc_seq, opts, nb_records);
+                        write_confidence(
+                            barcode_data,
+                            &my_vec,
+                            matched_barcode,
+                            bc_seq,
+                            &record.id,
+                            opts,
+                        )?;
+                        let dist = annotated_distance(matched_barcode, bc_seq, opts);
+                        let wrote_unknown = write_se_record(
+                            record,
+                            matched_barcode,
+                            dist,
+                            barcode_data,
+                            opts,
+                            nb_records,
+                        )
+                        .with_context(|| {
+                            anyhow!("Could not write record {} of file '{}'", nb_reads, file)
+                        })?;
+                        is_unk_empty &= !wrote_unknown;
+
+                        if let Some(l) = live.as_deref_mut() {
+                            l.tick(nb_records)?;
+                        }
+                    }
+                    budget.release(batch_size(&batch));
+                }
+            }
+
+            Ok(())
+        })();
+        // A reader waiting for memory would never get it once writing
+        // stopped on an error
+        budget.close();
+
+        let read_result = reader_thread.join().expect("reader thread panicked");
+        (read_result, write_result)
+    });
+    write_result?;
+    read_result?;
+    flush_outputs(barcode_data, opts)?;
+
+    Ok((nb_records, is_unk_empty))
+}
+
+// Number of pairs read by pe_sanity_check
+const SANITY_PAIRS: usize = 1000;
+
+/// Check on the first pairs that forward and reverse files look like
+/// mates of each other, in the right order
+///
+/// Errors when most sampled pairs have different read names, or when the
+/// barcodes are found at the start of the reverse reads rather than the
+/// forward ones.
+pub fn pe_sanity_check(
+    forward: &str,
+    reverse: &str,
+    barcodes: &[&[u8]],
+    opts: &DemuxOptions,
+) -> anyhow::Result<()> {
+    let (forward_reader, _compression) = open_input(forward)?;
+    let (reverse_reader, _compression) = open_input(reverse)?;
+    let mut forward_fastx_reader = parse_reads(forward_reader, opts)?;
+    let mut reverse_fastx_reader = parse_reads(reverse_reader, opts)?;
+    // Barcodes read elsewhere cannot tell the orientation
+    let check_orientation = opts.index.is_none() && opts.header_barcode.is_none();
+
+    let (mut nb_pairs, mut unpaired, mut forward_hits, mut reverse_hits) = (0, 0, 0, 0);
+    while nb_pairs < SANITY_PAIRS {
+        let (forward_record, reverse_record) =
+            match (forward_fastx_reader.next(), reverse_fastx_reader.next()) {
+                (Some(Ok(f)), Some(Ok(r))) => (f, r),
+                _ => break,
+            };
+        nb_pairs += 1;
+
+        if normalize_mate_id(forward_record.id()) != normalize_mate_id(reverse_record.id()) {
+            unpaired += 1;
+        }
+        if check_orientation {
+            if !closest_barcodes(barcodes, &forward_record.seq(), opts).is_empty() {
+                forward_hits += 1;
+            }
+            if !closest_barcodes(barcodes, &reverse_record.seq(), opts).is_empty() {
+                reverse_hits += 1;
+            }
+        }
+    }
+
+    if unpaired * 2 > nb_pairs {
+        return Err(anyhow!(
+            "{} of the first {} pairs have different read names in '{}' and '{}': \
+            the files do not look paired",
+            unpaired,
+            nb_pairs,
+            forward,
+            reverse
+        ));
+    }
+    if reverse_hits * 2 > nb_pairs && forward_hits * 10 < reverse_hits {
+        return Err(anyhow!(
+            "barcodes start {} of the first {} reverse reads but only {} forward reads: \
+            are '{}' and '{}' swapped?",
+            reverse_hits,
+            nb_pairs,
+            forward_hits,
+            forward,
+            reverse
+        ));
+    }
+
+    Ok(())
+}
+
+// Name of a record format for messages
+fn format_name(format: needletail::parser::Format) -> &'static str {
+    match format {
+        needletail::parser::Format::Fasta => "FASTA",
+        needletail::parser::Format::Fastq => "FASTQ",
+    }
+}
+
+/// Count the `len`-base prefixes of the reads of a FASTA/FASTQ file, e.g.
+/// to find the barcodes of an input without a panel
+///
+/// Prefixes are uppercased and returned with their number of reads, most
+/// frequent first then in lexical order. Reads shorter than `len` are not
+/// counted.
+pub fn count_prefixes(file: &str, len: usize) -> anyhow::Result<Vec<(Vec<u8>, u32)>> {
+    let (reader, _compression) = open_input(file)?;
+    let mut fastx_reader = needletail::parse_fastx_reader(reader)?;
+
+    let mut counts: HashMap<Vec<u8>, u32> = HashMap::new();
+    let mut nb_reads = 0;
+    while let Some(r) = fastx_reader.next() {
+        let record = r.map_err(|err| record_error(err, "file", file, nb_reads))?;
+        nb_reads += 1;
+        let seq = record.seq();
+        if seq.len() >= len {
+            *counts.entry(seq[..len].to_ascii_uppercase()).or_insert(0) += 1;
+        }
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|(a, n), (b, m)| m.cmp(n).then(a.cmp(b)));
+
+    Ok(counts)
+}
+
+/// Whether the unknown output files of a paired-end run are left empty,
+/// to be removed unless kept
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownStatus {
+    pub fwd_empty: bool,
+    pub rev_empty: bool,
+}
+
+/// A function to demultiplex a pair of FASTA/FASTQ files
+///
+/// Mates are read in lockstep: the barcode is searched on the forward
+/// read only, or on the index read when an index file is given, and
+/// both mates are written to the files of that barcode. With
+/// `opts.interleaved`, mates are alternating records of `forward` and
+/// `reverse` is not read.
+pub fn pe_demux<'a, 'b>(
+    forward: &str,
+    reverse: &str,
+    barcode_data: &mut Barcode<'a>,
+    opts: &DemuxOptions,
+    nb_records: &'b mut HashMap<&'a [u8], u32>,
+    mut live: Option<&mut LiveStats>,
+) -> anyhow::Result<(&'b mut HashMap<&'a [u8], u32>, UnknownStatus)> {
+    // Get fasta files reader
+    let forward_reader = open_forward(forward, opts)?;
+
+    // Get records, interleaved mates all come from the forward file
+    let mut forward_fastx_reader = parse_reads(forward_reader, opts)?;
+    let mut reverse_fastx_reader = match opts.interleaved {
+        true => None,
+        false => {
+            let reader = match opts.opened.take(reverse) {
+                Some(reader) => reader,
+                None => open_input(reverse)?.0,
+            };
+            Some(parse_reads(reader, opts)?)
+        }
+    };
+    let mut index_reader = open_index(opts)?;
+
+    // Clone barcode values in barcode_data structure for future iteration
+    let my_vec = candidate_barcodes(barcode_data);
+    let mut rng = opts.tie_break.rng();
+
+    // Initialize unknown files as empty
+    let mut unk_status = UnknownStatus {
+        fwd_empty: true,
+        rev_empty: true,
+    };
+
+    let mut nb_pairs = 0;
+    while !reached_head(nb_pairs, opts) {
+        let forward_mate;
+        let forward_copy;
+        let (forward_record, reverse_record): (&dyn FastxRecord, _) = match reverse_fastx_reader
+            .as_mut()
+        {
+            Some(reverse_fastx_reader) => {
+                match (forward_fastx_reader.next(), reverse_fastx_reader.next()) {
+                    (Some(f), Some(r)) => {
+                        forward_mate =
+                            f.map_err(|err| record_error(err, "forward file", forward, nb_pairs))?;
+                        (
+                            &forward_mate,
+                            r.map_err(|err| record_error(err, "reverse file", reverse, nb_pairs))?,
+                        )
+                    }
+                    (None, None) => break,
+                    (Some(_), None) => {
+                        return Err(anyhow!(
+                            "reverse file '{}' has less records than forward file '{}': \
+                                it ended after {} records",
+                            reverse,
+                            forward,
+                            nb_pairs
+                        ))
+                    }
+                    (None, Some(_)) => {
+                        return Err(anyhow!(
+                            "forward file '{}' has less records than reverse file '{}': \
+                                it ended after {} records",
+                            forward,
+                            reverse,
+                            nb_pairs
+                        ))
+                    }
+                }
+            }
+            // The forward mate is copied out of the parser to read the
+            // reverse one from the same file
+            None => {
+                let Some(f) = forward_fastx_reader.next() else {
+                    break;
+                };
+                let unreadable = |err, n| record_error(err, "interleaved file", forward, n);
+                forward_copy =
+                    OwnedRecord::from_record(&f.map_err(|err| unreadable(err, 2 * nb_pairs))?);
+                match forward_fastx_reader.next() {
+                    Some(r) => (
+                        &forward_copy,
+                        r.map_err(|err| unreadable(err, 2 * nb_pairs + 1))?,
+                    ),
+                    None => {
+                        return Err(anyhow!(
+                            "interleaved file '{}' has an odd number of records: \
+                                record {} has no mate",
+                            forward,
+                            2 * nb_pairs + 1
+                        ))
+                    }
+                }
+            }
+        };
+        nb_pairs += 1;
+        // Mates written in different formats would give inconsistent outputs
+        if nb_pairs == 1 && forward_record.format() != reverse_record.format() {
+            return Err(match opts.interleaved {
+                true => anyhow!(
+                    "interleaved file '{}' has a {} forward mate but a {} reverse mate \
+                        in its first pair",
+                    forward,
+                    format_name(forward_record.format()),
+                    format_name(reverse_record.format())
+                ),
+                false => anyhow!(
+                    "forward file '{}' is {} but reverse file '{}' is {}",
+                    forward,
+                    format_name(forward_record.format()),
+                    reverse,
+                    format_name(reverse_record.format())
+                ),
+            });
+        }
+
+        let index_seq = next_index_seq(index_reader.as_mut(), opts, nb_pairs)?;
+        // Pairs are dropped as a whole to keep mates in step
+        if is_length_anomaly(forward_record, opts) || is_length_anomaly(&reverse_record, opts) {
+            count_record(nb_records, LENGTH_ANOMALY);
+            if let Some(l) = live.as_deref_mut() {
+                l.tick(nb_records)?;
+            }
+            continue;
+        }
+        let seq = forward_record.seq();
+        let bc_seq = barcode_seq(opts, forward_record.id(), &seq, index_seq.as_deref());
+        let best = bc_seq
+            .map(|bc_seq| closest_barcodes(&my_vec, bc_seq, opts))
+            .unwrap_or_default();
+        let matched_barcode = pick_barcode(&best, opts, &mut rng);
+        count_ambiguous(&best, matched_barcode, nb_records);
+        count_revcomp(matched_barcode, bc_seq, opts, nb_records);
+        write_confidence(
+            barcode_data,
+            &my_vec,
+            matched_barcode,
+            bc_seq,
+            forward_record.id(),
+            opts,
+        )?;
+
+        let dist = annotated_distance(matched_barcode, bc_seq, opts);
+        if let Some(i) = matched_barcode {
+            // Only the forward mate carries the barcode, and the UMI which
+            // tags both mates
+            let trim = opts.trim_len(i);
+            let min_qual = opts.min_quality(i);
+            let tagged = umi_range(seq.len(), i, opts).map(|umi| {
+                (
+                    tag_umi(forward_record, &seq[umi.clone()], umi.clone()),
+                    tag_umi(&reverse_record, &seq[umi], 0..0),
+                )
+            });
+            let (forward_out, reverse_out): (&dyn FastxRecord, &dyn FastxRecord) = match &tagged {
+                Some((forward_tagged, reverse_tagged)) => (forward_tagged, reverse_tagged),
+                None => (forward_record, &reverse_record),
+            };
+            let cut = end_trimmed(forward_out, trim, opts);
+            let (forward_out, trim): (&dyn FastxRecord, usize) = match &cut {
+                Some(cut) => (cut, 0),
+                None => (forward_out, trim),
+            };
+            // Pairs are dropped as a whole to keep mates in step
+            if is_too_short(forward_out, trim, min_qual, opts)
+                || is_too_short(reverse_out, 0, min_qual, opts)
+            {
+                count_record(nb_records, TOO_SHORT);
+            } else if is_low_quality(forward_record, opts) || is_low_quality(&reverse_record, opts)
+            {
+                count_record(nb_records, LOW_QUALITY);
+            } else {
+                count_record(nb_records, i);
+                // Discarded barcodes have no output file
+                let files = barcode_data.get_mut(i).unwrap();
+                if files.len() == 2 {
+                    write_seqs(
+                        &mut files[0],
+                        forward_out,
+                        &output_id(forward_out, Some(i), dist, opts),
+                        trim,
+                        opts.trim_polyg,
+                        min_qual,
+                    )
+                    .with_context(|| {
+                        anyhow!("Could not write record {} of forward file", nb_pairs)
+                    })?;
+                    write_seqs(
+                        &mut files[1],
+                        reverse_out,
+                        &output_id(reverse_out, Some(i), dist, opts),
+                        0,
+                        opts.trim_polyg,
+                        min_qual,
+                    )
+                    .with_context(|| {
+                        anyhow!("Could not write record {} of reverse file", nb_pairs)
+                    })?;
+                }
+            }
+        } else if is_too_short(forward_record, 0, None, opts)
+            || is_too_short(&reverse_record, 0, None, opts)
+        {
+            count_record(nb_records, TOO_SHORT);
+        } else {
+            count_record(nb_records, b"XXX");
+            // Pairs are bucketed on their forward mate, and only counted
+            // when unknown pairs are discarded
+            if let Some(files) = unknown_writers(forward_record, barcode_data, opts, nb_records) {
+                if opts.unknown_split.is_none() {
+                    unk_status.fwd_empty = false;
+                    unk_status.rev_empty = false;
+                }
+                write_seqs(
+                    &mut files[0],
+                    forward_record,
+                    &output_id(forward_record, None, None, opts),
+                    0,
+                    opts.trim_polyg,
+                    None,
+                )
+                .with_context(|| anyhow!("Could not write record {} of forward file", nb_pairs))?;
+                write_seqs(
+                    &mut files[1],
+                    &reverse_record,
+                    &output_id(&reverse_record, None, None, opts),
+                    0,
+                    opts.trim_polyg,
+                    None,
+                )
+                .with_context(|| anyhow!("Could not write record {} of reverse file", nb_pairs))?;
+            }
+            if let Some(files) = barcode_data.get_mut(&"XXX".as_bytes()) {
+                write_unknown_id(&mut files[2..], forward_record.id())?;
+            }
+        }
+
+        if let Some(l) = live.as_deref_mut() {
+            l.tick(nb_records)?;
+        }
+    }
+    check_index_end(index_reader.as_mut(), opts, nb_pairs)?;
+    flush_outputs(barcode_data, opts)?;
+
+    Ok((nb_records, unk_status))
+}
+
+// Tests ----------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writers(files: Vec<std::fs::File>) -> Vec<Box<dyn Output>> {
+        files
+            .into_iter()
+            .map(|file| Box::new(file) as Box<dyn Output>)
+            .collect()
+    }
+
+    #[test]
+    fn test_se_demux_1() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_min_exact_run() {
+        let barcodes: Vec<&[u8]> = vec![b"ACGTACGT"];
+        let mut rng = TieBreak::Lexical.rng();
+        let mut opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+        // Two mismatches, longest exact run of 3 bases
+        let seq = b"ACGAACCTTTTT";
+        assert_eq!(
+            match_barcode(&barcodes, seq, &opts, &mut rng),
+            Some(&b"ACGTACGT"[..])
+        );
+
+        opts.min_exact_run = Some(4);
+        assert_eq!(match_barcode(&barcodes, seq, &opts, &mut rng), None);
+        opts.min_exact_run = Some(3);
+        assert_eq!(
+            match_barcode(&barcodes, seq, &opts, &mut rng),
+            Some(&b"ACGTACGT"[..])
+        );
+
+        // A deleted base leaves a 5 bases run past it
+        let barcodes: Vec<&[u8]> = vec![b"AACCGGTT"];
+        opts.edit_distance = true;
+        opts.min_exact_run = Some(5);
+        assert_eq!(
+            match_barcode(&barcodes, b"AACGGTTAAA", &opts, &mut rng),
+            Some(&b"AACCGGTT"[..])
+        );
+        opts.min_exact_run = Some(6);
+        assert_eq!(
+            match_barcode(&barcodes, b"AACGGTTAAA", &opts, &mut rng),
+            None
+        );
+    }
+
+    #[test]
+    fn test_se_demux_no_unknown() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions::default();
+        let (stats, is_unk_empty) =
+            se_demux("tests/test.fq", &mut bc_data, &opts, &mut nb_records, None).unwrap();
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&2));
+        assert!(is_unk_empty);
+    }
+
+    #[test]
+    fn test_pe_demux_no_unknown() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions::default();
+        let (stats, unk_status) = pe_demux(
+            "tests/test.fq",
+            "tests/test.fq",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&2));
+        assert_eq!(
+            unk_status,
+            UnknownStatus {
+                fwd_empty: true,
+                rev_empty: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_demux_head() {
+        let total = |stats: &HashMap<&[u8], u32>| stats.values().sum::<u32>();
+        let opts = DemuxOptions {
+            head: Some(2),
+            ..Default::default()
+        };
+
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+        let (stats, _) =
+            se_demux("tests/test.fq", &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        assert_eq!(total(stats), 2);
+
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        let (stats, _) = pe_demux(
+            "tests/test.fq",
+            "tests/test.fq",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+        assert_eq!(total(stats), 2);
+
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..3 * BATCH_SIZE {
+            writeln!(input, ">r{}\nACCGTA{}", i, "T".repeat(20)).unwrap();
+        }
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        let opts = DemuxOptions {
+            head: Some(BATCH_SIZE + 5),
+            threads: 2,
+            ..Default::default()
+        };
+        let budget = MemoryBudget::new(None);
+        let (stats, _) = se_demux_parallel(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+            &budget,
+        )
+        .unwrap();
+        assert_eq!(total(stats), BATCH_SIZE as u32 + 5);
+    }
+
+    #[test]
+    fn test_se_demux_tee() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let copy = tempfile::NamedTempFile::new().unwrap();
+        let opts = DemuxOptions {
+            tee: Some(TeeSink::new(Box::new(copy.reopen().unwrap()))),
+            ..Default::default()
+        };
+        se_demux(
+            "tests/reads_1.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read(copy.path()).unwrap(),
+            std::fs::read("tests/reads_1.fa").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_se_demux_trim() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            trim: true,
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_m1() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let reverse = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"ATTGTT", writers(vec![reverse]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            mismatch: 1,
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_m2() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let reverse = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"ATTGTT", writers(vec![reverse]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_discard() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", Vec::new());
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions::default();
+
+        let (stats, _) =
+            se_demux("tests/test.fq", &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+    }
+
+    #[test]
+    fn test_se_demux_2() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let reverse = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"ATTGTT", writers(vec![reverse]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fq.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_m3() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let reverse = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"ATTGTT", writers(vec![reverse]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            mismatch: 1,
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fq.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_m4() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let forward = tempfile::tempfile().expect("Cannot create temp file");
+        let reverse = tempfile::tempfile().expect("Cannot create temp file");
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+
+        bc_data.insert(b"ACCGTA", writers(vec![forward]));
+        bc_data.insert(b"ATTGTT", writers(vec![reverse]));
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+
+        assert!(se_demux(
+            "tests/test.fq.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_se_demux_live_stats() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let barcodes = std::fs::read_to_string("tests/bc_se.txt").unwrap();
+        for line in barcodes.lines() {
+            let bc = line.split('\t').next().unwrap();
+            let file = tempfile::tempfile().expect("Cannot create temp file");
+            bc_data.insert(bc.as_bytes(), writers(vec![file]));
+        }
+        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        bc_data.insert(b"XXX", writers(vec![unknown]));
+
+        let opts = DemuxOptions::default();
+        let ndjson = tempfile::NamedTempFile::new().expect("Cannot create temp file");
+        let mut live = LiveStats::new(1000, Box::new(ndjson.reopen().unwrap()));
+
+        se_demux(
+            "tests/reads_1.fa",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            Some(&mut live),
+        )
+        .unwrap();
+        drop(live);
+
+        let content = std::fs::read_to_string(ndjson.path()).unwrap();
+        let lines = content
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert!(lines.len() > 2);
+
+        for pair in lines.windows(2) {
+            assert!(pair[1]["records"].as_u64() > pair[0]["records"].as_u64());
+            let (prev, next) = (pair[0]["counts"].as_object().unwrap(), &pair[1]["counts"]);
+            for (bc, count) in prev {
+                assert!(next[bc].as_u64().unwrap() >= count.as_u64().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pe_demux_lockstep() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        writeln!(forward, ">r1\nACCGTAAAAA\n>r2\nGGGGGGGGGG").unwrap();
+        writeln!(reverse, ">r1\nTTTTTTTTTT\n>r2\nCCCCCCCCCC").unwrap();
+
+        let sample_r1 = tempfile::NamedTempFile::new().unwrap();
+        let sample_r2 = tempfile::NamedTempFile::new().unwrap();
+        let unknown_r1 = tempfile::NamedTempFile::new().unwrap();
+        let unknown_r2 = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                sample_r1.reopen().unwrap(),
+                sample_r2.reopen().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                unknown_r1.reopen().unwrap(),
+                unknown_r2.reopen().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions::default();
+
+        let (stats, unk_status) = pe_demux(
+            forward.path().to_str().unwrap(),
+            reverse.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(
+            unk_status,
+            UnknownStatus {
+                fwd_empty: false,
+                rev_empty: false
+            }
+        );
+        let read = |f: &tempfile::NamedTempFile| std::fs::read_to_string(f.path()).unwrap();
+        assert_eq!(read(&sample_r1), ">r1\nACCGTAAAAA\n");
+        assert_eq!(read(&sample_r2), ">r1\nTTTTTTTTTT\n");
+        assert_eq!(read(&unknown_r1), ">r2\nGGGGGGGGGG\n");
+        assert_eq!(read(&unknown_r2), ">r2\nCCCCCCCCCC\n");
+    }
+
+    #[test]
+    fn test_pe_demux_record_count_mismatch() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let sample_r1 = tempfile::tempfile().unwrap();
+        let sample_r2 = tempfile::tempfile().unwrap();
+        let unknown_r1 = tempfile::tempfile().unwrap();
+        let unknown_r2 = tempfile::tempfile().unwrap();
+        bc_data.insert(b"GTCTGATG", writers(vec![sample_r1, sample_r2]));
+        bc_data.insert(b"XXX", writers(vec![unknown_r1, unknown_r2]));
+
+        let opts = DemuxOptions::default();
+
+        let err = pe_demux(
+            "tests/reads_1.fa",
+            "tests/test.fa.gz",
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("ended after 1 records"));
+    }
+
+    #[test]
+    fn test_se_demux_trim_barcode() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let unknown = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+        let opts = DemuxOptions {
+            trim: true,
+            ..Default::default()
+        };
+
+        se_demux("tests/test.fq", &mut bc_data, &opts, &mut nb_records, None).unwrap();
+
+        let trimmed = std::fs::read_to_string(sample.path()).unwrap();
+        let mut lines = trimmed.lines().skip(1);
+        let (seq, qual) = (lines.next().unwrap(), lines.nth(1).unwrap());
+        assert_eq!(seq, "GGCTGACCGTAGGCTGAACGTAGGCTGAAAGTAGGCTGAAAACCCC");
+        assert_eq!(qual.len(), seq.len());
+
+        // unknown reads are written untrimmed
+        let untouched = std::fs::read_to_string(unknown.path()).unwrap();
+        assert!(untouched.contains("ATTGTTGTTTTAATTGTTGTTTTAATTGTTGTTTTAGGGG\n"));
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn test_se_demux_http_input() {
+        use std::io::Read;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/reads.fa", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            let body = ">r1\nACCGTAAAAA\n>r2\nGGGGGGGGGG\n";
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .unwrap();
+        });
+
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions::default();
+
+        let (stats, is_unk_empty) =
+            se_demux(&url, &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert!(!is_unk_empty);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_se_demux_variable_length() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nACCGTAGGTTTTTT\n>r2\nACCGTATTTTTTTT\n>r3\nCATGTCTAGGGGGG\n>r4\nCATGTCGGGGGGGG"
+        )
+        .unwrap();
+
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"ACCGTAGG", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"CATGTCTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions::default();
+
+        let (stats, is_unk_empty) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(stats.get(&b"ACCGTAGG"[..]), Some(&1));
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"CATGTCTA"[..]), Some(&1));
+        assert!(!is_unk_empty);
+    }
+
+    #[test]
+    fn test_match_barcode_closest_wins() {
+        // at 1 and 2 mismatches of the read, both within the threshold
+        let barcodes: Vec<&[u8]> = vec![b"ACCGTT", b"ACCGAA"];
+        let opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+        let mut rng = opts.tie_break.rng();
+
+        assert_eq!(
+            match_barcode(&barcodes, b"ACCGTCGGGG", &opts, &mut rng),
+            Some(&b"ACCGTT"[..])
+        );
+        assert_eq!(
+            match_barcode(&barcodes, b"ACCGACGGGG", &opts, &mut rng),
+            Some(&b"ACCGAA"[..])
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_read_shorter_than_barcode() {
+        let barcodes: Vec<&[u8]> = vec![b"ACCGTAGG", b"ACCG"];
+        let opts = DemuxOptions::default();
+        let mut rng = opts.tie_break.rng();
+        assert_eq!(
+            match_barcode(&barcodes, b"ACCGT", &opts, &mut rng),
+            Some(&b"ACCG"[..])
+        );
+        assert_eq!(match_barcode(&barcodes, b"ACC", &opts, &mut rng), None);
+    }
+
+    #[test]
+    fn test_match_barcode_three_prime() {
+        let barcodes: Vec<&[u8]> = vec![b"ACCGTA", b"GGCCTT"];
+        let mut opts = DemuxOptions::default();
+        let mut rng = opts.tie_break.rng();
+        let read = b"ACCGTATTTTTTGGCCTT";
+
+        assert_eq!(
+            match_barcode(&barcodes, read, &opts, &mut rng),
+            Some(&b"ACCGTA"[..])
+        );
+        opts.three_prime = true;
+        assert_eq!(
+            match_barcode(&barcodes, read, &opts, &mut rng),
+            Some(&b"GGCCTT"[..])
+        );
+        // shorter than the barcodes
+        assert_eq!(match_barcode(&barcodes, b"CCTT", &opts, &mut rng), None);
+
+        // an indel moves the start of the barcode, not its end
+        opts.mismatch = 1;
+        opts.edit_distance = true;
+        assert_eq!(
+            match_barcode(&barcodes, b"TTTTGGCTT", &opts, &mut rng),
+            Some(&b"GGCCTT"[..])
+        );
+    }
+
+    #[test]
+    fn test_se_demux_trim_three_prime() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            "@r1\nTTTTACCGTA\n+\nIIIIIIII#!\n@r2\nACCGTATTTT\n+\nIIIIIIIIII"
+        )
+        .unwrap();
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions {
+            three_prime: true,
+            trim: true,
+            ..Default::default()
+        };
+        let (stats, _) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+        drop(bc_data);
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            "@r1\nTTTT\n+\nIIII\n"
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_offset() {
+        let barcodes: Vec<&[u8]> = vec![b"ACCGTA", b"TTTTAC"];
+        let mut opts = DemuxOptions::default();
+        let mut rng = opts.tie_break.rng();
+        let read = b"TTTTACCGTAGGGG";
+
+        assert_eq!(
+            match_barcode(&barcodes, read, &opts, &mut rng),
+            Some(&b"TTTTAC"[..])
+        );
+        opts.offset = 4;
+        assert_eq!(
+            match_barcode(&barcodes, read, &opts, &mut rng),
+            Some(&b"ACCGTA"[..])
+        );
+        // too short to hold the barcode past the offset
+        assert_eq!(
+            match_barcode(&barcodes, b"TTTTACCGT", &opts, &mut rng),
+            None
+        );
+        opts.three_prime = true;
+        assert_eq!(
+            match_barcode(&barcodes, b"GGACCGTATTTT", &opts, &mut rng),
+            Some(&b"ACCGTA"[..])
+        );
+    }
+
+    #[test]
+    fn test_se_demux_trim_offset() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nGGGGACCGTATTTT\n>r2\nACCGTATTTTTTTT").unwrap();
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions {
+            offset: 4,
+            trim: true,
+            ..Default::default()
+        };
+        let (stats, _) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+        drop(bc_data);
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+        // the spacer is trimmed with the barcode
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\nTTTT\n"
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_per_barcode_mismatch() {
+        let barcodes: Vec<&[u8]> = vec![b"AAAAAA", b"CCCCCC"];
+        let mut opts = DemuxOptions {
+            mismatch: 1,
+            ..Default::default()
+        };
+        opts.barcode_mismatch.insert(b"CCCCCC".to_vec(), 0);
+        let mut rng = opts.tie_break.rng();
+
+        assert_eq!(
+            match_barcode(&barcodes, b"AAAATA", &opts, &mut rng),
+            Some(&b"AAAAAA"[..])
+        );
+        assert_eq!(match_barcode(&barcodes, b"CCCCGC", &opts, &mut rng), None);
+
+        opts.barcode_mismatch.insert(b"CCCCCC".to_vec(), 2);
+        assert_eq!(
+            match_barcode(&barcodes, b"CCGCGC", &opts, &mut rng),
+            Some(&b"CCCCCC"[..])
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_edit_distance() {
+        let barcodes: Vec<&[u8]> = vec![b"ACCGTA"];
+        let mut opts = DemuxOptions {
+            mismatch: 1,
+            ..Default::default()
+        };
+        let mut rng = opts.tie_break.rng();
+        // one base deleted from the barcode
+        assert_eq!(
+            match_barcode(&barcodes, b"ACGTAGGGGG", &opts, &mut rng),
+            None
+        );
+        opts.edit_distance = true;
+        assert_eq!(
+            match_barcode(&barcodes, b"ACGTAGGGGG", &opts, &mut rng),
+            Some(&b"ACCGTA"[..])
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_edit_distance_n_wildcard() {
+        let barcodes: Vec<&[u8]> = vec![b"ACNGTA"];
+        let mut opts = DemuxOptions {
+            mismatch: 0,
+            edit_distance: true,
+            ..Default::default()
+        };
+        let mut rng = opts.tie_break.rng();
+        // the N matches any base, as without --edit-distance
+        assert_eq!(
+            match_barcode(&barcodes, b"ACTGTAGGGG", &opts, &mut rng),
+            Some(&b"ACNGTA"[..])
+        );
+        assert_eq!(
+            match_barcode(&barcodes, b"ACGTAGGGGG", &opts, &mut rng),
+            None
+        );
+        opts.mismatch = 1;
+        assert_eq!(
+            match_barcode(&barcodes, b"ACGTAGGGGG", &opts, &mut rng),
+            Some(&b"ACNGTA"[..])
+        );
+    }
+
+    #[test]
+    fn test_match_barcode_tie_break() {
+        // reads at distance 2 of both barcodes
+        let barcodes: Vec<&[u8]> = vec![b"AAAA", b"CCCC"];
+        let reads: Vec<&[u8]> = vec![b"ACAC", b"CACA", b"AACC", b"CCAA", b"ACCA", b"CAAC"];
+        let mut opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+
+        let mut rng = opts.tie_break.rng();
+        for read in reads.iter() {
+            assert_eq!(match_barcode(&barcodes, read, &opts, &mut rng), None);
+        }
+
+        opts.tie_break = TieBreak::Lexical;
+        for read in reads.iter() {
+            assert_eq!(
+                match_barcode(&barcodes, read, &opts, &mut rng),
+                Some(&b"AAAA"[..])
+            );
+        }
+
+        let assign = |opts: &DemuxOptions| {
+            let mut rng = opts.tie_break.rng();
+            reads
+                .iter()
+                .cycle()
+                .take(60)
+                .map(|read| match_barcode(&barcodes, read, opts, &mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+        opts.tie_break = TieBreak::Random(7);
+        let first = assign(&opts);
+        assert_eq!(first, assign(&opts));
+        assert!(first.contains(&&b"AAAA"[..]) && first.contains(&&b"CCCC"[..]));
+
+        opts.tie_break = TieBreak::Random(8);
+        assert_ne!(first, assign(&opts));
+    }
+
+    #[test]
+    fn test_se_demux_revcomp() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nACCGTAGGGG\n>r2\nTACGGTGGGG\n>r3\nTTTTTTTTTT").unwrap();
+
+        for (revcomp, expected) in [
+            (false, ">r1\nACCGTAGGGG\n"),
+            (true, ">r1\nACCGTAGGGG\n>r2\nTACGGTGGGG\n"),
+        ] {
+            let sample = tempfile::NamedTempFile::new().unwrap();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+            let mut opts = DemuxOptions::default();
+            if revcomp {
+                opts.revcomp_barcodes
+                    .insert(b"ACCGTA".to_vec(), crate::utils::revcomp(b"ACCGTA"));
+            }
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            let (stats, _) = se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(std::fs::read_to_string(sample.path()).unwrap(), expected);
+            assert_eq!(stats.get(REVCOMP), revcomp.then_some(&1));
+        }
+    }
+
+    #[test]
+    fn test_se_demux_ambiguous() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nACACGGGG\n>r2\nAAAAGGGG\n>r3\nAACCGGGG").unwrap();
+
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"AAAA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"CCCC", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions {
+            mismatch: 2,
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, is_unk_empty) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(stats.get(&b"AAAA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"CCCC"[..]), None);
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&2));
+        assert_eq!(stats.get(AMBIGUOUS), Some(&2));
+        assert!(!is_unk_empty);
+    }
+
+    #[test]
+    fn test_se_demux_threads() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        let prefixes = ["ACCGTA", "ACCGTT", "ACCGTC", "CATGTC", "GGGGGG"];
+        for i in 0..5000 {
+            writeln!(
+                input,
+                "@r{}\n{}TTTT\n+\nIIIIIIIIII",
+                i,
+                prefixes[i * 7 % prefixes.len()]
+            )
+            .unwrap();
+        }
+
+        let run = |threads: usize| {
+            let outputs = (0..4)
+                .map(|_| tempfile::NamedTempFile::new().unwrap())
+                .collect::<Vec<_>>();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![outputs[0].reopen().unwrap()]));
+            bc_data.insert(b"ACCGTT", writers(vec![outputs[1].reopen().unwrap()]));
+            bc_data.insert(b"CATGTC", writers(vec![outputs[2].reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![outputs[3].reopen().unwrap()]));
+
+            // ACCGTC reads are tied between ACCGTA and ACCGTT
+            let opts = DemuxOptions {
+                mismatch: 1,
+                tie_break: TieBreak::Random(42),
+                threads,
+                ..Default::default()
+            };
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            let (stats, is_unk_empty) = se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
+            )
+            .unwrap();
+
+            let mut counts = stats
+                .iter()
+                .map(|(bc, n)| (bc.to_vec(), *n))
+                .collect::<Vec<_>>();
+            counts.sort();
+            let contents = outputs
+                .iter()
+                .map(|f| std::fs::read(f.path()).unwrap())
+                .collect::<Vec<_>>();
+            (counts, is_unk_empty, contents)
+        };
+
+        let single = run(1);
+        assert_eq!(single.0.iter().map(|(_, n)| n).sum::<u32>(), 5000);
+        assert_eq!(run(4), single);
+    }
+
+    #[test]
+    fn test_se_demux_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("a.fa"), ">r1\nACCGTAGG\n>r2\nGGGGGGGG\n").unwrap();
+        std::fs::write(dir.path().join("b.fa"), ">r3\nACCGTATT\n").unwrap();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions::default();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let files = crate::utils::expand_inputs(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(files.len(), 2);
+        for file in files.iter() {
+            se_demux(file, &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        }
+
+        assert_eq!(nb_records.get(&b"ACCGTA"[..]), Some(&2));
+        assert_eq!(nb_records.get(&b"XXX"[..]), Some(&1));
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\nACCGTAGG\n>r3\nACCGTATT\n"
+        );
+    }
+
+    #[test]
+    fn test_se_demux_copy_through() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        let content = ">r1 desc\nACCGTAGGGG\nTTTTTT\n>r2\nACCGTACCCC\n";
+        write!(input, "{}", content).unwrap();
+
+        for threads in [1, 2] {
+            let sample = tempfile::NamedTempFile::new().unwrap();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+            let opts = DemuxOptions {
+                threads,
+                ..Default::default()
+            };
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
+            )
+            .unwrap();
+
+            // records are copied as they are, wrapped ones are unwrapped
+            assert_eq!(
+                std::fs::read_to_string(sample.path()).unwrap(),
+                ">r1 desc\nACCGTAGGGGTTTTTT\n>r2\nACCGTACCCC\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_se_demux_unknown_ids() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nACCGTAGGGG\n>r2 desc\nTTTTTTTTTT\n>r3\nACCGTACCCC\n>r4\nGGGGGGGGGG"
+        )
+        .unwrap();
+
+        for threads in [1, 2] {
+            let ids = tempfile::NamedTempFile::new().unwrap();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+            bc_data.insert(
+                b"XXX",
+                writers(vec![tempfile::tempfile().unwrap(), ids.reopen().unwrap()]),
+            );
+
+            let opts = DemuxOptions {
+                threads,
+                ..Default::default()
+            };
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(
+                std::fs::read_to_string(ids.path()).unwrap(),
+                "r2 desc\nr4\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_barcode_extract() {
+        let id = b"M02542:42:000000000-ABVHU:1:2115:21477:2902 1:N:0:ACCGTA";
+        assert_eq!(HeaderBarcode::Field(11).extract(id), Some(&b"ACCGTA"[..]));
+        assert_eq!(HeaderBarcode::Field(1).extract(id), Some(&b"M02542"[..]));
+        assert_eq!(HeaderBarcode::Field(12).extract(id), None);
+        assert_eq!(HeaderBarcode::Field(0).extract(id), None);
+    }
+
+    #[test]
+    fn test_se_demux_header_regex() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">run7|sample=ACCGTA|lane=1\nGGGGGGGGGG\n\
+            >run7|sample=CATGTC|lane=1\nACCGTAGGGG\n\
+            >run7|lane=1\nACCGTAGGGG"
+        )
+        .unwrap();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"CATGTC", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions {
+            header_barcode: Some(HeaderBarcode::Regex(
+                regex::bytes::Regex::new(r"sample=([ACGTN]+)").unwrap(),
+            )),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"CATGTC"[..]), Some(&1));
+        // no barcode in the header
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">run7|sample=ACCGTA|lane=1\nGGGGGGGGGG\n"
+        );
+    }
 
-    // Get records
-    let mut fastx_reader = needletail::parse_fastx_reader(reader)?;
+    #[test]
+    fn test_se_demux_index() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
 
-    // Clone barcode values in barcode_data structure for future iteration
-    let my_vec = barcode_data.keys().cloned().collect::<Vec<_>>();
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        let mut index = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nGGGGGGGGGG\n>r2\nACCGTAGGGG\n>r3\nTTTTTTTTTT").unwrap();
+        writeln!(index, ">r1\nACCGTA\n>r2\nCATGTC\n>r3\nAAAAAA").unwrap();
 
-    // Get barcode length
-    let bc_len = my_vec[0].len();
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"CATGTC", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
 
-    // Initialize unknown file as empty
-    let mut is_unk_empty = true;
+        let opts = DemuxOptions {
+            index: Some(index.path().to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+
+        let (stats, is_unk_empty) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
 
-    // Change output compression format to user wanted compression
-    // format if specified by --format option
-    if format != niffler::send::compression::Format::No {
-        compression = format;
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"CATGTC"[..]), Some(&1));
+        assert!(!is_unk_empty);
+        // the read is written untouched
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\nGGGGGGGGGG\n"
+        );
     }
 
-    while let Some(r) = fastx_reader.next() {
-        let record = r.expect("invalid record");
+    #[test]
+    fn test_se_demux_index_errors() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        let mut index = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nGGGGGGGGGG\n>r2\nACCGTAGGGG").unwrap();
+        writeln!(index, ">r1\nACCGTA").unwrap();
 
-        // Match sequence and barcode with mismatch
-        // and return matched barcode. We first use
-        // let iter = my_vec.iter() to further stop
-        // the find at first match.
-        let mut iter = my_vec.iter();
-        let matched_barcode =
-            iter.find(|&&x| bc_cmp(x, &record.seq().as_ref()[..bc_len], mismatch));
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
 
-        if let Some(i) = matched_barcode {
-            nb_records.entry(i).and_modify(|e| *e += 1).or_insert(1);
-            write_seqs(
-                &barcode_data.get(i).unwrap()[0],
-                compression,
-                &record,
-                level,
-            )
-            .expect("file name should be available");
-        } else {
-            is_unk_empty = false;
-            write_seqs(
-                &barcode_data.get(&"XXX".as_bytes()).unwrap()[0],
-                compression,
-                &record,
-                level,
+        let mut opts = DemuxOptions {
+            index: Some(index.path().to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let err = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("ended after 1 records"));
+
+        opts.trim = true;
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        assert!(se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_se_demux_trim_length() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nACCGTANNGGGG\n>r2\nACCGTAN").unwrap();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let opts = DemuxOptions {
+            trim: true,
+            trim_length: Some(8),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        // the spacer goes with the barcode, shorter reads are emptied
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\nGGGG\n>r2\n\n"
+        );
+    }
+
+    #[test]
+    fn test_se_demux_umi() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            "@r1/1 desc\nACCGTAACGTGGGG\n+\nIIIIIIabcdJJJJ\n@r2\nTTTTTTTTTTTTTT\n+\nIIIIIIIIIIIIII"
+        )
+        .unwrap();
+
+        for trim in [false, true] {
+            let sample = tempfile::NamedTempFile::new().unwrap();
+            let unknown = tempfile::NamedTempFile::new().unwrap();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+            let opts = DemuxOptions {
+                trim,
+                umi_len: Some(4),
+                ..Default::default()
+            };
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
             )
-            .expect("file name should be available");
+            .unwrap();
+
+            let expected = if trim {
+                "@r1:UMI=ACGT/1 desc\nGGGG\n+\nJJJJ\n"
+            } else {
+                "@r1:UMI=ACGT/1 desc\nACCGTAGGGG\n+\nIIIIIIJJJJ\n"
+            };
+            assert_eq!(std::fs::read_to_string(sample.path()).unwrap(), expected);
+            // unknown reads keep their UMI-less id and sequence
+            assert!(std::fs::read_to_string(unknown.path())
+                .unwrap()
+                .starts_with("@r2\nTTTTTTTTTTTTTT\n"));
         }
     }
-    Ok((nb_records, is_unk_empty))
-}
 
-/// A function to demultiplex a pair of FASTA/FASTQ files
-pub fn pe_demux<'a>(
-    forward: &'a str,
-    reverse: &'a str,
-    format: niffler::send::compression::Format,
-    level: niffler::Level,
-    barcode_data: &'a Barcode,
-    mismatch: u8,
-    nb_records: &'a mut HashMap<&'a [u8], u32>,
-) -> anyhow::Result<(&'a mut HashMap<&'a [u8], u32>, String)> {
-    // Get fasta files reader and compression modes
-    let (forward_reader, mut compression) = niffler::send::from_path(forward)?;
-
-    let (reverse_reader, _compression) = niffler::send::from_path(reverse)?;
+    #[test]
+    fn test_se_demux_non_ascii_ids() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        input
+            .write_all(b"@r\xc3\xa91/1 \xff\xfe\nACCGTAACGTGGGG\n+\nIIIIIIabcdJJJJ\n")
+            .unwrap();
 
-    // Get records
-    let mut forward_fastx_reader = needletail::parse_fastx_reader(forward_reader)?;
-    //forward_records = forward_records.records();
-    let mut reverse_fastx_reader = needletail::parse_fastx_reader(reverse_reader)?;
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let confidence = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(CONFIDENCE, writers(vec![confidence.reopen().unwrap()]));
 
-    // Clone barcode values in barcode_data structure for future iteration
-    let my_vec = barcode_data.keys().cloned().collect::<Vec<_>>();
+        let opts = DemuxOptions {
+            umi_len: Some(4),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
 
-    // Get barcode length
-    let bc_len = my_vec[0].len();
+        // ids are rewritten and copied byte for byte
+        assert_eq!(
+            std::fs::read(sample.path()).unwrap(),
+            b"@r\xc3\xa91:UMI=ACGT/1 \xff\xfe\nACCGTAGGGG\n+\nIIIIIIJJJJ\n"
+        );
+        assert!(std::fs::read(confidence.path())
+            .unwrap()
+            .starts_with(b"r\xc3\xa91/1 \xff\xfe\tACCGTA\t0\t"));
+    }
 
-    // Initialize unknown files as empty
-    let mut unk1_empty = "true";
-    let mut unk2_empty = "true";
+    #[test]
+    fn test_se_demux_min_quality() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            "@r1\nAAAATTTTTT\n+\nIIII?5+++#\n@r2\nCCCCTTTTTT\n+\nIIII?5+++#"
+        )
+        .unwrap();
 
-    // Change output compression format to user wanted compression
-    // format if specified by --format option
-    if format != niffler::send::compression::Format::No {
-        compression = format;
-    }
+        let sample_a = tempfile::NamedTempFile::new().unwrap();
+        let sample_c = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"AAAA", writers(vec![sample_a.reopen().unwrap()]));
+        bc_data.insert(b"CCCC", writers(vec![sample_c.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
 
-    while let Some(r) = forward_fastx_reader.next() {
-        let record = r.expect("invalid record");
-        let mut iter = my_vec.iter();
-        let matched_barcode = iter.find(|&&x| bc_cmp(x, &record.seq()[..bc_len], mismatch));
+        let mut opts = DemuxOptions::default();
+        opts.barcode_min_quality.insert(b"AAAA".to_vec(), 5);
+        opts.barcode_min_quality.insert(b"CCCC".to_vec(), 20);
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
 
-        if let Some(i) = matched_barcode {
-            nb_records.entry(i).and_modify(|e| *e += 1).or_insert(1);
-            write_seqs(
-                &barcode_data.get(i).unwrap()[0],
-                compression,
-                &record,
-                level,
-            )
-            .expect("file name should be available");
-        } else {
-            unk1_empty = "false";
-            write_seqs(
-                &barcode_data.get(&"XXX".as_bytes()).unwrap()[0],
-                compression,
-                &record,
-                level,
-            )
-            .expect("file name should be available");
+        assert_eq!(
+            std::fs::read_to_string(sample_a.path()).unwrap(),
+            "@r1\nAAAATTTTT\n+\nIIII?5+++\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sample_c.path()).unwrap(),
+            "@r2\nCCCCTT\n+\nIIII?5\n"
+        );
+    }
+
+    #[test]
+    fn test_pe_sanity_check() {
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        let mut scrambled = tempfile::NamedTempFile::new().unwrap();
+        for n in 0..10 {
+            writeln!(forward, ">r{}/1\nACCGTAGGGG", n).unwrap();
+            writeln!(reverse, ">r{}/2\nTTTTTTTTTT", n).unwrap();
+            writeln!(scrambled, ">r{}/2\nTTTTTTTTTT", (n * 7) % 10).unwrap();
         }
+        let (forward, reverse, scrambled) = (
+            forward.path().to_str().unwrap(),
+            reverse.path().to_str().unwrap(),
+            scrambled.path().to_str().unwrap(),
+        );
+        let barcodes: [&[u8]; 1] = [b"ACCGTA"];
+        let opts = DemuxOptions::default();
+
+        assert!(pe_sanity_check(forward, reverse, &barcodes, &opts).is_ok());
+
+        let err = pe_sanity_check(forward, scrambled, &barcodes, &opts).unwrap_err();
+        assert!(err.to_string().contains("do not look paired"));
+
+        let err = pe_sanity_check(reverse, forward, &barcodes, &opts).unwrap_err();
+        assert!(err.to_string().contains("swapped"));
     }
 
-    while let Some(r) = reverse_fastx_reader.next() {
-        let record = r.expect("invalid record");
-        let mut iter = my_vec.iter();
-        let matched_barcode = iter.find(|&&x| bc_cmp(x, &record.seq()[..bc_len], mismatch));
+    #[test]
+    fn test_count_prefixes() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nACGTAAAA\n>r2\nTTTTCCCC\n>r3\nacgtGGGG\n>r4\nTTTTAAAA\n>r5\nACGTCCCC\n>r6\nACG"
+        )
+        .unwrap();
 
-        if let Some(i) = matched_barcode {
-            nb_records.entry(i).and_modify(|e| *e += 1).or_insert(1);
-            write_seqs(
-                &barcode_data.get(i).unwrap()[1],
-                compression,
-                &record,
-                level,
-            )
-            .expect("file name should be available");
-        } else {
-            unk2_empty = "false";
-            write_seqs(
-                &barcode_data.get(&"XXX".as_bytes()).unwrap()[1],
-                compression,
-                &record,
-                level,
+        let counts = count_prefixes(input.path().to_str().unwrap(), 4).unwrap();
+
+        assert_eq!(counts, vec![(b"ACGT".to_vec(), 3), (b"TTTT".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn test_se_demux_min_len() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nACCGTAGGGGGG\n>r2\nACCGTAGG\n>r3\nTTTTTTTTTT\n>r4\nTTT"
+        )
+        .unwrap();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let unknown = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+        let opts = DemuxOptions {
+            trim: true,
+            min_len: Some(4),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        // r2 is too short once its barcode is trimmed, r4 even untrimmed
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\nGGGGGG\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(unknown.path()).unwrap(),
+            ">r3\nTTTTTTTTTT\n"
+        );
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+        assert_eq!(stats.get(TOO_SHORT), Some(&2));
+    }
+
+    #[test]
+    fn test_se_demux_expect_length() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nACCGTAGGGG\n>r2\nACCGTAGGGGGGG\n>r3\nACCGTAGG\n>r4\nTTTTTTTTTTT\n>r5\nTTTTTTT"
+        )
+        .unwrap();
+
+        for threads in [1, 2] {
+            let sample = tempfile::NamedTempFile::new().unwrap();
+            let unknown = tempfile::NamedTempFile::new().unwrap();
+            let mut bc_data: Barcode = HashMap::new();
+            bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+            let opts = DemuxOptions {
+                expect_length: Some(9..=11),
+                threads,
+                ..Default::default()
+            };
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            let (stats, _) = se_demux(
+                input.path().to_str().unwrap(),
+                &mut bc_data,
+                &opts,
+                &mut nb_records,
+                None,
             )
-            .expect("file name should be available");
+            .unwrap();
+
+            // matched or not, reads outside 10±1 bases are dropped
+            assert_eq!(
+                std::fs::read_to_string(sample.path()).unwrap(),
+                ">r1\nACCGTAGGGG\n"
+            );
+            assert_eq!(
+                std::fs::read_to_string(unknown.path()).unwrap(),
+                ">r4\nTTTTTTTTTTT\n"
+            );
+            assert_eq!(stats.get(LENGTH_ANOMALY), Some(&3));
         }
     }
-    let mut final_str = String::with_capacity(unk1_empty.len() + unk2_empty.len());
-    final_str.push_str(unk1_empty);
-    final_str.push_str(unk2_empty);
 
-    Ok((nb_records, final_str))
-}
+    #[test]
+    fn test_pe_demux_min_len() {
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        writeln!(forward, ">r1\nACCGTAAAAA\n>r2\nACCGTACCCC\n").unwrap();
+        writeln!(reverse, ">r1\nTTTTTTTTTT\n>r2\nTT").unwrap();
 
-// Tests ----------------------------------------------------------------------
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let sample_r1 = tempfile::NamedTempFile::new().unwrap();
+        let sample_r2 = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                sample_r1.reopen().unwrap(),
+                sample_r2.reopen().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions {
+            min_len: Some(4),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) = pe_demux(
+            forward.path().to_str().unwrap(),
+            reverse.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+
+        // the short reverse mate drops the whole pair
+        assert_eq!(
+            std::fs::read_to_string(sample_r1.path()).unwrap(),
+            ">r1\nACCGTAAAAA\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sample_r2.path()).unwrap(),
+            ">r1\nTTTTTTTTTT\n"
+        );
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(TOO_SHORT), Some(&1));
+    }
 
     #[test]
-    fn test_se_demux_1() {
+    fn test_se_demux_min_mean_quality() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            "@r1\nACCGTAGG\n+\nIIIIIIII\n@r2\nACCGTAGG\n+\nIIII####\n@r3\nTTTT\n+\n####"
+        )
+        .unwrap();
+
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let unknown = tempfile::NamedTempFile::new().unwrap();
         let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+        let opts = DemuxOptions {
+            min_mean_quality: Some(30),
+            ..Default::default()
+        };
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        // r2 has a mean quality of 21, unknown reads are not filtered
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            "@r1\nACCGTAGG\n+\nIIIIIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(unknown.path()).unwrap(),
+            "@r3\nTTTT\n+\n####\n"
+        );
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(LOW_QUALITY), Some(&1));
+    }
+
+    #[test]
+    fn test_se_demux_unknown_split_length() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            ">r1\nTTTTTTTTTT\n>r2\n{}\n>r3\nACCGTAGG",
+            "T".repeat(60)
+        )
+        .unwrap();
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"XXX", vec![unknown]);
+        let unknown = tempfile::NamedTempFile::new().unwrap();
+        let buckets = UnknownSplit::Length
+            .buckets()
+            .into_iter()
+            .map(|bucket| (bucket, tempfile::NamedTempFile::new().unwrap()))
+            .collect::<Vec<_>>();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+        for (bucket, file) in buckets.iter() {
+            bc_data.insert(bucket, writers(vec![file.reopen().unwrap()]));
+        }
 
-        assert!(se_demux(
-            "tests/test.fa.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            0,
+        let opts = DemuxOptions {
+            unknown_split: Some(UnknownSplit::Length),
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, is_unk_empty) = se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap();
+        drop(bc_data);
+
+        let content = |bucket: &[u8]| {
+            let (_, file) = buckets.iter().find(|(b, _)| *b == bucket).unwrap();
+            std::fs::read_to_string(file.path()).unwrap()
+        };
+        assert_eq!(content(b"unknown_len0-19"), ">r1\nTTTTTTTTTT\n");
+        assert_eq!(
+            content(b"unknown_len50-99"),
+            format!(">r2\n{}\n", "T".repeat(60))
+        );
+        assert_eq!(content(b"unknown_len20-49"), "");
+        assert!(is_unk_empty);
+        assert_eq!(std::fs::read_to_string(unknown.path()).unwrap(), "");
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&2));
+        assert_eq!(stats.get(&b"unknown_len0-19"[..]), Some(&1));
+        assert_eq!(stats.get(&b"unknown_len50-99"[..]), Some(&1));
     }
 
     #[test]
-    fn test_se_demux_trim() {
+    fn test_candidate_barcodes_skip_buckets() {
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"ACCGTA", Vec::new());
+        bc_data.insert(b"XXX", Vec::new());
+        for bucket in UnknownSplit::Length.buckets() {
+            bc_data.insert(bucket, Vec::new());
+        }
+        assert_eq!(candidate_barcodes(&bc_data), vec![&b"ACCGTA"[..]]);
+    }
+
+    #[test]
+    fn test_unknown_split_format() {
+        let data = b">r1\nACGT\n";
+        let mut reader = needletail::parse_fastx_reader(&data[..]).unwrap();
+        let record = reader.next().unwrap().unwrap();
+        assert_eq!(UnknownSplit::Format.bucket(&record), b"unknown_fasta");
+        assert_eq!(UnknownSplit::Length.bucket(&record), b"unknown_len0-19");
+        assert!(is_extra_count(b"unknown_fastq"));
+        assert!(is_extra_count(TOO_SHORT));
+        assert!(!is_extra_count(b"ACGT"));
+    }
+
+    // Output lagging behind the reader, as the file of a sample getting
+    // most of the reads
+    struct SlowWriter(std::fs::File);
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            std::thread::sleep(std::time::Duration::from_micros(20));
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Output for SlowWriter {
+        fn finish(&mut self) -> io::Result<()> {
+            self.flush()
+        }
+    }
+
+    #[test]
+    fn test_se_demux_parallel_max_memory() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        for i in 0..10 * BATCH_SIZE {
+            let bc = if i % 100 == 0 { "CATGTC" } else { "ACCGTA" };
+            writeln!(input, ">r{}\n{}{}", i, bc, "T".repeat(44)).unwrap();
+        }
+
+        let dominant = tempfile::NamedTempFile::new().unwrap();
         let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            vec![Box::new(SlowWriter(dominant.reopen().unwrap())) as Box<dyn Output>],
+        );
+        bc_data.insert(b"CATGTC", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+
+        // about two batches of the input
+        let max_memory = 250_000;
+        let opts = DemuxOptions {
+            threads: 2,
+            max_memory: Some(max_memory),
+            ..Default::default()
+        };
+        let budget = MemoryBudget::new(opts.max_memory);
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) = se_demux_parallel(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+            &budget,
+        )
+        .unwrap();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        assert!(budget.peak() > 0);
+        assert!(budget.peak() <= max_memory, "peak {}", budget.peak());
+        assert_eq!(
+            stats.get(&b"ACCGTA"[..]),
+            Some(&(10 * BATCH_SIZE as u32 - 103))
+        );
+        assert_eq!(stats.get(&b"CATGTC"[..]), Some(&103));
+    }
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"XXX", vec![unknown]);
+    #[test]
+    fn test_assignment_margin() {
+        let barcodes: Vec<&[u8]> = vec![b"AAAAAA", b"AAAACC", b"CCCCCC"];
+        let opts = DemuxOptions::default();
 
-        assert!(se_demux(
-            "tests/test.fa.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            0,
+        // 0 from AAAAAA, 2 from AAAACC
+        assert_eq!(
+            assignment_margin(&barcodes, b"AAAAAA", b"AAAAAATT", &opts),
+            (0, 2)
+        );
+        // 1 from both AAAAAA and AAAACC
+        assert_eq!(
+            assignment_margin(&barcodes, b"AAAAAA", b"AAAACATT", &opts),
+            (1, 0)
+        );
+        // no other barcode
+        assert_eq!(
+            assignment_margin(&barcodes[..1], b"AAAAAA", b"AAAAAA", &opts),
+            (0, 6)
+        );
+    }
+
+    #[test]
+    fn test_se_demux_confidence() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">clear\nAAAAAATT\n>tied\nAAAACATT\n>none\nGGGGGGGG").unwrap();
+
+        let confidence = tempfile::NamedTempFile::new().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(b"AAAAAA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"AAAACC", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(CONFIDENCE, writers(vec![confidence.reopen().unwrap()]));
+
+        let opts = DemuxOptions {
+            mismatch: 1,
+            tie_break: TieBreak::Lexical,
+            ..Default::default()
+        };
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap();
+
+        // unknown reads get no line
+        assert_eq!(
+            std::fs::read_to_string(confidence.path()).unwrap(),
+            "clear\tAAAAAA\t0\t2\t0.333\ntied\tAAAAAA\t1\t0\t0.000\n"
+        );
     }
 
     #[test]
-    fn test_se_demux_m1() {
+    fn test_pe_demux_interleaved() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            input,
+            "@r1/1\nACCGTAAAAA\n+\nIIIIIIIIII\n@r1/2\nGGGG\n+\nIIII\n\
+            @r2/1\nTTTTTTTTTT\n+\nIIIIIIIIII\n@r2/2\nCCCC\n+\nIIII"
+        )
+        .unwrap();
+
+        let outputs = (0..4)
+            .map(|_| tempfile::NamedTempFile::new().unwrap())
+            .collect::<Vec<_>>();
         let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                outputs[0].reopen().unwrap(),
+                outputs[1].reopen().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                outputs[2].reopen().unwrap(),
+                outputs[3].reopen().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions {
+            interleaved: true,
+            ..Default::default()
+        };
+        let path = input.path().to_str().unwrap();
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let (stats, _) =
+            pe_demux(path, "unused", &mut bc_data, &opts, &mut nb_records, None).unwrap();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let reverse = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        let contents = outputs
+            .iter()
+            .map(|f| std::fs::read_to_string(f.path()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents[0], "@r1/1\nACCGTAAAAA\n+\nIIIIIIIIII\n");
+        assert_eq!(contents[1], "@r1/2\nGGGG\n+\nIIII\n");
+        assert_eq!(contents[2], "@r2/1\nTTTTTTTTTT\n+\nIIIIIIIIII\n");
+        assert_eq!(contents[3], "@r2/2\nCCCC\n+\nIIII\n");
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+    }
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"ATTGTT", vec![reverse]);
-        bc_data.insert(b"XXX", vec![unknown]);
+    #[test]
+    fn test_pe_demux_interleaved_odd() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1/1\nACCGTAAAAA\n>r1/2\nGGGG\n>r2/1\nACCGTAAAAA").unwrap();
 
-        assert!(se_demux(
-            "tests/test.fa.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            1,
+        let mut bc_data: Barcode = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions {
+            interleaved: true,
+            ..Default::default()
+        };
+        let path = input.path().to_str().unwrap();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let err = pe_demux(path, "unused", &mut bc_data, &opts, &mut nb_records, None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("odd number of records"), "{}", err);
+    }
+
+    #[test]
+    fn test_pe_demux_normalize_ids() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        writeln!(forward, ">r1/1 extra\nACCGTAAAAA").unwrap();
+        writeln!(reverse, ">r1/2 extra\nTTTTTTTTTT").unwrap();
+
+        let sample_r1 = tempfile::NamedTempFile::new().unwrap();
+        let sample_r2 = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                sample_r1.reopen().unwrap(),
+                sample_r2.reopen().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions {
+            normalize_ids: true,
+            ..Default::default()
+        };
+
+        pe_demux(
+            forward.path().to_str().unwrap(),
+            reverse.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap();
+
+        let header = |f: &tempfile::NamedTempFile| {
+            let content = std::fs::read_to_string(f.path()).unwrap();
+            content.lines().next().unwrap().to_string()
+        };
+        assert_eq!(header(&sample_r1), ">r1");
+        assert_eq!(header(&sample_r1), header(&sample_r2));
     }
 
     #[test]
-    fn test_se_demux_m2() {
+    fn test_pe_demux_keep_barcode_in_id() {
         let mut bc_data: Barcode = HashMap::new();
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let reverse = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        writeln!(forward, ">r1/1 extra\nACCGTCAAAA\n>r2/1\nGGGGGGGGGG").unwrap();
+        writeln!(reverse, ">r1/2 extra\nTTTTTTTTTT\n>r2/2\nCCCCCCCCCC").unwrap();
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"ATTGTT", vec![reverse]);
-        bc_data.insert(b"XXX", vec![unknown]);
+        let sample_r1 = tempfile::NamedTempFile::new().unwrap();
+        let sample_r2 = tempfile::NamedTempFile::new().unwrap();
+        let unknown_r1 = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                sample_r1.reopen().unwrap(),
+                sample_r2.reopen().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                unknown_r1.reopen().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
 
-        assert!(se_demux(
-            "tests/test.fa.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            2,
+        let opts = DemuxOptions {
+            mismatch: 1,
+            keep_barcode_in_id: true,
+            ..Default::default()
+        };
+        pe_demux(
+            forward.path().to_str().unwrap(),
+            reverse.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap();
+
+        // both mates carry the panel barcode, sequences are untouched
+        assert_eq!(
+            std::fs::read_to_string(sample_r1.path()).unwrap(),
+            ">r1/1 extra\tBC:Z:ACCGTA\nACCGTCAAAA\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sample_r2.path()).unwrap(),
+            ">r1/2 extra\tBC:Z:ACCGTA\nTTTTTTTTTT\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(unknown_r1.path()).unwrap(),
+            ">r2/1\nGGGGGGGGGG\n"
+        );
     }
 
     #[test]
-    fn test_se_demux_2() {
+    fn test_se_demux_annotate_distance() {
         let mut bc_data: Barcode = HashMap::new();
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let reverse = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nACCGTAAAAA\n>r2\nACCGTCAAAA\n>r3\nGGGGGGGGGG").unwrap();
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        let unknown = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+
+        let opts = DemuxOptions {
+            mismatch: 1,
+            keep_barcode_in_id: true,
+            annotate_distance: true,
+            ..Default::default()
+        };
+        se_demux(
+            input.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
+            &mut nb_records,
+            None,
+        )
+        .unwrap();
+        drop(bc_data);
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"ATTGTT", vec![reverse]);
-        bc_data.insert(b"XXX", vec![unknown]);
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1\tBC:Z:ACCGTA\tmm:i:0\nACCGTAAAAA\n>r2\tBC:Z:ACCGTA\tmm:i:1\nACCGTCAAAA\n"
+        );
+        // unknown reads have no barcode to be compared to
+        assert_eq!(
+            std::fs::read_to_string(unknown.path()).unwrap(),
+            ">r3\nGGGGGGGGGG\n"
+        );
+    }
 
-        assert!(se_demux(
-            "tests/test.fq.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            0,
+    #[test]
+    fn test_se_demux_input_format() {
+        let mut input = tempfile::NamedTempFile::new().unwrap();
+        writeln!(input, ">r1\nACCGTAAAAA\n>r2\nGGGGGGGGGG").unwrap();
+        let path = input.path().to_str().unwrap();
+        let demux = |input_format| {
+            let mut bc_data: Barcode = HashMap::new();
+            let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+            let sample = tempfile::NamedTempFile::new().unwrap();
+            bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+            bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+            let opts = DemuxOptions {
+                input_format,
+                ..Default::default()
+            };
+            se_demux(path, &mut bc_data, &opts, &mut nb_records, None)
+                .is_ok()
+                .then(|| std::fs::read_to_string(sample.path()).unwrap())
+        };
+
+        let sample = Some(">r1\nACCGTAAAAA\n".to_string());
+        assert_eq!(demux(None), sample);
+        assert_eq!(demux(Some(needletail::parser::Format::Fasta)), sample);
+        // fasta records are not read as fastq ones
+        assert_eq!(demux(Some(needletail::parser::Format::Fastq)), None);
+    }
+
+    #[test]
+    fn test_pe_demux_format_mismatch() {
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+
+        let opts = DemuxOptions::default();
+        let err = pe_demux(
+            "tests/test.fq",
+            "tests/reads_2.fa",
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "forward file 'tests/test.fq' is FASTQ but reverse file 'tests/reads_2.fa' is FASTA"
+        );
     }
 
     #[test]
-    fn test_se_demux_m3() {
+    fn test_demux_read_shorter_than_barcode() {
+        let mut forward = tempfile::NamedTempFile::new().unwrap();
+        writeln!(forward, ">r1\nACCG\n>r2\nACCGTAAAAA").unwrap();
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        writeln!(reverse, ">r1\nTTTT\n>r2\nTTTTTTTTTT").unwrap();
+        let forward = forward.path().to_str().unwrap();
+        let reverse = reverse.path().to_str().unwrap();
+        let opts = DemuxOptions {
+            trim: true,
+            umi_len: Some(2),
+            ..Default::default()
+        };
+
         let mut bc_data: Barcode = HashMap::new();
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let unknown = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![unknown.reopen().unwrap()]));
+        se_demux(forward, &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        drop(bc_data);
+        assert_eq!(nb_records.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(nb_records.get(&b"XXX"[..]), Some(&1));
+        assert_eq!(
+            std::fs::read_to_string(unknown.path()).unwrap(),
+            ">r1\nACCG\n"
+        );
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let reverse = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        let (stats, _) =
+            pe_demux(forward, reverse, &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        assert_eq!(stats.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.get(&b"XXX"[..]), Some(&1));
+
+        // Two deletions let the short read match, it is trimmed to nothing
+        let opts = DemuxOptions {
+            mismatch: 2,
+            edit_distance: true,
+            ..opts
+        };
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        let sample = tempfile::NamedTempFile::new().unwrap();
+        bc_data.insert(b"ACCGTA", writers(vec![sample.reopen().unwrap()]));
+        bc_data.insert(b"XXX", writers(vec![tempfile::tempfile().unwrap()]));
+        se_demux(forward, &mut bc_data, &opts, &mut nb_records, None).unwrap();
+        drop(bc_data);
+        assert_eq!(nb_records.get(&b"ACCGTA"[..]), Some(&2));
+        assert_eq!(
+            std::fs::read_to_string(sample.path()).unwrap(),
+            ">r1:UMI=\n\n>r2:UMI=AA\nAA\n"
+        );
+    }
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"ATTGTT", vec![reverse]);
-        bc_data.insert(b"XXX", vec![unknown]);
+    // Gzip file of `nb` fastq records, cut before its end
+    fn truncated_gzip(nb: usize) -> tempfile::NamedTempFile {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        for i in 0..nb {
+            writeln!(encoder, "@r{}\nACCGTAAAAA\n+\nIIIIIIIIII", i).unwrap();
+        }
+        let data = encoder.finish().unwrap();
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&data[..data.len() - 10]).unwrap();
+        file
+    }
 
-        assert!(se_demux(
-            "tests/test.fq.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            1,
+    #[test]
+    fn test_demux_truncated_gzip() {
+        let input = truncated_gzip(1000);
+        let path = input.path().to_str().unwrap();
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(b"ACCGTA", writers(vec![tempfile::tempfile().unwrap()]));
+
+        let err = se_demux(
+            path,
+            &mut bc_data,
+            &DemuxOptions::default(),
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("file '{}' appears truncated after", path)));
+
+        let mut bc_data: Barcode = HashMap::new();
+        let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        let err = pe_demux(
+            path,
+            path,
+            &mut bc_data,
+            &DemuxOptions::default(),
+            &mut nb_records,
+            None,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("forward file '{}' appears truncated after", path)));
     }
 
     #[test]
-    fn test_se_demux_m4() {
+    fn test_pe_demux_malformed_record() {
         let mut bc_data: Barcode = HashMap::new();
         let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
 
-        let forward = tempfile::tempfile().expect("Cannot create temp file");
-        let reverse = tempfile::tempfile().expect("Cannot create temp file");
-        let unknown = tempfile::tempfile().expect("Cannot create temp file");
+        let mut reverse = tempfile::NamedTempFile::new().unwrap();
+        write!(reverse, "@r1\nACGT\n+\nIIII\n@r2\nACGT\n+\nII").unwrap();
 
-        bc_data.insert(b"ACCGTA", vec![forward]);
-        bc_data.insert(b"ATTGTT", vec![reverse]);
-        bc_data.insert(b"XXX", vec![unknown]);
+        bc_data.insert(
+            b"ACCGTA",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
+        bc_data.insert(
+            b"XXX",
+            writers(vec![
+                tempfile::tempfile().unwrap(),
+                tempfile::tempfile().unwrap(),
+            ]),
+        );
 
-        assert!(se_demux(
-            "tests/test.fq.gz",
-            niffler::send::compression::Format::Gzip,
-            niffler::Level::One,
-            &bc_data,
-            2,
+        let opts = DemuxOptions::default();
+
+        let err = pe_demux(
+            "tests/test.fq",
+            reverse.path().to_str().unwrap(),
+            &mut bc_data,
+            &opts,
             &mut nb_records,
+            None,
         )
-        .is_ok());
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("malformed record 2 in reverse file"));
     }
 }
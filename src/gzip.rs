@@ -0,0 +1,258 @@
+// Copyright 2021-2024 Anicet Ebou.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Gzip outputs compressed on several threads.
+//!
+//! A [`GzipPool`] holds the threads compressing the blocks of all the
+//! [`ParallelGzWriter`]s of a run. As with pigz, each block is deflated on
+//! its own and ends on a byte boundary, so that the blocks join into a
+//! single gzip member readable by any gzip decoder.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::thread;
+
+use crossbeam_channel::{Receiver, Sender};
+
+// Uncompressed size of the blocks compressed in parallel
+const BLOCK_SIZE: usize = 1 << 20;
+
+// Gzip header without name nor timestamp, of an unknown OS
+const HEADER: [u8; 10] = [0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff];
+
+// Empty final deflate block, with fixed Huffman codes
+const LAST_BLOCK: [u8; 2] = [0x03, 0x00];
+
+// Deflated block with the CRC of its uncompressed content
+type Compressed = io::Result<(Vec<u8>, flate2::Crc)>;
+
+// A block to compress and where to send it once compressed
+struct Job {
+    data: Vec<u8>,
+    level: flate2::Compression,
+    done: Sender<Compressed>,
+}
+
+/// Threads compressing the blocks of gzip outputs, shared by all the
+/// outputs of a run. The threads stop once the pool and the writers using
+/// it are dropped.
+#[derive(Clone)]
+pub struct GzipPool {
+    jobs: Sender<Job>,
+    threads: usize,
+}
+
+impl GzipPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, queue) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads.max(1) {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for job in queue {
+                    // The writer may be gone after a failure
+                    let _ = job.done.send(deflate_block(&job.data, job.level));
+                }
+            });
+        }
+
+        GzipPool {
+            jobs,
+            threads: threads.max(1),
+        }
+    }
+}
+
+// Deflate a block, ending it on a byte boundary with a sync flush so that
+// the next block can follow it. The output has room for the block stored
+// uncompressed, so that it is deflated in a single call: flushing the
+// flate2 writers loses data at the fastest level.
+fn deflate_block(data: &[u8], level: flate2::Compression) -> Compressed {
+    let mut compress = flate2::Compress::new(level, false);
+    let mut out = Vec::with_capacity(data.len() + data.len() / 1000 + 64);
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress
+            .compress_vec(&data[consumed..], &mut out, flate2::FlushCompress::Sync)
+            .map_err(io::Error::other)?;
+        if compress.total_in() as usize == data.len() && out.len() < out.capacity() {
+            break;
+        }
+        out.reserve(out.capacity());
+    }
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+
+    Ok((out, crc))
+}
+
+/// Writer compressing its content as a single gzip member, blocks being
+/// deflated by the threads of a [`GzipPool`] and written in order. The
+/// pending block and the gzip trailer are written when the writer is
+/// dropped.
+pub struct ParallelGzWriter<W: Write> {
+    inner: W,
+    pool: GzipPool,
+    level: flate2::Compression,
+    // Uncompressed content of the next block
+    block: Vec<u8>,
+    // Blocks being compressed, in output order
+    pending: VecDeque<Receiver<Compressed>>,
+    // CRC and size of the content written so far
+    crc: flate2::Crc,
+    finished: bool,
+}
+
+impl<W: Write> ParallelGzWriter<W> {
+    pub fn new(mut inner: W, pool: &GzipPool, level: flate2::Compression) -> io::Result<Self> {
+        inner.write_all(&HEADER)?;
+
+        Ok(ParallelGzWriter {
+            inner,
+            pool: pool.clone(),
+            level,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            pending: VecDeque::new(),
+            crc: flate2::Crc::new(),
+            finished: false,
+        })
+    }
+
+    // Hand the current block to the pool, writing the oldest compressed
+    // blocks when too many are in flight
+    fn send_block(&mut self) -> io::Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let (done, compressed) = crossbeam_channel::bounded(1);
+        let job = Job {
+            data: std::mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE)),
+            level: self.level,
+            done,
+        };
+        self.pool
+            .jobs
+            .send(job)
+            .map_err(|_| io::Error::other("gzip threads stopped"))?;
+        self.pending.push_back(compressed);
+        while self.pending.len() > 2 * self.pool.threads {
+            self.write_next()?;
+        }
+
+        Ok(())
+    }
+
+    // Wait for the oldest pending block and write it
+    fn write_next(&mut self) -> io::Result<()> {
+        if let Some(compressed) = self.pending.pop_front() {
+            let (data, crc) = compressed
+                .recv()
+                .map_err(|_| io::Error::other("gzip threads stopped"))??;
+            self.inner.write_all(&data)?;
+            self.crc.combine(&crc);
+        }
+
+        Ok(())
+    }
+
+    /// Write the pending blocks, the final block and the gzip trailer.
+    /// Dropping the writer does the same but ignores the errors.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.send_block()?;
+        while !self.pending.is_empty() {
+            self.write_next()?;
+        }
+        self.inner.write_all(&LAST_BLOCK)?;
+        self.inner.write_all(&self.crc.sum().to_le_bytes())?;
+        self.inner.write_all(&self.crc.amount().to_le_bytes())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for ParallelGzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..len]);
+        if self.block.len() == BLOCK_SIZE {
+            self.send_block()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_block()?;
+        while !self.pending.is_empty() {
+            self.write_next()?;
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for ParallelGzWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Tests --------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use std::io::Read;
+
+    #[test]
+    fn test_parallel_gz_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut data = Vec::new();
+        for i in 0..20_000 {
+            let seq = (0..60)
+                .map(|_| *b"ACGT".choose(&mut rng).unwrap() as char)
+                .collect::<String>();
+            writeln!(data, "@r{}\n{}\n+\n{}", i, seq, "I".repeat(60)).unwrap();
+        }
+        let pool = GzipPool::new(4);
+
+        let mut out = Vec::new();
+        {
+            let mut writer =
+                ParallelGzWriter::new(&mut out, &pool, flate2::Compression::fast()).unwrap();
+            for chunk in data.chunks(1000) {
+                writer.write_all(chunk).unwrap();
+            }
+        }
+        assert!(data.len() > 2 * BLOCK_SIZE);
+
+        // a single member, checked by the decoder against CRC and size
+        let mut content = Vec::new();
+        flate2::read::GzDecoder::new(&out[..])
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, data);
+        let (mut decoder, format) = niffler::send::get_reader(Box::new(&out[..])).unwrap();
+        assert_eq!(format, niffler::send::compression::Format::Gzip);
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content).unwrap();
+        assert_eq!(content, data);
+    }
+
+    #[test]
+    fn test_parallel_gz_empty() {
+        let pool = GzipPool::new(2);
+        let mut out = Vec::new();
+        drop(ParallelGzWriter::new(&mut out, &pool, flate2::Compression::fast()).unwrap());
+
+        let mut content = Vec::new();
+        flate2::read::GzDecoder::new(&out[..])
+            .read_to_end(&mut content)
+            .unwrap();
+        assert!(content.is_empty());
+    }
+}
@@ -0,0 +1,1988 @@
+// Copyright 2021-2024 Anicet Ebou.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Barcode demultiplexing of FASTA/FASTQ files.
+//!
+//! [`demultiplex`] runs a whole demultiplexing as the `sabreur` command
+//! does, from a [`DemuxConfig`]. The lower level [`se_demux`] and
+//! [`pe_demux`] demultiplex files into already opened writers.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+
+pub mod demux;
+pub mod gzip;
+pub mod s3;
+pub mod ubam;
+pub mod utils;
+
+pub use demux::{pe_demux, se_demux, Barcode, DemuxOptions, UnknownStatus};
+
+/// Settings of a demultiplexing run
+pub struct DemuxConfig {
+    /// Barcode file: a barcode and its output file names on each line
+    pub barcode: String,
+    /// Barcodes and their output names given directly, used instead of the
+    /// barcode file, which must then be empty. The two output names of
+    /// paired-end mode are given as a single `R1,R2` name.
+    pub barcode_seqs: Vec<(String, String)>,
+    /// Field delimiter of the barcode file, a tab or else a comma when None
+    pub delimiter: Option<char>,
+    /// Forward reads, a file, directory, glob pattern or `-` for stdin
+    pub forward: String,
+    /// Reverse reads, for paired-end mode, a file or a directory or glob
+    /// pattern giving the mates of the forward files in sorted order
+    pub reverse: Option<String>,
+    /// Output directory
+    pub output: PathBuf,
+    /// Erase the output directory if it already exists
+    pub force: bool,
+    /// Write into the output directory if it already exists, appending
+    /// the reads to its files of the same name
+    pub append: bool,
+    /// Compression of the output files, that of the inputs when None
+    pub format: Option<niffler::send::compression::Format>,
+    /// Compression of the reverse output files in paired-end mode, that of
+    /// `format` when None
+    pub reverse_format: Option<niffler::send::compression::Format>,
+    /// Take the input compression from the file extension when it tells,
+    /// rather than reading the start of the file
+    pub trust_ext: bool,
+    /// Compression level of the output files
+    pub level: niffler::Level,
+    /// Level and long distance matching of the zstd output files, instead
+    /// of `level`
+    pub zstd: Option<utils::ZstdTuning>,
+    /// Number of threads compressing the gzip output files, deflating
+    /// blocks in parallel when more than one
+    pub gzip_threads: usize,
+    /// File of the expected read count of each barcode, sizing the buffers
+    /// of their output files
+    pub expected_counts: Option<String>,
+    /// When the buffers of the output files are written out, the lru
+    /// policy keeping them under `options.max_memory` bytes, 16 MiB by
+    /// default
+    pub flush_policy: utils::FlushPolicy,
+    /// Demultiplex only these samples, named by barcode or output file,
+    /// see [`utils::select_samples`]. All samples when empty.
+    pub only: Vec<String>,
+    /// Discard the reads of the samples left out by `only` rather than
+    /// writing them as unknown
+    pub discard_others: bool,
+    /// Write all sample reads to this single output file, grouped by
+    /// sample in barcode file order, instead of one file per sample.
+    /// Single-end mode only.
+    pub grouped_output: Option<String>,
+    /// Directory of the temporary files of `grouped_output` and `shuffle`,
+    /// the system one when None
+    pub tmpdir: Option<PathBuf>,
+    /// Shuffle the reads of each sample with this seed, see
+    /// [`utils::shuffle_outputs`]. The spilling of reads to temporary files
+    /// follows `options.max_memory`.
+    pub shuffle: Option<u64>,
+    /// File of the allowed read count range of barcodes, the run failing
+    /// when a final count is out of its range
+    pub count_bounds: Option<String>,
+    /// Write the unknown pairs to a single interleaved file, alternating
+    /// forward and reverse mates. Paired-end mode only.
+    pub interleaved_unknown: bool,
+    /// Base name of the unknown output files, followed by `_R1` and `_R2`
+    /// in paired-end mode and by the extension of the input format
+    pub unknown_prefix: String,
+    /// Keep unknown output files which received no read
+    pub keep_empty_outputs: bool,
+    /// Count the unknown reads without writing them anywhere
+    pub no_unknown: bool,
+    /// Read both paired-end output names from a single `R1,R2` field
+    pub split_outputs: bool,
+    /// Print the planned output files and stop
+    pub dry_run: bool,
+    /// Match and count all reads but write none, the output directory not
+    /// being created. Reports and stats are still written.
+    pub count_only: bool,
+    /// Check on the first pairs that the inputs look paired. Inputs read
+    /// from stdin or a pipe cannot be checked.
+    pub sanity_check: bool,
+    /// Also match the reverse complement of barcodes
+    pub revcomp: bool,
+    /// Pool the samples with less reads than this
+    pub min_reads: Option<u32>,
+    /// Write the per-barcode read counts to this TSV file
+    pub stats_tsv: Option<PathBuf>,
+    /// Print the per-barcode read counts table to stdout at the end
+    pub summary: bool,
+    /// Write the ids of unknown reads to this file
+    pub unknown_ids: Option<PathBuf>,
+    /// Write the confidence of each read assignment to this TSV file, see
+    /// [`demux::CONFIDENCE`]
+    pub confidence: Option<PathBuf>,
+    /// Write the decompressed forward input, or the interleaved one, to
+    /// this file while demultiplexing
+    pub tee_decompressed: Option<PathBuf>,
+    /// Write the hash of the resolved panel, see [`utils::panel_hash`], to
+    /// this file
+    pub panel_hash: Option<PathBuf>,
+    /// Write a JSON summary of the run to this file
+    pub run_info: Option<PathBuf>,
+    /// Write a JSON report of the run, counts and output files to this file
+    pub report_json: Option<PathBuf>,
+    /// Write the absolute paths of the output files of each sample to this
+    /// TSV file, see [`utils::RunReport::write_manifest`]
+    pub manifest: Option<PathBuf>,
+    /// Write unaligned BAM outputs, named after the barcode file ones with
+    /// a `.bam` extension, instead of fasta or fastq. Needs the `ubam`
+    /// feature.
+    pub ubam: bool,
+    /// Upload the outputs to this S3 bucket and key prefix instead of
+    /// writing them to `output`. Needs the `s3` feature.
+    pub s3: Option<s3::S3Target>,
+    /// Periodic emission of the running counts
+    pub live_stats: Option<utils::LiveStats>,
+    /// Draw a progress bar on stderr of the bytes read from the forward
+    /// input, when its size is known
+    pub progress: bool,
+    /// Matching options. The per-barcode mismatches, minimum qualities and
+    /// reverse complements are filled from the barcode file and `revcomp`.
+    pub options: DemuxOptions,
+}
+
+impl Default for DemuxConfig {
+    fn default() -> Self {
+        DemuxConfig {
+            barcode: String::new(),
+            barcode_seqs: Vec::new(),
+            delimiter: None,
+            forward: String::new(),
+            reverse: None,
+            output: PathBuf::from("sabreur_out"),
+            force: false,
+            append: false,
+            format: None,
+            reverse_format: None,
+            trust_ext: false,
+            level: niffler::Level::One,
+            zstd: None,
+            gzip_threads: 1,
+            expected_counts: None,
+            flush_policy: utils::FlushPolicy::Lazy,
+            only: Vec::new(),
+            discard_others: false,
+            grouped_output: None,
+            tmpdir: None,
+            shuffle: None,
+            count_bounds: None,
+            interleaved_unknown: false,
+            unknown_prefix: "unknown".to_string(),
+            no_unknown: false,
+            keep_empty_outputs: false,
+            split_outputs: false,
+            dry_run: false,
+            count_only: false,
+            sanity_check: false,
+            revcomp: false,
+            min_reads: None,
+            stats_tsv: None,
+            summary: false,
+            unknown_ids: None,
+            confidence: None,
+            tee_decompressed: None,
+            panel_hash: None,
+            run_info: None,
+            report_json: None,
+            manifest: None,
+            ubam: false,
+            s3: None,
+            live_stats: None,
+            progress: false,
+            options: DemuxOptions::default(),
+        }
+    }
+}
+
+/// Outcome of a demultiplexing run
+pub struct Stats {
+    /// Number of reads, or pairs, of each barcode, `XXX` holding the
+    /// unknown ones, plus the counters of [`demux::EXTRA_COUNTS`] and the
+    /// buckets of split unknown reads
+    pub counts: HashMap<Vec<u8>, u32>,
+    /// Time spent in each phase of the run
+    pub timings: utils::PhaseTimings,
+    /// Size of the input files, None when one of them is stdin, a pipe or
+    /// remote
+    pub input_bytes: Option<u64>,
+}
+
+impl Stats {
+    /// Number of reads, or pairs, read: those written under a barcode or
+    /// as unknown, plus the dropped ones
+    pub fn total_records(&self) -> u64 {
+        utils::total_records(self.counts.iter().map(|(key, n)| (key.as_slice(), *n)))
+    }
+}
+
+/// Demultiplex the reads of `config.forward`, and `config.reverse` in
+/// paired-end mode, into one file per barcode of `config.barcode`
+pub fn demultiplex(config: DemuxConfig) -> anyhow::Result<Stats> {
+    let mut timings = utils::PhaseTimings::new(Instant::now());
+    let DemuxConfig {
+        barcode,
+        forward,
+        reverse,
+        output,
+        force,
+        format,
+        trust_ext,
+        level,
+        keep_empty_outputs: keep_empty,
+        mut live_stats,
+        mut options,
+        ..
+    } = config;
+    let output = &output;
+    let ubam = config.ubam;
+    if ubam && !cfg!(feature = "ubam") {
+        return Err(anyhow!(
+            "sabreur was built without uBAM support, rebuild it with `--features ubam`"
+        ));
+    }
+    if ubam && config.min_reads.is_some() {
+        return Err(anyhow!("uBAM outputs cannot be pooled with --min-reads"));
+    }
+    if config.grouped_output.is_some() && (ubam || config.min_reads.is_some()) {
+        return Err(anyhow!(
+            "a grouped output cannot be written as uBAM or pooled with --min-reads"
+        ));
+    }
+    if config.count_only
+        && (config.grouped_output.is_some()
+            || config.shuffle.is_some()
+            || config.min_reads.is_some())
+    {
+        return Err(anyhow!(
+            "no reads are written when only counting, they cannot be grouped, shuffled or pooled"
+        ));
+    }
+    if ubam && config.shuffle.is_some() {
+        return Err(anyhow!("uBAM outputs cannot be shuffled"));
+    }
+    if config.append
+        && (force || ubam || config.grouped_output.is_some() || config.min_reads.is_some())
+    {
+        return Err(anyhow!(
+            "appending to existing outputs cannot be forced, written as uBAM, grouped or pooled"
+        ));
+    }
+    if config.s3.is_some() && !cfg!(feature = "s3") {
+        return Err(anyhow!(
+            "sabreur was built without S3 support, rebuild it with `--features s3`"
+        ));
+    }
+    if config.s3.is_some()
+        && (ubam
+            || config.append
+            || config.grouped_output.is_some()
+            || config.shuffle.is_some()
+            || config.min_reads.is_some())
+    {
+        return Err(anyhow!(
+            "S3 outputs cannot be written as uBAM, appended to, grouped, shuffled or pooled"
+        ));
+    }
+    // Previous reads may lie in the files left without new ones, and
+    // uploaded objects are not removed
+    let keep_empty = keep_empty || config.append || config.s3.is_some();
+    if ubam && config.reverse_format.is_some() {
+        return Err(anyhow!(
+            "uBAM outputs cannot be given a reverse compression"
+        ));
+    }
+
+    // Interleaved mates are all read from the forward file, which stands
+    // for the reverse one too
+    let interleaved = options.interleaved;
+    if interleaved && reverse.is_some() {
+        return Err(anyhow!(
+            "a reverse file cannot be given with interleaved paired-end input"
+        ));
+    }
+    let reverse = if interleaved {
+        Some(forward.clone())
+    } else {
+        reverse
+    };
+    if config.grouped_output.is_some() && reverse.is_some() {
+        return Err(anyhow!(
+            "a grouped output is only supported in single-end mode"
+        ));
+    }
+    if config.interleaved_unknown && (reverse.is_none() || ubam) {
+        return Err(anyhow!(
+            "interleaved unknown pairs need paired-end input and fasta or fastq outputs"
+        ));
+    }
+    if config.reverse_format.is_some() && reverse.is_none() {
+        return Err(anyhow!(
+            "a reverse output compression needs paired-end input"
+        ));
+    }
+
+    // A directory or glob pattern gives several forward files, demultiplexed
+    // one after the other into the same outputs. Reverse files pair with
+    // them in sorted order, e.g. the lanes of a sample.
+    let forward_files = utils::expand_inputs(&forward)?;
+    if forward_files.len() > 1 && options.index.is_some() {
+        return Err(anyhow!(
+            "'{}' gives several forward files, they cannot share an index file",
+            forward
+        ));
+    }
+    let reverse_files = match &reverse {
+        Some(_) if interleaved => forward_files.clone(),
+        Some(reverse) => utils::expand_inputs(reverse)?,
+        None => Vec::new(),
+    };
+    if let Some(reverse) = reverse.as_deref().filter(|_| !interleaved) {
+        if reverse_files.len() != forward_files.len() {
+            return Err(anyhow!(
+                "'{}' gives {} forward files but '{}' gives {} reverse files",
+                forward,
+                forward_files.len(),
+                reverse,
+                reverse_files.len()
+            ));
+        }
+        // Sorted order only pairs the right files when their names agree
+        if forward_files.len() > 1 {
+            for (forward, reverse) in forward_files.iter().zip(reverse_files.iter()) {
+                if !utils::is_mate_pair(forward, reverse) {
+                    return Err(anyhow!(
+                        "forward file '{}' pairs with reverse file '{}', which is not its mate",
+                        forward,
+                        reverse
+                    ));
+                }
+            }
+        }
+    }
+
+    // The check reads the first pairs on their own, which a stream would
+    // not give back to the demultiplexing
+    if config.sanity_check && !interleaved {
+        if let Some(stream) = forward_files
+            .iter()
+            .chain(reverse_files.iter())
+            .find(|path| utils::is_stream(path))
+        {
+            return Err(anyhow!(
+                "--sanity-check reads the inputs twice, it cannot check the stream '{}'",
+                stream
+            ));
+        }
+    }
+
+    let progress = match config.progress {
+        true => {
+            utils::inputs_size(&forward_files).map(|total| (total, utils::ByteCounter::default()))
+        }
+        false => None,
+    };
+    options.progress = progress.as_ref().map(|(_, counter)| counter.clone());
+    // Interleaved mates are all read from the forward file
+    let mut inputs = forward_files.clone();
+    if !interleaved {
+        inputs.extend(reverse_files.iter().cloned());
+    }
+    let input_bytes = utils::inputs_size(&inputs);
+
+    // The sniffed inputs are kept open for the demultiplexing. Files sharing
+    // the outputs must share their compression, unless it is given.
+    let forward_sniffed = utils::sniff_formats(
+        match format {
+            Some(_) => &forward_files[..1],
+            None => &forward_files,
+        },
+        trust_ext,
+        options.progress.as_ref(),
+        &options.opened,
+    )?;
+    let mut forward_format = forward_sniffed;
+
+    if reverse.is_none() {
+        info!("You are in single-end mode");
+    } else if interleaved {
+        info!("You are in interleaved paired-end mode");
+    } else {
+        info!("You are in paired-end mode");
+    }
+
+    // Change file compression format here for files extension
+    if let Some(format) = format {
+        forward_format = format;
+        info!(
+            "Output files will be {} compressed",
+            utils::to_compression_ext(forward_format)
+        );
+    }
+
+    // Read and validate data from barcode file before touching outputs
+    let mut barcode_info: Barcode = HashMap::new();
+    let barcode_data;
+    let (mut barcode_rows, panel, delimiter) = match config.barcode_seqs.is_empty() {
+        true => {
+            barcode_data = utils::read_to_string(&barcode)?;
+            let fields = utils::split_by_delimiter(&barcode_data, config.delimiter)
+                .with_context(|| anyhow!("Could not read barcode file '{}'", barcode))?;
+            let delimiter = utils::detect_delimiter(&barcode_data, config.delimiter).ok();
+            (fields, format!("barcode file '{}'", barcode), delimiter)
+        }
+        false if !barcode.is_empty() => {
+            return Err(anyhow!(
+                "barcodes cannot be given both on the command line and in barcode file '{}'",
+                barcode
+            ))
+        }
+        false => {
+            // Numbered in the order they are given
+            let fields = config
+                .barcode_seqs
+                .iter()
+                .enumerate()
+                .map(|(i, (seq, name))| {
+                    let fields = std::iter::once(seq.as_str())
+                        .chain(name.split(','))
+                        .collect();
+                    (i + 1, fields)
+                })
+                .collect::<Vec<utils::Row>>();
+            (fields, "command line barcodes".to_string(), None)
+        }
+    };
+    if config.split_outputs {
+        barcode_rows = utils::split_paired_outputs(barcode_rows)?;
+    }
+    let nb_outputs = if reverse.is_some() { 2 } else { 1 };
+    options.barcode_min_quality = utils::take_quality_column(&mut barcode_rows, nb_outputs)?;
+    options.barcode_mismatch = utils::take_mismatch_column(&mut barcode_rows, nb_outputs)?;
+    utils::validate_barcode_fields(&barcode_rows, reverse.is_some(), delimiter)
+        .with_context(|| anyhow!("Invalid {}", panel))?;
+    utils::check_output_names(&barcode_rows)?;
+    let mut barcode_fields = barcode_rows
+        .into_iter()
+        .map(|(_, fields)| fields)
+        .collect::<Vec<_>>();
+    if !config.only.is_empty() {
+        barcode_fields =
+            utils::select_samples(barcode_fields, &config.only, config.discard_others)?;
+        info!(
+            "Demultiplexing only {} of the panel samples",
+            config.only.len()
+        );
+    }
+
+    // Provenance of the panel, identical for runs on the same panel
+    let panel_hash = format!("{:016x}", utils::panel_hash(&barcode_fields));
+    info!("Panel hash: {}", panel_hash);
+    if let Some(path) = &config.panel_hash {
+        fs::write(path, format!("{}\n", panel_hash))
+            .with_context(|| anyhow!("Could not write panel hash to '{}'", path.display()))?;
+    }
+
+    // Counted reads are discarded rather than written
+    if config.count_only {
+        for fields in barcode_fields.iter_mut() {
+            for name in fields[1..].iter_mut() {
+                *name = "DISCARD";
+            }
+        }
+    }
+
+    // uBAM outputs replace the fasta or fastq ones and are always BGZF
+    // compressed
+    let bam_names: Vec<Vec<String>>;
+    if ubam {
+        bam_names = barcode_fields
+            .iter()
+            .map(|fields| {
+                let names = fields[1..]
+                    .iter()
+                    .map(|name| match utils::is_discard(name) {
+                        true => name.to_string(),
+                        false => ubam::bam_name(name),
+                    });
+                std::iter::once(fields[0].to_string())
+                    .chain(names)
+                    .collect()
+            })
+            .collect();
+        barcode_fields = bam_names
+            .iter()
+            .map(|fields| fields.iter().map(String::as_str).collect())
+            .collect();
+        forward_format = niffler::send::compression::Format::No;
+        info!("Output files will be unaligned BAM");
+    }
+
+    if options.mismatch != 0 {
+        warn!("Barcode mismatch allowed: {}", options.mismatch);
+    }
+    if !options.barcode_mismatch.is_empty() {
+        warn!(
+            "{} barcodes have their own mismatch allowance",
+            options.barcode_mismatch.len()
+        );
+    }
+    if !options.barcode_min_quality.is_empty() {
+        info!(
+            "{} barcodes have their reads quality trimmed",
+            options.barcode_min_quality.len()
+        );
+    }
+
+    // Barcodes may be read in either orientation
+    if config.revcomp {
+        options.revcomp_barcodes = barcode_fields
+            .iter()
+            .map(|fields| {
+                let bc = fields[0].as_bytes();
+                (bc.to_vec(), utils::revcomp(bc))
+            })
+            .collect();
+    }
+    // Mean qualities need fastq reads, stdin and pipes cannot be read twice
+    // and are not checked
+    if options.min_mean_quality.is_some() {
+        for input in forward_files.iter().chain(reverse_files.iter()) {
+            let has_qualities = match options.input_format {
+                Some(format) => format == needletail::parser::Format::Fastq,
+                None => utils::is_stream(input) || utils::has_qualities(input)?,
+            };
+            if !has_qualities {
+                return Err(anyhow!(
+                    "--min-qual needs fastq reads but '{}' has no qualities",
+                    input
+                ));
+            }
+        }
+    }
+    if let Some(head) = options.head {
+        info!(
+            "Processing a subsample of the first {} reads of each input",
+            head
+        );
+    }
+    if options.three_prime && options.umi_len.is_some() {
+        return Err(anyhow!(
+            "UMIs follow 5' barcodes, they cannot be read with --position 3"
+        ));
+    }
+    if options.threads > 1 && reverse.is_some() {
+        warn!("--threads only applies to single-end mode, using a single thread");
+    }
+    if options.max_memory.is_some()
+        && (options.threads <= 1 || reverse.is_some())
+        && config.shuffle.is_none()
+        && config.flush_policy != utils::FlushPolicy::Lru
+    {
+        warn!("--max-memory only applies with --threads in single-end mode");
+    }
+    if config.tee_decompressed.is_some() && reverse.is_some() && !interleaved {
+        return Err(anyhow!(
+            "--tee-decompressed needs a single input, not forward and reverse files"
+        ));
+    }
+    timings.checkpoint("panel_parsing");
+
+    // Compression of the reverse outputs: the reverse one if given, else
+    // that of all outputs, else the one sniffed from the reverse input
+    let reverse_output_format = config.reverse_format.or(format);
+    let reverse_format = match (reverse.as_deref(), reverse_output_format) {
+        (None, _) => None,
+        (Some(_), _) if ubam => Some(niffler::send::compression::Format::No),
+        (Some(_), Some(format)) => Some(format),
+        (Some(_), None) if interleaved => Some(forward_sniffed),
+        (Some(_), None) => Some(utils::sniff_formats(
+            &reverse_files,
+            trust_ext,
+            None,
+            &options.opened,
+        )?),
+    };
+    if let Some(reverse_format) = config.reverse_format {
+        match utils::to_compression_ext(reverse_format).as_str() {
+            "" => info!("Reverse output files will not be compressed"),
+            ext => info!("Reverse output files will be {} compressed", ext),
+        }
+    }
+
+    let no_unknown = config.no_unknown || config.count_only;
+    if no_unknown && (config.unknown_ids.is_some() || options.unknown_split.is_some()) {
+        return Err(anyhow!(
+            "unknown reads are discarded, they cannot be split or have their ids written"
+        ));
+    }
+    // Unknown files, named after the input format
+    let unknown_ext = match ubam {
+        true => "bam",
+        false => unknown_ext(&forward_files[0], options.input_format)?,
+    };
+    let prefix = &config.unknown_prefix;
+    let (formats, unknown_names) = match reverse_format {
+        None => (
+            vec![forward_format],
+            vec![format!("{}.{}", prefix, unknown_ext)],
+        ),
+        Some(reverse_format) if config.interleaved_unknown => (
+            vec![forward_format, reverse_format],
+            vec![format!("{}.{}", prefix, unknown_ext)],
+        ),
+        Some(reverse_format) => (
+            vec![forward_format, reverse_format],
+            vec![
+                format!("{}_R1.{}", prefix, unknown_ext),
+                format!("{}_R2.{}", prefix, unknown_ext),
+            ],
+        ),
+    };
+    let unknown_files = match no_unknown {
+        true => vec!["DISCARD"; unknown_names.len()],
+        false => unknown_names.iter().map(String::as_str).collect(),
+    };
+
+    // Read count ranges, checked once all reads are counted
+    let count_bounds = match &config.count_bounds {
+        Some(path) => Some(
+            utils::read_count_bounds(path)
+                .with_context(|| anyhow!("Could not read count bounds '{}'", path))?,
+        ),
+        None => None,
+    };
+
+    // Larger buffers for the outputs expected to get more reads
+    let expected_counts = match &config.expected_counts {
+        Some(path) => utils::read_expected_counts(path)
+            .with_context(|| anyhow!("Could not read expected counts '{}'", path))?,
+        None => HashMap::new(),
+    };
+
+    // Output writers, the uBAM ones tagging the records with their barcode
+    let encoders = utils::Encoders {
+        zstd: config.zstd,
+        gzip: match config.gzip_threads {
+            0 | 1 => None,
+            threads => Some(gzip::GzipPool::new(threads)),
+        },
+    };
+    let buffers = utils::OutputBuffers::new(
+        config.flush_policy,
+        options.max_memory.unwrap_or(utils::BUFFER_POOL),
+    );
+    let s3_target = config.s3.as_ref();
+    let open_output = |path: &Path,
+                       format: niffler::send::compression::Format,
+                       barcode: Option<&[u8]>,
+                       flag: u16| match (s3_target, ubam) {
+        // Objects are named after the output files, without their folder
+        (Some(target), _) => s3::create_writer(
+            target,
+            &path.file_name().unwrap_or_default().to_string_lossy(),
+            format,
+            level,
+            &encoders,
+        ),
+        (None, true) => ubam::create_writer(path, barcode, flag, level, &buffers),
+        (None, false) => utils::create_writer_with_capacity(
+            path,
+            format,
+            level,
+            &encoders,
+            &buffers,
+            utils::buffer_capacity(&expected_counts, barcode.unwrap_or(b"XXX")),
+        ),
+    };
+
+    // Report the planned outputs without creating anything
+    if config.dry_run {
+        utils::write_plan(
+            io::stdout().lock(),
+            &barcode_fields,
+            output,
+            &formats,
+            &unknown_files,
+        )?;
+        if output.exists() && !force {
+            warn!(
+                "output folder '{}' already exists, use --out or --force for the actual run",
+                output.display()
+            );
+        }
+        info!("Dry run, no output written");
+        return Ok(Stats {
+            counts: HashMap::new(),
+            timings,
+            input_bytes: None,
+        });
+    }
+
+    // Handle output dir
+    let outdir_exists = output.exists();
+    if config.count_only {
+        info!("Counting reads only, no output written");
+    } else if let Some(target) = &config.s3 {
+        info!("Uploading the outputs to {}", target);
+    } else if outdir_exists && config.append {
+        info!("Appending to the files of {}", output.display());
+    } else if outdir_exists && !force {
+        return Err(anyhow!(
+            "output folder '{}', already exists! change it using --out or use --force",
+            output.display()
+        ));
+    } else if outdir_exists && force {
+        info!("Reusing directory {}", output.display());
+        fs::remove_dir_all(output).with_context(|| {
+            anyhow!(
+                "Could not remove folder '{}'. Do you have permission to remove this folder?",
+                output.display()
+            )
+        })?;
+        fs::create_dir(output).with_context(|| {
+            anyhow!(
+                "Could not create folder '{}'. Do you have permission to create this folder?",
+                output.display()
+            )
+        })?;
+    } else if !outdir_exists {
+        fs::create_dir(output)?;
+    }
+
+    // Ids of the unknown reads, compressed according to the file extension
+    let unknown_ids = match &config.unknown_ids {
+        Some(path) => {
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            let file = fs::File::create(path)
+                .with_context(|| anyhow!("Could not create file '{}'", path.display()))?;
+            Some(utils::encode_output(
+                path,
+                Box::new(io::BufWriter::new(file)),
+                utils::to_niffler_format(ext)?,
+                level,
+                &utils::Encoders::default(),
+            )?)
+        }
+        None => None,
+    };
+
+    // Confidence of each assignment, from the margin to the next closest
+    // barcode
+    if let Some(path) = &config.confidence {
+        let file = fs::File::create(path)
+            .with_context(|| anyhow!("Could not create file '{}'", path.display()))?;
+        let mut out: Box<dyn utils::Output> = Box::new(io::BufWriter::new(file));
+        writeln!(out, "read_id\tbarcode\tdistance\tmargin\tconfidence")?;
+        barcode_info.insert(demux::CONFIDENCE, vec![out]);
+    }
+
+    // Decompressed copy of the input, written as it is read
+    if let Some(path) = &config.tee_decompressed {
+        let file = fs::File::create(path)
+            .with_context(|| anyhow!("Could not create file '{}'", path.display()))?;
+        options.tee = Some(utils::TeeSink::new(Box::new(io::BufWriter::new(file))));
+    }
+    let opts = &options;
+
+    timings.checkpoint("output_setup");
+
+    let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+
+    // Main processing of reads
+    let outputs = match reverse_format {
+        // paired-end fasta mode
+        Some(reverse_format) => {
+            if config.sanity_check && !interleaved {
+                let barcodes = barcode_fields
+                    .iter()
+                    .map(|fields| fields[0].as_bytes())
+                    .collect::<Vec<_>>();
+                for (forward, reverse) in forward_files.iter().zip(reverse_files.iter()) {
+                    demux::pe_sanity_check(forward, reverse, &barcodes, opts)?;
+                }
+            }
+
+            // Read barcode data
+            let mut samples: Vec<(&[u8], Vec<PathBuf>)> = Vec::new();
+            for b_vec in barcode_fields.iter() {
+                if b_vec[1..].iter().any(|name| utils::is_discard(name)) {
+                    barcode_info.insert(b_vec[0].as_bytes(), Vec::new());
+                    continue;
+                }
+
+                let forward_path =
+                    utils::create_relpath_from(&mut output.clone(), b_vec[1], forward_format);
+                let reverse_path =
+                    utils::create_relpath_from(&mut output.clone(), b_vec[2], reverse_format);
+
+                let barcode = Some(b_vec[0].as_bytes());
+                let file1 = open_output(&forward_path, forward_format, barcode, ubam::FIRST_MATE)?;
+                let file2 = open_output(&reverse_path, reverse_format, barcode, ubam::SECOND_MATE)?;
+                barcode_info.insert(b_vec[0].as_bytes(), vec![file1, file2]);
+                samples.push((b_vec[0].as_bytes(), vec![forward_path, reverse_path]));
+            }
+            // Create unknown files
+            let unknown_1 =
+                utils::create_relpath_from(&mut output.clone(), unknown_files[0], forward_format);
+            let unknown_2 = match unknown_files.get(1) {
+                Some(name) => utils::create_relpath_from(&mut output.clone(), name, reverse_format),
+                None => unknown_1.clone(),
+            };
+
+            let future_unk_path1 = unknown_1.clone();
+            let future_unk_path2 = unknown_2.clone();
+
+            // Discarded unknown pairs are counted without an XXX entry
+            if !no_unknown {
+                let mut unknown_writers = match config.interleaved_unknown {
+                    // Both mates are written in turn to a shared writer
+                    true => {
+                        let shared = utils::TeeSink::new(open_output(
+                            &unknown_1,
+                            forward_format,
+                            None,
+                            ubam::FIRST_MATE,
+                        )?);
+                        let mates: Vec<Box<dyn utils::Output>> =
+                            vec![Box::new(shared.clone()), Box::new(shared)];
+                        mates
+                    }
+                    false => vec![
+                        open_output(&unknown_1, forward_format, None, ubam::FIRST_MATE)?,
+                        open_output(&unknown_2, reverse_format, None, ubam::SECOND_MATE)?,
+                    ],
+                };
+                unknown_writers.extend(unknown_ids);
+                barcode_info.insert(b"XXX", unknown_writers);
+            }
+            let buckets = open_unknown_buckets(
+                &mut barcode_info,
+                opts.unknown_split,
+                output,
+                &[
+                    ("_R1", forward_format, ubam::FIRST_MATE),
+                    ("_R2", reverse_format, ubam::SECOND_MATE),
+                ],
+                unknown_ext,
+                &open_output,
+            )?;
+            timings.checkpoint("output_setup");
+
+            // Demultiplexing
+            let bar = progress.map(|(total, counter)| utils::ProgressBar::start(total, counter));
+            let mut unk_status = demux::UnknownStatus {
+                fwd_empty: true,
+                rev_empty: true,
+            };
+            for (forward, reverse) in forward_files.iter().zip(reverse_files.iter()) {
+                if forward_files.len() > 1 && interleaved {
+                    info!("Demultiplexing {}", forward);
+                } else if forward_files.len() > 1 {
+                    info!("Demultiplexing {} and {}", forward, reverse);
+                }
+                let (_, status) = pe_demux(
+                    forward,
+                    reverse,
+                    &mut barcode_info,
+                    opts,
+                    &mut nb_records,
+                    live_stats.as_mut(),
+                )
+                .inspect_err(|_| warn_partial(output))?;
+                unk_status.fwd_empty &= status.fwd_empty;
+                unk_status.rev_empty &= status.rev_empty;
+            }
+            drop(bar);
+            RunOutputs {
+                samples,
+                unknown: vec![
+                    (future_unk_path1, unk_status.fwd_empty && !no_unknown),
+                    (
+                        future_unk_path2,
+                        // An interleaved file is removed once, as the first
+                        unk_status.rev_empty && !no_unknown && !config.interleaved_unknown,
+                    ),
+                ],
+                buckets,
+                spill: None,
+            }
+        }
+        // single-end fasta mode
+        _ => {
+            // A grouped output is made of per-sample spill files, merged
+            // once all reads are written
+            let spill = match &config.grouped_output {
+                Some(_) => Some(
+                    utils::SpillDir::create(config.tmpdir.as_deref())
+                        .with_context(|| anyhow!("Could not create temporary directory"))?,
+                ),
+                None => None,
+            };
+            let sample_dir = spill
+                .as_ref()
+                .map_or_else(|| output.clone(), |dir| dir.path().to_path_buf());
+
+            // Read barcode data
+            let mut samples: Vec<(&[u8], Vec<PathBuf>)> = Vec::new();
+            for b_vec in barcode_fields.iter() {
+                // Discarded barcodes are counted but get no output file
+                if utils::is_discard(b_vec[1]) {
+                    barcode_info.insert(b_vec[0].as_bytes(), Vec::new());
+                    continue;
+                }
+
+                let filepath =
+                    utils::create_relpath_from(&mut sample_dir.clone(), b_vec[1], forward_format);
+
+                let file = open_output(
+                    &filepath,
+                    forward_format,
+                    Some(b_vec[0].as_bytes()),
+                    ubam::UNPAIRED,
+                )?;
+                barcode_info.insert(b_vec[0].as_bytes(), vec![file]);
+                samples.push((b_vec[0].as_bytes(), vec![filepath]));
+            }
+            // Create unknown file
+            let unknow_path =
+                utils::create_relpath_from(&mut output.clone(), unknown_files[0], forward_format);
+
+            let future_unk_path = unknow_path.clone();
+            // Discarded unknown reads are counted without an XXX entry
+            if !no_unknown {
+                let unknown_file = open_output(&unknow_path, forward_format, None, ubam::UNPAIRED)?;
+                let mut unknown_writers = vec![unknown_file];
+                unknown_writers.extend(unknown_ids);
+                barcode_info.insert(b"XXX", unknown_writers);
+            }
+            let buckets = open_unknown_buckets(
+                &mut barcode_info,
+                opts.unknown_split,
+                output,
+                &[("", forward_format, ubam::UNPAIRED)],
+                unknown_ext,
+                &open_output,
+            )?;
+            timings.checkpoint("output_setup");
+
+            // Demultiplexing
+            let bar = progress.map(|(total, counter)| utils::ProgressBar::start(total, counter));
+            let mut is_unk_empty = true;
+            for file in forward_files.iter() {
+                if forward_files.len() > 1 {
+                    info!("Demultiplexing {}", file);
+                }
+                let (_, is_empty) = se_demux(
+                    file,
+                    &mut barcode_info,
+                    opts,
+                    &mut nb_records,
+                    live_stats.as_mut(),
+                )
+                .inspect_err(|_| warn_partial(output))?;
+                is_unk_empty &= is_empty;
+            }
+            drop(bar);
+            RunOutputs {
+                samples,
+                unknown: vec![(future_unk_path, is_unk_empty && !no_unknown)],
+                buckets,
+                spill,
+            }
+        }
+    };
+    let stats = &nb_records;
+    // Finish compressed streams before output files are used
+    utils::finish_outputs(barcode_info)?;
+    timings.checkpoint("demultiplexing");
+    log_counts(stats, no_unknown);
+
+    if let Some(path) = &config.stats_tsv {
+        utils::write_stats_tsv(
+            fs::File::create(path)?,
+            &barcode_fields,
+            stats,
+            &unknown_files,
+        )
+        .with_context(|| anyhow!("Could not write stats to '{}'", path.display()))?;
+    }
+    if config.summary {
+        utils::write_stats_tsv(io::stdout().lock(), &barcode_fields, stats, &unknown_files)?;
+    }
+
+    let unknown = outputs
+        .unknown
+        .iter()
+        .map(|(path, is_empty)| (path.as_path(), *is_empty))
+        .collect::<Vec<_>>();
+    utils::remove_empty_outputs(&unknown, keep_empty)?;
+    remove_empty_buckets(&outputs.buckets, stats, keep_empty)?;
+
+    if let Some(seed) = config.shuffle {
+        shuffle_samples(
+            &outputs.samples,
+            &formats,
+            level,
+            &encoders,
+            seed,
+            opts,
+            config.tmpdir.as_deref(),
+        )?;
+    }
+
+    if let Some(min_reads) = config.min_reads {
+        // One pool per mate, named after the first sample outputs
+        let pools = formats
+            .iter()
+            .enumerate()
+            .map(|(i, format)| {
+                let stem = match formats.len() {
+                    1 => "low_count_pool".to_string(),
+                    _ => format!("low_count_pool_R{}", i + 1),
+                };
+                let name = utils::with_ext_of(&stem, barcode_fields[0][i + 1]);
+                utils::create_relpath_from(&mut output.clone(), &name, *format)
+            })
+            .collect::<Vec<_>>();
+        let pooled = utils::pool_low_count(stats, &outputs.samples, min_reads, &pools)?;
+        if !pooled.is_empty() {
+            info!(
+                "{} samples with less than {} reads pooled in low_count_pool",
+                pooled.len(),
+                min_reads
+            );
+        }
+    }
+
+    if let (Some(name), Some(spill)) = (&config.grouped_output, outputs.spill) {
+        let grouped = utils::create_relpath_from(&mut output.clone(), name, forward_format);
+        let parts = outputs
+            .samples
+            .iter()
+            .map(|(_, paths)| paths[0].clone())
+            .collect::<Vec<_>>();
+        utils::concat_files(&parts, &grouped)
+            .with_context(|| anyhow!("Could not write grouped output '{}'", grouped.display()))?;
+        drop(spill);
+        info!("Sample reads grouped in {}", grouped.display());
+    }
+    let counts = owned_counts(stats);
+
+    timings.checkpoint("finalization");
+    if let Some(path) = &config.run_info {
+        utils::write_run_info(fs::File::create(path)?, &timings)
+            .with_context(|| anyhow!("Could not write run info to '{}'", path.display()))?;
+    }
+    let report = utils::RunReport {
+        barcode_file: &barcode,
+        inputs: forward_files
+            .iter()
+            .map(|file| file.as_str())
+            .chain(
+                reverse_files
+                    .iter()
+                    .map(String::as_str)
+                    .filter(|_| !interleaved),
+            )
+            .collect(),
+        outdir: output,
+        formats: &formats,
+        unknown_files: &unknown_files,
+    };
+    if let Some(path) = &config.report_json {
+        report
+            .write_json(fs::File::create(path)?, &barcode_fields, &counts, &timings)
+            .with_context(|| anyhow!("Could not write report to '{}'", path.display()))?;
+    }
+    if let Some(path) = &config.manifest {
+        report
+            .write_manifest(fs::File::create(path)?, &barcode_fields)
+            .with_context(|| anyhow!("Could not write manifest to '{}'", path.display()))?;
+    }
+
+    // Outputs and reports are kept for inspection of the failing samples
+    if let Some(bounds) = &count_bounds {
+        let offenders = utils::check_count_bounds(bounds, &counts);
+        if !offenders.is_empty() {
+            return Err(anyhow!(
+                "{} samples have a read count out of their bounds:\n{}",
+                offenders.len(),
+                offenders.join("\n")
+            ));
+        }
+    }
+
+    Ok(Stats {
+        counts,
+        timings,
+        input_bytes,
+    })
+}
+
+// Outputs of a single-end or paired-end run, finalized the same way once
+// all reads are demultiplexed
+struct RunOutputs<'a> {
+    // Files of each sample, one per mate
+    samples: Vec<(&'a [u8], Vec<PathBuf>)>,
+    // Unknown files, with whether they got no read
+    unknown: Vec<(PathBuf, bool)>,
+    buckets: Vec<(&'static [u8], Vec<PathBuf>)>,
+    // Spill files of the samples of a grouped output
+    spill: Option<utils::SpillDir>,
+}
+
+// Flag the outputs written before demultiplexing failed as incomplete
+fn warn_partial(output: &Path) {
+    warn!(
+        "Demultiplexing stopped early, the files written in {} are incomplete",
+        output.display()
+    );
+}
+
+// Output file opener of demultiplex, given the path, compression, barcode
+// tag and uBAM flag of an output
+type OpenOutput<'a> = dyn Fn(
+        &Path,
+        niffler::send::compression::Format,
+        Option<&[u8]>,
+        u16,
+    ) -> anyhow::Result<Box<dyn utils::Output>>
+    + 'a;
+
+// Open the files of each bucket of the split unknown reads, one per
+// (suffix, compression, uBAM flag) of `mates`, named after the bucket
+fn open_unknown_buckets(
+    barcode_info: &mut Barcode,
+    split: Option<demux::UnknownSplit>,
+    output: &Path,
+    mates: &[(&str, niffler::send::compression::Format, u16)],
+    ext: &str,
+    open_output: &OpenOutput,
+) -> anyhow::Result<Vec<(&'static [u8], Vec<PathBuf>)>> {
+    let mut buckets = Vec::new();
+    for bucket in split.map_or_else(Vec::new, |split| split.buckets()) {
+        let mut writers = Vec::new();
+        let mut paths = Vec::new();
+        for (suffix, format, flag) in mates {
+            let name = format!("{}{}.{}", String::from_utf8_lossy(bucket), suffix, ext);
+            let path = utils::create_relpath_from(&mut output.to_path_buf(), &name, *format);
+            writers.push(open_output(&path, *format, None, *flag)?);
+            paths.push(path);
+        }
+        barcode_info.insert(bucket, writers);
+        buckets.push((bucket, paths));
+    }
+
+    Ok(buckets)
+}
+
+// Shuffle the reads of each sample, seeded from `seed` and the rank of the
+// sample so that samples do not share a permutation
+fn shuffle_samples(
+    samples: &[(&[u8], Vec<PathBuf>)],
+    formats: &[niffler::send::compression::Format],
+    level: niffler::Level,
+    encoders: &utils::Encoders,
+    seed: u64,
+    opts: &DemuxOptions,
+    tmpdir: Option<&Path>,
+) -> anyhow::Result<()> {
+    for (i, (barcode, paths)) in samples.iter().enumerate() {
+        utils::shuffle_outputs(
+            paths,
+            formats,
+            level,
+            encoders,
+            seed.wrapping_add(i as u64),
+            opts.max_memory,
+            tmpdir,
+        )
+        .with_context(|| {
+            anyhow!(
+                "Could not shuffle the reads of barcode {}",
+                String::from_utf8_lossy(barcode)
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+// Extension of the unknown files, from the forced input format, the input
+// file name or else the format of its first record. Stdin and pipes cannot
+// be read twice and get fa.
+fn unknown_ext(
+    input: &str,
+    format: Option<needletail::parser::Format>,
+) -> anyhow::Result<&'static str> {
+    match (format, utils::fastx_ext(input)) {
+        (Some(needletail::parser::Format::Fasta), _) => Ok("fa"),
+        (Some(needletail::parser::Format::Fastq), _) => Ok("fq"),
+        (None, Some(ext)) => Ok(ext),
+        (None, None) if utils::is_stream(input) => Ok("fa"),
+        (None, None) => match utils::has_qualities(input)? {
+            true => Ok("fq"),
+            false => Ok("fa"),
+        },
+    }
+}
+
+// Remove the files of the buckets of unknown reads which got no read
+fn remove_empty_buckets(
+    buckets: &[(&[u8], Vec<PathBuf>)],
+    stats: &HashMap<&[u8], u32>,
+    keep_empty: bool,
+) -> io::Result<()> {
+    let outputs = buckets
+        .iter()
+        .flat_map(|(bucket, paths)| {
+            paths
+                .iter()
+                .map(move |path| (path.as_path(), !stats.contains_key(bucket)))
+        })
+        .collect::<Vec<_>>();
+    utils::remove_empty_outputs(&outputs, keep_empty)
+}
+
+// Log the read count of each barcode and of the extra counters
+fn log_counts(stats: &HashMap<&[u8], u32>, no_unknown: bool) {
+    let total = utils::total_records(stats.iter().map(|(key, n)| (*key, *n)));
+    let mut barcodes = stats
+        .iter()
+        .filter(|(key, _)| !demux::is_extra_count(key) && **key != b"XXX")
+        .collect::<Vec<_>>();
+    barcodes.sort();
+    for (key, value) in barcodes {
+        info!(
+            "{} records found for {} barcode ({:.2}%)",
+            value,
+            String::from_utf8_lossy(key),
+            utils::percentage(*value, total)
+        );
+    }
+    let unknown = stats.get(&b"XXX"[..]).copied().unwrap_or(0);
+    info!(
+        "{} unknown records ({:.2}%){}",
+        unknown,
+        utils::percentage(unknown, total),
+        if no_unknown { " discarded" } else { "" }
+    );
+    if let Some(n) = stats.get(demux::AMBIGUOUS) {
+        warn!(
+            "{} records matched several barcodes equally well and were written as unknown",
+            n
+        );
+    }
+    if let Some(n) = stats.get(demux::TOO_SHORT) {
+        info!("{} records shorter than the minimum length were dropped", n);
+    }
+    if let Some(n) = stats.get(demux::LOW_QUALITY) {
+        info!("{} records below the minimum mean quality were dropped", n);
+    }
+    if let Some(n) = stats.get(demux::LENGTH_ANOMALY) {
+        warn!("{} records of unexpected length were dropped", n);
+    }
+    let mut buckets = stats
+        .iter()
+        .filter(|(key, _)| demux::is_extra_count(key) && key.starts_with(b"unknown_"))
+        .collect::<Vec<_>>();
+    buckets.sort();
+    for (bucket, n) in buckets {
+        info!(
+            "{} unknown records in {}",
+            n,
+            String::from_utf8_lossy(bucket)
+        );
+    }
+    if let Some(n) = stats.get(demux::REVCOMP) {
+        info!(
+            "{} records matched the reverse complement of their barcode",
+            n
+        );
+    }
+}
+
+fn owned_counts(stats: &HashMap<&[u8], u32>) -> HashMap<Vec<u8>, u32> {
+    stats.iter().map(|(bc, n)| (bc.to_vec(), *n)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_demultiplex() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq").unwrap();
+
+        let config = DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            ..Default::default()
+        };
+        let stats = demultiplex(config).unwrap();
+
+        assert_eq!(stats.counts.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.counts.get(&b"XXX"[..]), Some(&2));
+        assert!(dir.path().join("out/sample.fq").exists());
+        assert!(dir.path().join("out/unknown.fq").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_barcode_seqs() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |barcode: &str| DemuxConfig {
+            barcode: barcode.to_string(),
+            barcode_seqs: vec![("ACCGTA".to_string(), "sample.fq".to_string())],
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            ..Default::default()
+        };
+        assert!(demultiplex(config("tests/bc_se.txt")).is_err());
+
+        let stats = demultiplex(config("")).unwrap();
+        assert_eq!(stats.counts.get(&b"ACCGTA"[..]), Some(&1));
+        assert!(dir.path().join("out/sample.fq").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_barcode_seqs_paired() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DemuxConfig {
+            barcode_seqs: vec![("GTCTGATG".to_string(), "a_R1.fa,a_R2.fa".to_string())],
+            forward: "tests/reads_1.fa".to_string(),
+            reverse: Some("tests/reads_2.fa".to_string()),
+            output: dir.path().join("out"),
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        assert!(dir.path().join("out/a_R1.fa").exists());
+        assert!(dir.path().join("out/a_R2.fa").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_paired_lanes() {
+        let dir = tempfile::tempdir().unwrap();
+        for lane in ["L001", "L002"].iter() {
+            for mate in ["1", "2"].iter() {
+                let lane_file = dir.path().join(format!("S1_{}_R{}.fa", lane, mate));
+                fs::copy(format!("tests/reads_{}.fa", mate), lane_file).unwrap();
+            }
+        }
+        let lanes = |mate: &str| {
+            dir.path()
+                .join(format!("S1_L00*_R{}.fa", mate))
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let config = |forward: String, reverse: String, out: &str| DemuxConfig {
+            barcode: "tests/bc_pe_fa.txt".to_string(),
+            forward,
+            reverse: Some(reverse),
+            output: dir.path().join(out),
+            ..Default::default()
+        };
+
+        let single = demultiplex(config(
+            "tests/reads_1.fa".to_string(),
+            "tests/reads_2.fa".to_string(),
+            "single",
+        ))
+        .unwrap();
+        let stats = demultiplex(config(lanes("1"), lanes("2"), "lanes")).unwrap();
+        assert_eq!(stats.total_records(), 2 * single.total_records());
+        for (bc, n) in single.counts.iter() {
+            assert_eq!(stats.counts.get(bc), Some(&(2 * n)));
+        }
+        let bc1 = fs::read_to_string(dir.path().join("lanes/bc1_R2.fa")).unwrap();
+        assert_eq!(
+            bc1,
+            fs::read_to_string(dir.path().join("single/bc1_R2.fa"))
+                .unwrap()
+                .repeat(2)
+        );
+
+        let err = match demultiplex(config(lanes("1"), "tests/reads_2.fa".to_string(), "odd")) {
+            Ok(_) => panic!("forward and reverse file counts differ"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.ends_with("gives 2 forward files but 'tests/reads_2.fa' gives 1 reverse files"));
+
+        // Sorted order pairs L001 with L002 once a reverse lane is missing
+        fs::remove_file(dir.path().join("S1_L001_R2.fa")).unwrap();
+        fs::copy("tests/reads_2.fa", dir.path().join("S1_L003_R2.fa")).unwrap();
+        let err = match demultiplex(config(lanes("1"), lanes("2"), "shifted")) {
+            Ok(_) => panic!("lanes are not paired with their mates"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.contains("S1_L001_R1.fa' pairs with reverse file"));
+        assert!(err.ends_with("S1_L002_R2.fa', which is not its mate"));
+
+        // Every lane has its compression sniffed, not only the first one
+        fs::remove_file(dir.path().join("S1_L003_R2.fa")).unwrap();
+        fs::copy("tests/reads_2.fa.gz", dir.path().join("S1_L001_R2.fa")).unwrap();
+        let err = match demultiplex(config(lanes("1"), lanes("2"), "mixed")) {
+            Ok(_) => panic!("lanes differ in compression"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.ends_with(
+            "S1_L002_R2.fa' is uncompressed, their outputs cannot share one compression"
+        ));
+    }
+
+    #[test]
+    fn test_demultiplex_sanity_check_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |forward: &str, reverse: &str| DemuxConfig {
+            barcode: "tests/bc_pe_fa.txt".to_string(),
+            forward: forward.to_string(),
+            reverse: Some(reverse.to_string()),
+            output: dir.path().join("out"),
+            sanity_check: true,
+            ..Default::default()
+        };
+
+        // Rejected before stdin is read
+        let err = match demultiplex(config("tests/reads_1.fa", utils::STDIN)) {
+            Ok(_) => panic!("stdin cannot be checked"),
+            Err(err) => err.to_string(),
+        };
+        assert_eq!(
+            err,
+            "--sanity-check reads the inputs twice, it cannot check the stream '-'"
+        );
+        assert!(!dir.path().join("out").exists());
+
+        demultiplex(config("tests/reads_1.fa", "tests/reads_2.fa")).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_demultiplex_fifo() {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo = dir.path().join("reads.fifo");
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .unwrap()
+            .success());
+        let fifo = fifo.to_str().unwrap().to_string();
+        assert!(utils::is_stream(&fifo));
+        assert!(!utils::is_stream("tests/test.fq"));
+
+        // Opening the pipe a second time would block for want of a writer
+        let writer = {
+            let fifo = fifo.clone();
+            std::thread::spawn(move || {
+                let content = fs::read("tests/test.fq").unwrap();
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(fifo)
+                    .unwrap()
+                    .write_all(&content)
+                    .unwrap();
+            })
+        };
+        let config = DemuxConfig {
+            barcode_seqs: vec![("ACCGTA".to_string(), "sample.fq".to_string())],
+            forward: fifo,
+            output: dir.path().join("out"),
+            ..Default::default()
+        };
+        let stats = demultiplex(config).unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(stats.counts.get(&b"ACCGTA"[..]), Some(&1));
+        assert!(dir.path().join("out/sample.fq").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq").unwrap();
+        let config = |append: bool| DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            append,
+            ..Default::default()
+        };
+        demultiplex(config(false)).unwrap();
+        let sample = fs::read_to_string(dir.path().join("out/sample.fq")).unwrap();
+
+        assert!(demultiplex(config(false)).is_err());
+        demultiplex(config(true)).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out/sample.fq")).unwrap(),
+            sample.repeat(2)
+        );
+    }
+
+    #[test]
+    fn test_demultiplex_unknown_ext_sniffed() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq").unwrap();
+        let reads = dir.path().join("reads.txt");
+        fs::copy("tests/test.fq", &reads).unwrap();
+
+        let config = DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: reads.to_str().unwrap().to_string(),
+            output: dir.path().join("out"),
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        assert!(dir.path().join("out/unknown.fq").exists());
+        assert_eq!(unknown_ext("tests/reads_1.fa", None).unwrap(), "fa");
+        assert_eq!(unknown_ext("-", None).unwrap(), "fa");
+        assert_eq!(
+            unknown_ext("-", Some(needletail::parser::Format::Fastq)).unwrap(),
+            "fq"
+        );
+    }
+
+    #[test]
+    fn test_demultiplex_count_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let stats_tsv = dir.path().join("stats.tsv");
+
+        let run = |out: &str, count_only: bool| {
+            let config = DemuxConfig {
+                barcode: "tests/bc_se.txt".to_string(),
+                forward: "tests/reads_1.fa".to_string(),
+                output: dir.path().join(out),
+                only: vec!["GTCTGATG".to_string()],
+                count_only,
+                stats_tsv: Some(stats_tsv.clone()),
+                ..Default::default()
+            };
+            demultiplex(config).unwrap().counts
+        };
+        let written = run("written", false);
+        let counted = run("counted", true);
+
+        assert_eq!(written, counted);
+        assert!(counted[&b"XXX"[..]] > 0);
+        assert!(!dir.path().join("counted").exists());
+        assert!(fs::read_to_string(&stats_tsv).unwrap().contains(&format!(
+            "GTCTGATG\tDISCARD\t\t{}",
+            counted[&b"GTCTGATG"[..]]
+        )));
+    }
+
+    #[test]
+    fn test_demultiplex_unknown_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = DemuxConfig {
+            barcode: "tests/bc_pe_fa.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            reverse: Some("tests/reads_2.fa".to_string()),
+            output: dir.path().join("out"),
+            only: vec!["GTCTGATG".to_string()],
+            unknown_prefix: "undetermined".to_string(),
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        let out = dir.path().join("out");
+        assert!(out.join("undetermined_R1.fa").exists());
+        assert!(out.join("undetermined_R2.fa").exists());
+        assert!(!out.join("unknown_R1.fa").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_no_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq").unwrap();
+        let report = dir.path().join("report.json");
+
+        let config = DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            keep_empty_outputs: true,
+            no_unknown: true,
+            report_json: Some(report.clone()),
+            ..Default::default()
+        };
+        let stats = demultiplex(config).unwrap();
+
+        assert_eq!(stats.counts.get(&b"ACCGTA"[..]), Some(&1));
+        assert_eq!(stats.counts.get(&b"XXX"[..]), Some(&2));
+        let outputs = fs::read_dir(dir.path().join("out"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, vec!["sample.fq"]);
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(report).unwrap()).unwrap();
+        assert_eq!(report["unknown"]["reads"], 2);
+        assert_eq!(report["unknown"]["files"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn test_demultiplex_gzip_single_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq").unwrap();
+
+        let config = DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            format: Some(niffler::send::compression::Format::Gzip),
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        // unknown reads are several records written to one gzip member
+        let compressed = fs::read(dir.path().join("out/unknown.fq.gz")).unwrap();
+        assert_eq!(
+            compressed
+                .windows(3)
+                .filter(|w| *w == [0x1f, 0x8b, 0x08])
+                .count(),
+            1
+        );
+        let (mut decoder, _) = niffler::send::get_reader(Box::new(&compressed[..])).unwrap();
+        let mut content = String::new();
+        decoder.read_to_string(&mut content).unwrap();
+        assert_eq!(content.matches('@').count(), 2);
+    }
+
+    #[test]
+    fn test_demultiplex_expected_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = dir.path().join("expected.tsv");
+        writeln!(
+            fs::File::create(&expected).unwrap(),
+            "GTCTGATG\t900000\nTGACTTGT\t10\nXXX\t5000"
+        )
+        .unwrap();
+
+        let run = |out: &str, expected_counts: Option<String>| {
+            let config = DemuxConfig {
+                barcode: "tests/bc_se.txt".to_string(),
+                forward: "tests/reads_1.fa".to_string(),
+                output: dir.path().join(out),
+                format: Some(niffler::send::compression::Format::Gzip),
+                expected_counts,
+                ..Default::default()
+            };
+            demultiplex(config).unwrap().counts
+        };
+        let counts = run("default", None);
+        let sized = run("sized", Some(expected.to_str().unwrap().to_string()));
+
+        // buffer sizes change neither the counts nor the files
+        assert_eq!(counts, sized);
+        for entry in fs::read_dir(dir.path().join("default")).unwrap() {
+            let name = entry.unwrap().file_name();
+            assert_eq!(
+                fs::read(dir.path().join("default").join(&name)).unwrap(),
+                fs::read(dir.path().join("sized").join(&name)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_demultiplex_flush_policy() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let run = |out: &str, flush_policy: utils::FlushPolicy, max_memory: Option<usize>| {
+            let config = DemuxConfig {
+                barcode: "tests/bc_se.txt".to_string(),
+                forward: "tests/reads_1.fa".to_string(),
+                output: dir.path().join(out),
+                format: Some(niffler::send::compression::Format::Gzip),
+                flush_policy,
+                options: DemuxOptions {
+                    max_memory,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            demultiplex(config).unwrap().counts
+        };
+        let counts = run("lazy", utils::FlushPolicy::Lazy, None);
+        // a cap below the size of a read flushes a buffer at each write
+        let lru = run("lru", utils::FlushPolicy::Lru, Some(32));
+
+        // flushing changes neither the counts nor the reads
+        assert_eq!(counts, lru);
+        for entry in fs::read_dir(dir.path().join("lazy")).unwrap() {
+            let name = entry.unwrap().file_name();
+            let read = |out: &str| {
+                let (mut reader, _) =
+                    niffler::send::from_path(dir.path().join(out).join(&name)).unwrap();
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content).unwrap();
+                content
+            };
+            assert_eq!(read("lazy"), read("lru"));
+        }
+    }
+
+    #[cfg(feature = "s3")]
+    #[test]
+    fn test_demultiplex_s3() {
+        let (endpoint, requests) = s3::mock_store();
+        let dir = tempfile::tempdir().unwrap();
+        let config = DemuxConfig {
+            barcode: "tests/bc_se.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            output: dir.path().join("out"),
+            s3: Some(s3::S3Target {
+                bucket: "runs".to_string(),
+                prefix: "flowcell1".to_string(),
+                endpoint,
+                region: "us-east-1".to_string(),
+                access_key: "key".to_string(),
+                secret_key: "secret".to_string(),
+                session_token: None,
+            }),
+            ..Default::default()
+        };
+        let stats = demultiplex(config).unwrap();
+        assert!(!dir.path().join("out").exists());
+
+        // One completed upload per output, empty ones included
+        let requests = requests.lock().unwrap();
+        let mut keys = requests
+            .iter()
+            .filter(|r| r.method == "POST" && r.query.starts_with("uploadId="))
+            .map(|r| r.path.as_str())
+            .collect::<Vec<_>>();
+        keys.sort();
+        let mut expected = (1..=10)
+            .map(|i| format!("/runs/flowcell1/bc{}_R1.fa", i))
+            .chain(std::iter::once("/runs/flowcell1/unknown.fa".to_string()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        // Each object holds the reads of its barcode
+        let uploaded = |key: &str| {
+            requests
+                .iter()
+                .filter(|r| r.method == "PUT" && r.path == key)
+                .flat_map(|r| r.body.clone())
+                .collect::<Vec<_>>()
+        };
+        let bc1 = uploaded("/runs/flowcell1/bc1_R1.fa");
+        assert_eq!(
+            bc1.iter().filter(|&&b| b == b'>').count() as u32,
+            stats.counts[&b"GTCTGATG".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_demultiplex_grouped_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmpdir = dir.path().join("tmp");
+        fs::create_dir(&tmpdir).unwrap();
+
+        let run = |out: &str, grouped_output: Option<String>| {
+            let config = DemuxConfig {
+                barcode: "tests/bc_se.txt".to_string(),
+                forward: "tests/reads_1.fa".to_string(),
+                output: dir.path().join(out),
+                grouped_output,
+                tmpdir: Some(tmpdir.clone()),
+                ..Default::default()
+            };
+            demultiplex(config).unwrap().counts
+        };
+        let counts = run("split", None);
+        let grouped_counts = run("grouped", Some("all.fa".to_string()));
+        assert_eq!(counts, grouped_counts);
+
+        // sample reads follow each other in barcode file order
+        let mut expected = Vec::new();
+        for line in fs::read_to_string("tests/bc_se.txt").unwrap().lines() {
+            let name = line.split('\t').nth(1).unwrap();
+            expected.extend(fs::read(dir.path().join("split").join(name)).unwrap());
+        }
+        let grouped = dir.path().join("grouped");
+        assert_eq!(fs::read(grouped.join("all.fa")).unwrap(), expected);
+        assert!(!grouped.join("bc1_R1.fa").exists());
+
+        // the spill files are gone
+        assert_eq!(fs::read_dir(&tmpdir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_demultiplex_only() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = DemuxConfig {
+            barcode: "tests/bc_se.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            output: dir.path().join("out"),
+            only: vec!["bc1_R1".to_string(), "CATGTCTA".to_string()],
+            ..Default::default()
+        };
+        let counts = demultiplex(config).unwrap().counts;
+
+        let mut outputs = fs::read_dir(dir.path().join("out"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        outputs.sort();
+        assert_eq!(outputs, ["bc1_R1.fa", "bc3_R1.fa", "unknown.fa"]);
+        assert!(counts[&b"XXX"[..]] > 0);
+        assert!(!counts.contains_key(&b"TGACTTGT"[..]));
+    }
+
+    #[test]
+    fn test_demultiplex_count_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let bounds = dir.path().join("bounds.tsv");
+        writeln!(
+            fs::File::create(&bounds).unwrap(),
+            "GTCTGATG\t1\t-\nTGACTTGT\t1000000\t-"
+        )
+        .unwrap();
+
+        let config = DemuxConfig {
+            barcode: "tests/bc_se.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            output: dir.path().join("out"),
+            count_bounds: Some(bounds.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        let err = match demultiplex(config) {
+            Ok(_) => panic!("TGACTTGT is below its minimum"),
+            Err(err) => err.to_string(),
+        };
+        assert!(err.starts_with("1 samples have a read count out of their bounds"));
+        assert!(err.contains("TGACTTGT: "));
+        assert!(err.contains(" < 1000000"));
+        assert!(!err.contains("GTCTGATG"));
+        assert!(dir.path().join("out").join("bc2_R1.fa").exists());
+    }
+
+    #[test]
+    fn test_demultiplex_interleaved_unknown() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = DemuxConfig {
+            barcode: "tests/bc_pe_fa.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            reverse: Some("tests/reads_2.fa".to_string()),
+            output: dir.path().join("out"),
+            only: vec!["GTCTGATG".to_string()],
+            interleaved_unknown: true,
+            ..Default::default()
+        };
+        let counts = demultiplex(config).unwrap().counts;
+        let out = dir.path().join("out");
+        assert!(!out.join("unknown_R1.fa").exists());
+        assert!(!out.join("unknown_R2.fa").exists());
+
+        let reverse = fs::read_to_string("tests/reads_2.fa").unwrap();
+        let reverse = reverse.lines().collect::<Vec<_>>();
+        let records = fs::read_to_string(out.join("unknown.fa")).unwrap();
+        let records = records
+            .lines()
+            .collect::<Vec<_>>()
+            .chunks(2)
+            .map(|record| (record[0], record[1]))
+            .collect::<Vec<_>>();
+        assert!(counts[&b"XXX"[..]] > 0);
+        assert_eq!(records.len(), 2 * counts[&b"XXX"[..]] as usize);
+
+        // forward then reverse mate of each pair
+        for pair in records.chunks(2) {
+            assert_eq!(pair[0].0, pair[1].0);
+            let mate = reverse.iter().position(|line| *line == pair[1].0).unwrap();
+            assert_eq!(pair[1].1, reverse[mate + 1]);
+        }
+    }
+
+    #[test]
+    fn test_demultiplex_reverse_format() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = DemuxConfig {
+            barcode: "tests/bc_pe_fa.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            reverse: Some("tests/reads_2.fa".to_string()),
+            output: dir.path().join("out"),
+            format: Some(niffler::send::compression::Format::Gzip),
+            reverse_format: Some(niffler::send::compression::Format::No),
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        let (_, forward) = niffler::send::from_path(dir.path().join("out/bc1_R1.fa.gz")).unwrap();
+        assert_eq!(forward, niffler::send::compression::Format::Gzip);
+        let (_, reverse) = niffler::send::from_path(dir.path().join("out/bc1_R2.fa")).unwrap();
+        assert_eq!(reverse, niffler::send::compression::Format::No);
+        assert!(!dir.path().join("out/bc1_R2.fa.gz").exists());
+
+        // single-end outputs have no reverse compression to set
+        let config = DemuxConfig {
+            barcode: "tests/bc_se.txt".to_string(),
+            forward: "tests/reads_1.fa".to_string(),
+            output: dir.path().join("se"),
+            reverse_format: Some(niffler::send::compression::Format::No),
+            ..Default::default()
+        };
+        assert!(demultiplex(config).is_err());
+    }
+
+    #[cfg(feature = "ubam")]
+    #[test]
+    fn test_demultiplex_ubam() {
+        let dir = tempfile::tempdir().unwrap();
+        let barcode = dir.path().join("barcode.txt");
+        writeln!(fs::File::create(&barcode).unwrap(), "ACCGTA\tsample.fq.gz").unwrap();
+
+        let config = DemuxConfig {
+            barcode: barcode.to_str().unwrap().to_string(),
+            forward: "tests/test.fq".to_string(),
+            output: dir.path().join("out"),
+            ubam: true,
+            ..Default::default()
+        };
+        demultiplex(config).unwrap();
+
+        let decode = |name: &str| {
+            let mut data = Vec::new();
+            flate2::read::MultiGzDecoder::new(fs::File::open(dir.path().join(name)).unwrap())
+                .read_to_end(&mut data)
+                .unwrap();
+            assert!(data.starts_with(b"BAM\x01"));
+            data
+        };
+        let tagged = |data: &[u8]| data.windows(4).filter(|w| *w == b"BCZA").count();
+        assert_eq!(tagged(&decode("out/sample.bam")), 1);
+        assert_eq!(tagged(&decode("out/unknown.bam")), 0);
+        assert!(!dir.path().join("out/sample.fq.gz").exists());
+    }
+}
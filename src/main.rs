@@ -3,248 +3,385 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use std::collections::HashMap;
 use std::env;
-use std::fs;
+use std::io::{self, IsTerminal};
 use std::path::PathBuf;
-use std::process;
 use std::time::Instant;
 
 use anyhow::{anyhow, Context};
 use clap::crate_version;
-use log::{error, info, warn};
+use log::info;
+
+use sabreur::{demux, utils, DemuxConfig};
 
 mod app;
-mod demux;
-mod utils;
 
-// TODO: Check if supplied barcode file for se or pe is properly
-// formated before giving it to the demultiplexing function
+// Number of prefixes printed by --count-barcodes
+const COUNT_BARCODES_TOP: usize = 20;
+
 fn main() -> anyhow::Result<()> {
     let startime = Instant::now();
 
     // Define command-line arguments ----------------------------------------
     let matches = app::build_app().get_matches_from(env::args_os());
 
-    // is --quiet option specified by the user?
-    let quiet = matches.get_flag("quiet");
-    utils::setup_logging(quiet)?; // Settting up logging
-
-    // Read command-line arguments
-    let forward = matches
-        .get_one::<String>("FORWARD")
-        .expect("input file is required");
-
-    let mut forward_format = utils::which_format(forward);
-
-    let barcode = matches
-        .get_one::<String>("BARCODE")
-        .expect("input barcode is required");
-
-    let output: &PathBuf = matches.get_one("output").unwrap();
-    let mismatch: u8 = *matches.get_one("mismatch").unwrap();
-
-    // If user force output to be compressed even if input is not
-    // add option to change compression of output
-    let mut format = niffler::send::compression::Format::No;
-    if matches.contains_id("format") {
-        format = utils::to_niffler_format(matches.get_one::<String>("format").unwrap())
-            .with_context(|| anyhow!("Could not convert compression format to niffler format"))?;
+    // Print the version for provenance records, before any log is set up
+    if matches.get_flag("version_json") {
+        println!("{}", utils::version_json(crate_version!()));
+        return Ok(());
     }
 
-    let raw_level: u8 = *matches.get_one("level").unwrap();
-    let force = matches.get_flag("force");
-
-    info!("sabreur v{} starting up!", crate_version!());
-    if !matches.contains_id("REVERSE") {
-        info!("You are in single-end mode");
-    } else {
-        info!("You are in paired-end mode");
-    }
-
-    // Change file compression format here for files extension
-    if format != niffler::send::compression::Format::No {
-        forward_format = format;
+    // is --quiet option specified by the user? A summary only run is as
+    // quiet until its final table.
+    let quiet = matches.get_flag("quiet") || matches.get_flag("summary");
+    let log_file = match matches.get_flag("no_log_file") {
+        true => None,
+        false => matches.get_one::<PathBuf>("log"),
+    };
+    // Settting up logging
+    utils::setup_logging(
+        quiet,
+        matches.get_flag("syslog"),
+        log_file.map(PathBuf::as_path),
+    )?;
+
+    let delimiter = matches
+        .get_one::<String>("delimiter")
+        .map(|name| utils::to_delimiter(name));
+
+    // Compare two panels and exit without demultiplexing
+    if let Some(other) = matches.get_one::<String>("compare_panels") {
+        let barcode = matches.get_one::<String>("BARCODE").unwrap();
+        let base_data = utils::read_to_string(barcode)?;
+        let other_data = utils::read_to_string(other)?;
+        let fields = |data| -> anyhow::Result<Vec<Vec<&str>>> {
+            Ok(utils::split_by_delimiter(data, delimiter)?
+                .into_iter()
+                .map(|(_, fields)| fields)
+                .collect())
+        };
+        let base_fields = fields(&base_data)?;
+        let other_fields = fields(&other_data)?;
+        let diff = utils::compare_panels(&base_fields, &other_fields);
+
+        for (bc, files) in diff.added.iter() {
+            println!("added\t{}\t{}", bc, files.join("\t"));
+        }
+        for (bc, files) in diff.removed.iter() {
+            println!("removed\t{}\t{}", bc, files.join("\t"));
+        }
+        for (bc, old_files, new_files) in diff.changed.iter() {
+            println!(
+                "changed\t{}\t{}\t->\t{}",
+                bc,
+                old_files.join("\t"),
+                new_files.join("\t")
+            );
+        }
         info!(
-            "Output files will be {} compressed",
-            utils::to_compression_ext(forward_format)
+            "{} added, {} removed and {} changed barcodes",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
         );
+        return Ok(());
     }
 
-    // Handle output dir
-    let outdir_exists = output.exists();
-    if outdir_exists && !force {
-        error!(
-            "output folder '{}', already exists! change it using --out or use --force",
-            output.display()
+    // Profile the read prefixes and exit without demultiplexing
+    if let Some(len) = matches.get_one::<usize>("count_barcodes") {
+        // Without a panel the reads are the only file argument
+        let input = matches
+            .get_one::<String>("FORWARD")
+            .or_else(|| matches.get_one::<String>("BARCODE"))
+            .unwrap();
+        let counts = demux::count_prefixes(input, *len)?;
+        for (prefix, n) in counts.iter().take(COUNT_BARCODES_TOP) {
+            println!("{}\t{}", String::from_utf8_lossy(prefix), n);
+        }
+        info!(
+            "{} distinct {}-base prefixes in {} reads",
+            counts.len(),
+            len,
+            counts.iter().map(|(_, n)| u64::from(*n)).sum::<u64>()
         );
-        process::exit(exitcode::CANTCREAT);
-    } else if outdir_exists && force {
-        info!("Reusing directory {}", output.display());
-        fs::remove_dir_all(output).with_context(|| {
-            anyhow!(
-                "Could not remove folder '{}'. Do you have permission to remove this folder?",
-                output.display()
-            )
-        })?;
-        fs::create_dir(output).with_context(|| {
-            anyhow!(
-                "Could not create folder '{}'. Do you have permission to create this folder?",
-                output.display()
-            )
-        })?;
-    } else if !outdir_exists {
-        fs::create_dir(output)?;
+        return Ok(());
     }
 
-    // Read data from barcode file
-    let mut barcode_info: demux::Barcode = HashMap::new();
-    let barcode_data = fs::read_to_string(barcode)?;
-    let barcode_fields = utils::split_by_tab(&barcode_data).unwrap();
+    // Read command-line arguments
+    let output: &PathBuf = matches.get_one("output").unwrap();
+    let force = matches.get_flag("force");
+
+    // If user force output to be compressed even if input is not
+    // add option to change compression of output
+    let format =
+        match matches.get_one::<String>("format") {
+            Some(format) => Some(utils::to_niffler_format(format).with_context(|| {
+                anyhow!("Could not convert compression format to niffler format")
+            })?),
+            None => None,
+        };
+
+    let reverse_format =
+        match matches.get_one::<String>("reverse_format") {
+            Some(format) => Some(utils::to_niffler_format(format).with_context(|| {
+                anyhow!("Could not convert compression format to niffler format")
+            })?),
+            None => None,
+        };
 
-    if mismatch != 0 {
-        warn!("Barcode mismatch allowed: {}", mismatch);
-    }
+    info!("sabreur v{} starting up!", crate_version!());
 
-    let mut nb_records: HashMap<&[u8], u32> = HashMap::new();
+    // Ties between equally close barcodes, random draws are seeded so
+    // that a run can be reproduced
+    let tie_break = match matches.get_one::<String>("tie_break").unwrap().as_str() {
+        "random" => {
+            let seed = match matches.get_one::<u64>("seed") {
+                Some(seed) => *seed,
+                None => rand::random(),
+            };
+            info!(
+                "Ties between barcodes are drawn at random with seed {}",
+                seed
+            );
+            demux::TieBreak::Random(seed)
+        }
+        "lexical" => demux::TieBreak::Lexical,
+        _ => demux::TieBreak::Unknown,
+    };
 
-    // Main processing of reads
-    match !matches.contains_id("REVERSE") {
-        // single-end fasta mode
+    // Shuffled sample reads, seeded as the random ties
+    let shuffle = match matches.get_flag("shuffle") {
         true => {
-            // Read barcode data
-            for b_vec in barcode_fields.iter() {
-                let filepath =
-                    utils::create_relpath_from(&mut output.clone(), b_vec[1], forward_format);
-
-                let file = fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(filepath)?;
-                barcode_info.insert(b_vec[0].as_bytes(), vec![file]);
-            }
-            // Create unknown file
-            let unknow_path =
-                utils::create_relpath_from(&mut output.clone(), "unkwnown.fa", forward_format);
-
-            let future_unk_path = unknow_path.clone();
-            let unknown_file = fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(unknow_path)?;
-            barcode_info.insert(b"XXX", vec![unknown_file]);
-
-            // Demultiplexing
-            let (stats, is_unk_empty) = demux::se_demux(
-                forward,
-                format,
-                utils::to_niffler_level(raw_level),
-                &barcode_info,
-                mismatch,
-                &mut nb_records,
-            )?;
-            if !quiet {
-                for (key, value) in stats.iter() {
-                    info!(
-                        "{} records found for {} barcode",
-                        value,
-                        String::from_utf8_lossy(key)
-                    );
-                }
-            }
-            if is_unk_empty {
-                fs::remove_file(future_unk_path)?;
-            }
+            let seed = match matches.get_one::<u64>("seed") {
+                Some(seed) => *seed,
+                None => rand::random(),
+            };
+            info!("Sample reads are shuffled with seed {}", seed);
+            Some(seed)
         }
-        // paired-end fasta mode
-        false => {
-            let reverse = matches.get_one::<String>("REVERSE").unwrap();
-            let mut reverse_format = utils::which_format(reverse);
-            if format != niffler::send::compression::Format::No {
-                reverse_format = format;
-            }
-
-            // Read barcode data
-            for b_vec in barcode_fields.iter() {
-                let forward_path =
-                    utils::create_relpath_from(&mut output.clone(), b_vec[1], forward_format);
-                let reverse_path =
-                    utils::create_relpath_from(&mut output.clone(), b_vec[2], reverse_format);
-
-                let file1 = fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(forward_path)?;
-                let file2 = fs::OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(reverse_path)?;
-                barcode_info.insert(b_vec[0].as_bytes(), vec![file1, file2]);
-            }
-            // Create unknown files
-            let unknown_1 =
-                utils::create_relpath_from(&mut output.clone(), "unknown_R1.fa", forward_format);
-            let unknown_2 =
-                utils::create_relpath_from(&mut output.clone(), "unknown_R2.fa", reverse_format);
-
-            let future_unk_path1 = unknown_1.clone();
-            let future_unk_path2 = unknown_2.clone();
-
-            let unknown_file1 = fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(unknown_1)?;
-            let unknown_file2 = fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(unknown_2)?;
-            barcode_info.insert(b"XXX", vec![unknown_file1, unknown_file2]);
-
-            // Demultiplexing
-            let (stats, unk_status) = demux::pe_demux(
-                forward,
-                reverse,
-                format,
-                utils::to_niffler_level(raw_level),
-                &barcode_info,
-                mismatch,
-                &mut nb_records,
-            )?;
-
-            if !quiet {
-                for (key, value) in stats.iter() {
-                    info!(
-                        "{} records found for {} barcode",
-                        value,
-                        String::from_utf8_lossy(key)
-                    );
+        false => None,
+    };
+
+    // Barcodes read from the read headers rather than the sequences
+    let header_barcode = match (
+        matches.get_one::<usize>("header_field"),
+        matches.get_one::<String>("header_regex"),
+    ) {
+        (Some(field), _) => Some(demux::HeaderBarcode::Field(*field)),
+        (None, Some(re)) => Some(demux::HeaderBarcode::Regex(
+            regex::bytes::Regex::new(re)
+                .with_context(|| anyhow!("Invalid --header-regex '{}'", re))?,
+        )),
+        (None, None) => None,
+    };
+
+    // Periodic NDJSON counts for monitoring, on stdout or a unix socket
+    let live_stats = match matches.get_one::<u64>("live_stats") {
+        Some(every) => {
+            let sink: Box<dyn io::Write> = match matches.get_one::<PathBuf>("live_socket") {
+                #[cfg(unix)]
+                Some(path) => Box::new(
+                    std::os::unix::net::UnixStream::connect(path).with_context(|| {
+                        anyhow!("Could not connect to socket '{}'", path.display())
+                    })?,
+                ),
+                #[cfg(not(unix))]
+                Some(_) => {
+                    return Err(anyhow!("--live-socket is only supported on unix platforms"))
                 }
+                None => Box::new(io::stdout()),
+            };
+            Some(utils::LiveStats::new(*every, sink))
+        }
+        None => None,
+    };
+
+    // Zstd outputs tuned beyond the generic levels, at --level unless set
+    let level = *matches.get_one::<u8>("level").unwrap();
+    let zstd_long = matches.get_flag("zstd_long");
+    let zstd = match matches.get_one::<i32>("zstd_level") {
+        Some(zstd_level) => Some(utils::ZstdTuning {
+            level: *zstd_level,
+            long_distance: zstd_long,
+        }),
+        None if zstd_long => Some(utils::ZstdTuning {
+            level: i32::from(level),
+            long_distance: true,
+        }),
+        None => None,
+    };
+
+    // Barcodes given on the command line leave all file arguments to the
+    // reads, paired-end output names being given as R1,R2
+    let files = ["BARCODE", "FORWARD", "REVERSE"]
+        .iter()
+        .filter_map(|name| matches.get_one::<String>(name).cloned())
+        .collect::<Vec<_>>();
+    let barcode_seqs = match matches.get_many::<String>("barcode_seq") {
+        Some(seqs) => {
+            let names = matches
+                .get_many::<String>("out_name")
+                .unwrap_or_default()
+                .collect::<Vec<_>>();
+            let seqs = seqs.collect::<Vec<_>>();
+            if seqs.len() != names.len() {
+                return Err(anyhow!(
+                    "{} --barcode-seq but {} --out-name given, each barcode needs one",
+                    seqs.len(),
+                    names.len()
+                ));
             }
-
-            if unk_status == *"truetrue" {
-                fs::remove_file(future_unk_path1)?;
-                fs::remove_file(future_unk_path2)?;
-            } else if unk_status == *"falsetrue" {
-                fs::remove_file(future_unk_path2)?;
-            } else if unk_status == *"truefalse" {
-                fs::remove_file(future_unk_path1)?;
+            let paired = names.iter().any(|name| name.contains(','));
+            if files.len() > 2 || (files.len() == 2 && !paired) {
+                return Err(anyhow!(
+                    "a barcode file cannot be given with --barcode-seq, only the reads \
+                    (with R1,R2 output names in paired-end mode)"
+                ));
             }
+            seqs.into_iter()
+                .cloned()
+                .zip(names.into_iter().cloned())
+                .collect()
         }
+        None => Vec::new(),
+    };
+    let (barcode, reads) = match barcode_seqs.is_empty() {
+        true => (files[0].clone(), &files[1..]),
+        false => (String::new(), &files[..]),
+    };
+
+    let config = DemuxConfig {
+        barcode,
+        barcode_seqs,
+        delimiter,
+        forward: reads.first().expect("input file is required").clone(),
+        reverse: reads.get(1).cloned(),
+        output: output.clone(),
+        force,
+        append: matches.get_flag("append"),
+        format,
+        reverse_format,
+        trust_ext: matches.get_flag("trust_ext"),
+        level: utils::to_niffler_level(level)?,
+        zstd,
+        gzip_threads: usize::from(*matches.get_one::<u16>("gzip_threads").unwrap()),
+        expected_counts: matches.get_one::<String>("expected_counts").cloned(),
+        flush_policy: match matches
+            .get_one::<String>("flush_policy")
+            .map(String::as_str)
+        {
+            Some("eager") => utils::FlushPolicy::Eager,
+            Some("lru") => utils::FlushPolicy::Lru,
+            _ => utils::FlushPolicy::Lazy,
+        },
+        only: matches
+            .get_many::<String>("only")
+            .map_or_else(Vec::new, |names| names.cloned().collect()),
+        discard_others: matches.get_flag("discard_others"),
+        grouped_output: matches.get_one::<String>("grouped_output").cloned(),
+        tmpdir: matches.get_one::<PathBuf>("tmpdir").cloned(),
+        shuffle,
+        count_bounds: matches.get_one::<String>("count_bounds").cloned(),
+        interleaved_unknown: matches.get_flag("interleaved_unknown"),
+        unknown_prefix: matches.get_one::<String>("unknown_prefix").unwrap().clone(),
+        keep_empty_outputs: matches.get_flag("keep_empty_outputs"),
+        no_unknown: matches.get_flag("no_unknown"),
+        split_outputs: matches.get_flag("split_outputs"),
+        dry_run: matches.get_flag("dry_run"),
+        count_only: matches.get_flag("count_only"),
+        sanity_check: matches.get_flag("sanity_check"),
+        revcomp: matches.get_flag("rc"),
+        min_reads: matches.get_one::<u32>("min_reads").copied(),
+        summary: matches.get_flag("summary"),
+        stats_tsv: matches.get_one::<PathBuf>("stats_tsv").cloned(),
+        unknown_ids: matches.get_one::<PathBuf>("unknown_ids").cloned(),
+        confidence: matches.get_one::<PathBuf>("confidence").cloned(),
+        tee_decompressed: matches.get_one::<PathBuf>("tee_decompressed").cloned(),
+        panel_hash: matches.get_one::<PathBuf>("panel_hash").cloned(),
+        run_info: matches.get_one::<PathBuf>("run_info").cloned(),
+        report_json: matches.get_one::<PathBuf>("report_json").cloned(),
+        manifest: matches.get_one::<PathBuf>("manifest").cloned(),
+        ubam: matches.get_flag("ubam"),
+        s3: matches
+            .get_one::<String>("s3")
+            .map(|location| sabreur::s3::S3Target::from_env(location))
+            .transpose()?,
+        live_stats,
+        progress: !quiet && io::stderr().is_terminal(),
+        options: demux::DemuxOptions {
+            mismatch: *matches.get_one("mismatch").unwrap(),
+            edit_distance: matches.get_flag("edit_distance"),
+            min_exact_run: matches.get_one::<usize>("min_exact_run").copied(),
+            three_prime: matches.get_one::<String>("position").unwrap() == "3",
+            offset: *matches.get_one::<usize>("offset").unwrap(),
+            trim: matches.get_flag("trim"),
+            trim_length: matches.get_one::<usize>("trim_length").copied(),
+            umi_len: matches.get_one::<usize>("umi_len").copied(),
+            min_len: matches.get_one::<usize>("min_len").copied(),
+            min_mean_quality: matches.get_one::<u8>("min_qual").copied(),
+            expect_length: matches
+                .get_one::<std::ops::RangeInclusive<usize>>("expect_length")
+                .cloned(),
+            unknown_split: matches.get_one::<String>("unknown_split").map(|split| {
+                match split.as_str() {
+                    "format" => demux::UnknownSplit::Format,
+                    _ => demux::UnknownSplit::Length,
+                }
+            }),
+            trim_polyg: matches.get_one::<usize>("trim_polyg").copied(),
+            normalize_ids: matches.get_flag("normalize_mate_ids"),
+            keep_barcode_in_id: matches.get_flag("keep_barcode_in_id"),
+            annotate_distance: matches.get_flag("annotate_distance"),
+            tie_break,
+            input_format: matches.get_one::<String>("input_format").map(|format| {
+                match format.as_str() {
+                    "fastq" => needletail::parser::Format::Fastq,
+                    _ => needletail::parser::Format::Fasta,
+                }
+            }),
+            index: matches.get_one::<String>("index").cloned(),
+            interleaved: matches.get_flag("interleaved"),
+            head: matches.get_one::<usize>("head").copied(),
+            threads: *matches.get_one::<u16>("threads").unwrap() as usize,
+            max_memory: matches.get_one::<usize>("max_memory").copied(),
+            header_barcode,
+            ..Default::default()
+        },
+    };
+
+    let stats = sabreur::demultiplex(config)?;
+    if matches.get_flag("dry_run") {
+        return Ok(());
     }
 
     if !quiet {
         // Finishing
+        for (phase, duration) in stats.timings.phases() {
+            info!("{}: {}ms", phase, duration.as_millis());
+        }
         let duration = startime.elapsed();
         let miliseconds = duration.as_millis();
         let seconds = duration.as_secs();
         let minutes = duration.as_secs() / 60;
         let hours = duration.as_secs() / 3600;
 
-        info!("Results are available in {}", output.display());
+        if !matches.get_flag("count_only") {
+            match matches.get_one::<String>("s3") {
+                Some(location) => info!(
+                    "Results are available in s3://{}",
+                    location.trim_start_matches("s3://")
+                ),
+                None => info!("Results are available in {}", output.display()),
+            }
+        }
         info!(
             "Walltime: {}h:{}m:{}s {}ms",
             hours, minutes, seconds, miliseconds
         );
+        info!(
+            "{}",
+            sabreur::utils::throughput(stats.total_records(), stats.input_bytes, duration)
+        );
         info!("Thanks. Share. Come again!");
     }
 
@@ -0,0 +1,446 @@
+// Copyright 2021-2024 Anicet Ebou.
+// Licensed under the MIT license (http://opensource.org/licenses/MIT)
+// This file may not be copied, modified, or distributed except according
+// to those terms.
+
+//! Unaligned BAM (uBAM) outputs, built with the `ubam` feature.
+//!
+//! A [`UbamWriter`] receives the records written by the demultiplexing
+//! functions and stores each as an unmapped BAM record, named after the
+//! read without its mate suffix and tagged with the barcode of its sample
+//! in `BC`.
+
+use std::path::Path;
+
+#[cfg(feature = "ubam")]
+use std::io::{self, Write};
+
+use anyhow::anyhow;
+
+#[cfg(feature = "ubam")]
+use crate::utils::{normalize_mate_id, OutputFile, OutputRecord, DEFAULT_BUFFER};
+use crate::utils::{Output, OutputBuffers};
+
+/// Flag of single-end reads: unmapped
+pub const UNPAIRED: u16 = 0x4;
+/// Flag of forward reads: paired, unmapped, mate unmapped, first in pair
+pub const FIRST_MATE: u16 = 0x4d;
+/// Flag of reverse reads: paired, unmapped, mate unmapped, second in pair
+pub const SECOND_MATE: u16 = 0x8d;
+
+// Largest uncompressed content of a BGZF block
+#[cfg(feature = "ubam")]
+const BLOCK_SIZE: usize = 0xff00;
+
+// Empty BGZF block closing a BAM file
+#[cfg(feature = "ubam")]
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Name of the uBAM output replacing a fastx output name, e.g.
+/// "sample1.fq.gz" gives "sample1.bam"
+pub fn bam_name(name: &str) -> String {
+    let stem = [".gz", ".bz2", ".xz", ".zst"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name);
+    let stem = [".fasta", ".fastq", ".fa", ".fq"]
+        .iter()
+        .find_map(|ext| stem.strip_suffix(ext))
+        .unwrap_or(stem);
+
+    format!("{}.bam", stem)
+}
+
+/// Create a uBAM output file whose records get `flag` and, if any, the
+/// `barcode` as `BC` tag, buffered in `buffers`
+#[cfg(feature = "ubam")]
+pub fn create_writer(
+    path: &Path,
+    barcode: Option<&[u8]>,
+    flag: u16,
+    level: niffler::Level,
+    buffers: &OutputBuffers,
+) -> anyhow::Result<Box<dyn Output>> {
+    use anyhow::Context;
+
+    let file = std::fs::File::create(path)
+        .with_context(|| anyhow!("Could not open output file '{}'", path.display()))?;
+    let output = OutputFile::new(path, buffers.buffer(file, DEFAULT_BUFFER), |sink| {
+        Ok(Box::new(UbamWriter::new(
+            sink,
+            barcode,
+            flag,
+            level.into(),
+        )?))
+    })?;
+
+    Ok(buffers.flushed(Box::new(output)))
+}
+
+#[cfg(not(feature = "ubam"))]
+pub fn create_writer(
+    _path: &Path,
+    _barcode: Option<&[u8]>,
+    _flag: u16,
+    _level: niffler::Level,
+    _buffers: &OutputBuffers,
+) -> anyhow::Result<Box<dyn Output>> {
+    Err(anyhow!(
+        "sabreur was built without uBAM support, rebuild it with `--features ubam`"
+    ))
+}
+
+/// Writer encoding records into a BGZF compressed uBAM file, only through
+/// `write_record`. The pending block and the BAM end of file marker are
+/// written by `finish`, or when the writer is dropped.
+#[cfg(feature = "ubam")]
+pub struct UbamWriter<W: Write> {
+    inner: W,
+    level: flate2::Compression,
+    barcode: Option<Vec<u8>>,
+    flag: u16,
+    // Uncompressed content of the next BGZF block
+    block: Vec<u8>,
+    finished: bool,
+}
+
+#[cfg(feature = "ubam")]
+impl<W: Write> UbamWriter<W> {
+    pub fn new(
+        inner: W,
+        barcode: Option<&[u8]>,
+        flag: u16,
+        level: flate2::Compression,
+    ) -> io::Result<Self> {
+        let mut writer = UbamWriter {
+            inner,
+            level,
+            barcode: barcode.map(|bc| bc.to_vec()),
+            flag,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            finished: false,
+        };
+        let text = format!(
+            "@HD\tVN:1.6\tSO:unsorted\n@PG\tID:sabreur\tPN:sabreur\tVN:{}\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        writer.block.extend_from_slice(b"BAM\x01");
+        writer
+            .block
+            .extend_from_slice(&(text.len() as i32).to_le_bytes());
+        writer.block.extend_from_slice(text.as_bytes());
+        // no reference sequences
+        writer.block.extend_from_slice(&0i32.to_le_bytes());
+
+        Ok(writer)
+    }
+
+    // Append a record to the block as an unmapped BAM record, named as
+    // its mate
+    fn encode(&mut self, record: &OutputRecord) {
+        let name = normalize_mate_id(record.id);
+        let name = &name[..name.len().min(254)];
+        let tag_len = self.barcode.as_ref().map_or(0, |bc| bc.len() + 4);
+        let l_seq = record.seq.len();
+        let block_size = 32 + name.len() + 1 + l_seq.div_ceil(2) + l_seq + tag_len;
+
+        let block = &mut self.block;
+        block.extend_from_slice(&(block_size as i32).to_le_bytes());
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // refID
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // pos
+        block.push(name.len() as u8 + 1);
+        block.push(255); // mapq
+        block.extend_from_slice(&4680u16.to_le_bytes()); // bin of unmapped reads
+        block.extend_from_slice(&0u16.to_le_bytes()); // no cigar
+        block.extend_from_slice(&self.flag.to_le_bytes());
+        block.extend_from_slice(&(l_seq as i32).to_le_bytes());
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // mate refID
+        block.extend_from_slice(&(-1i32).to_le_bytes()); // mate pos
+        block.extend_from_slice(&0i32.to_le_bytes()); // template length
+        block.extend_from_slice(name);
+        block.push(0);
+        for pair in record.seq.chunks(2) {
+            let high = base_code(pair[0]) << 4;
+            let low = pair.get(1).map_or(0, |base| base_code(*base));
+            block.push(high | low);
+        }
+        match record.qual {
+            Some(qual) => block.extend(qual.iter().map(|q| q.saturating_sub(33))),
+            None => block.resize(block.len() + l_seq, 0xff),
+        }
+        if let Some(bc) = &self.barcode {
+            block.extend_from_slice(b"BCZ");
+            block.extend_from_slice(bc);
+            block.push(0);
+        }
+    }
+
+    // Compress the block content as BGZF blocks
+    fn write_block(&mut self) -> io::Result<()> {
+        for data in self.block.chunks(BLOCK_SIZE) {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), self.level);
+            encoder.write_all(data)?;
+            let cdata = encoder.finish()?;
+            let mut crc = flate2::Crc::new();
+            crc.update(data);
+
+            let bsize = (18 + cdata.len() + 8 - 1) as u16;
+            self.inner.write_all(&[
+                0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C',
+                0x02, 0x00,
+            ])?;
+            self.inner.write_all(&bsize.to_le_bytes())?;
+            self.inner.write_all(&cdata)?;
+            self.inner.write_all(&crc.sum().to_le_bytes())?;
+            self.inner.write_all(&(data.len() as u32).to_le_bytes())?;
+        }
+        self.block.clear();
+
+        Ok(())
+    }
+
+    /// Write the last record, the pending block and the end of file marker
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_block()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "ubam")]
+impl<W: Write> Write for UbamWriter<W> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "uBAM outputs only accept whole records",
+        ))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "ubam")]
+impl<W: Write + Send> Output for UbamWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        UbamWriter::finish(self)
+    }
+
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        self.encode(record);
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "ubam")]
+impl<W: Write> Drop for UbamWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// 4-bit code of a base in BAM sequences
+#[cfg(feature = "ubam")]
+fn base_code(base: u8) -> u8 {
+    b"=ACMGRSVTWYHKDBN"
+        .iter()
+        .position(|code| *code == base.to_ascii_uppercase())
+        .unwrap_or(15) as u8
+}
+
+#[cfg(all(test, feature = "ubam"))]
+mod tests {
+    use super::*;
+    use crate::utils::write_seqs;
+    use std::convert::TryInto;
+
+    // (name, flag, sequence, qualities, BC tag) of the records of a BAM file
+    type BamRecord = (String, u16, String, Vec<u8>, Option<String>);
+
+    // Next `len` bytes of a BAM file
+    fn take<R: io::BufRead>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        reader.read_exact(&mut data).unwrap();
+        data
+    }
+
+    // Records of a BAM file, whose BGZF blocks are read by the bgzip crate
+    // up to its end of file marker
+    fn read_bam(bam: &[u8]) -> Vec<BamRecord> {
+        let mut reader = bgzip::BGZFReader::new(io::Cursor::new(bam));
+        let i32_of = |data: &[u8]| i32::from_le_bytes(data[..4].try_into().unwrap());
+        assert_eq!(take(&mut reader, 4), b"BAM\x01");
+        let l_text = i32_of(&take(&mut reader, 4)) as usize;
+        take(&mut reader, l_text);
+        assert_eq!(i32_of(&take(&mut reader, 4)), 0);
+
+        let mut records = Vec::new();
+        while !io::BufRead::fill_buf(&mut reader).unwrap().is_empty() {
+            let block_size = i32_of(&take(&mut reader, 4)) as usize;
+            let data = take(&mut reader, block_size);
+            let l_name = data[8] as usize;
+            let flag = u16::from_le_bytes([data[14], data[15]]);
+            let l_seq = i32_of(&data[16..]) as usize;
+            let name = String::from_utf8(data[32..32 + l_name - 1].to_vec());
+            let seq_start = 32 + l_name;
+            let seq = (0..l_seq)
+                .map(|i| {
+                    let byte = data[seq_start + i / 2];
+                    let code = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+                    b"=ACMGRSVTWYHKDBN"[code as usize] as char
+                })
+                .collect();
+            let qual_start = seq_start + l_seq.div_ceil(2);
+            let qual = data[qual_start..qual_start + l_seq].to_vec();
+            let tags = &data[qual_start + l_seq..];
+            let bc = tags
+                .strip_prefix(b"BCZ")
+                .map(|bc| String::from_utf8(bc.strip_suffix(b"\0").unwrap().to_vec()).unwrap());
+            records.push((name.unwrap(), flag, seq, qual, bc));
+        }
+        records
+    }
+
+    // Write the records of a fasta or fastq text to a uBAM writer
+    fn write_text(writer: &mut dyn Output, text: &[u8]) {
+        let mut reader = needletail::parse_fastx_reader(text).unwrap();
+        while let Some(record) = reader.next() {
+            let record = record.unwrap();
+            write_seqs(writer, &record, record.id(), 0, None, None).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bam_name() {
+        assert_eq!(bam_name("sample1.fq"), "sample1.bam");
+        assert_eq!(bam_name("sample1.fastq.gz"), "sample1.bam");
+        assert_eq!(bam_name("sample1_R1.fa.zst"), "sample1_R1.bam");
+    }
+
+    #[test]
+    fn test_ubam_writer_fastq() {
+        let mut bam = Vec::new();
+        {
+            let mut writer = UbamWriter::new(
+                &mut bam,
+                Some(b"ACGT"),
+                FIRST_MATE,
+                flate2::Compression::fast(),
+            )
+            .unwrap();
+            write_text(
+                &mut writer,
+                b"@r1/1 extra\nACGTN\n+\nII#5!\n@r2/1\r\nGG\r\n+\r\n##\r\n",
+            );
+            writer.finish().unwrap();
+        }
+
+        // both mates of a pair share their name
+        assert_eq!(
+            read_bam(&bam),
+            vec![
+                (
+                    "r1".to_string(),
+                    FIRST_MATE,
+                    "ACGTN".to_string(),
+                    vec![40, 40, 2, 20, 0],
+                    Some("ACGT".to_string())
+                ),
+                (
+                    "r2".to_string(),
+                    FIRST_MATE,
+                    "GG".to_string(),
+                    vec![2, 2],
+                    Some("ACGT".to_string())
+                ),
+            ]
+        );
+        assert!(bam.ends_with(&EOF_BLOCK));
+    }
+
+    #[test]
+    fn test_ubam_writer_fasta() {
+        let mut bam = Vec::new();
+        {
+            let mut writer =
+                UbamWriter::new(&mut bam, None, UNPAIRED, flate2::Compression::fast()).unwrap();
+            write_text(&mut writer, b">r1\nACG\nTA\n>r2\nC");
+        }
+
+        assert_eq!(
+            read_bam(&bam),
+            vec![
+                (
+                    "r1".to_string(),
+                    UNPAIRED,
+                    "ACGTA".to_string(),
+                    vec![0xff; 5],
+                    None
+                ),
+                (
+                    "r2".to_string(),
+                    UNPAIRED,
+                    "C".to_string(),
+                    vec![0xff],
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ubam_writer_text() {
+        let mut writer =
+            UbamWriter::new(Vec::new(), None, UNPAIRED, flate2::Compression::fast()).unwrap();
+
+        assert!(writer.write_all(b">r1\nACGT\n").is_err());
+    }
+
+    #[test]
+    fn test_ubam_writer_blocks() {
+        let mut bam = Vec::new();
+        {
+            let mut writer =
+                UbamWriter::new(&mut bam, Some(b"AC"), UNPAIRED, flate2::Compression::fast())
+                    .unwrap();
+            let seq = "ACGT".repeat(10);
+            let qual = "I".repeat(40);
+            for i in 0..5000 {
+                writer
+                    .write_record(&OutputRecord {
+                        id: format!("r{}", i).as_bytes(),
+                        seq: seq.as_bytes(),
+                        qual: Some(qual.as_bytes()),
+                        format: needletail::parser::Format::Fastq,
+                        raw: None,
+                    })
+                    .unwrap();
+            }
+        }
+
+        // several BGZF blocks, each giving its own size
+        let mut pos = 0;
+        let mut nb_blocks = 0;
+        while pos < bam.len() {
+            assert_eq!(&bam[pos + 12..pos + 14], b"BC");
+            pos += u16::from_le_bytes([bam[pos + 16], bam[pos + 17]]) as usize + 1;
+            nb_blocks += 1;
+        }
+        assert_eq!(pos, bam.len());
+        assert!(nb_blocks > 2);
+        assert_eq!(read_bam(&bam).len(), 5000);
+    }
+}
@@ -3,14 +3,24 @@
 // This file may not be copied, modified, or distributed except according
 // to those terms.
 
-use std::fs::File;
-use std::io;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use fern::colors::ColoredLevelConfig;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 
-pub fn setup_logging(quiet: bool) -> anyhow::Result<(), fern::InitError> {
+// Set up logging to stdout, to the `log_file` if any and to syslog
+pub fn setup_logging(quiet: bool, syslog: bool, log_file: Option<&Path>) -> anyhow::Result<()> {
     let colors = ColoredLevelConfig::default();
     let mut base_config = fern::Dispatch::new();
 
@@ -24,167 +34,3536 @@ pub fn setup_logging(quiet: bool) -> anyhow::Result<(), fern::InitError> {
     };
 
     // Separate file config so we can include year, month and day in file logs
-    let file_config = fern::Dispatch::new()
-        .format(|out, message, record| {
+    if let Some(path) = log_file {
+        let file_config = fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{}[{}][{}] {}",
+                    chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
+                    record.target(),
+                    record.level(),
+                    message
+                ))
+            })
+            .chain(
+                fern::log_file(path)
+                    .with_context(|| anyhow!("Could not open log file '{}'", path.display()))?,
+            );
+        base_config = base_config.chain(file_config);
+    }
+
+    let stdout_config = fern::Dispatch::new()
+        .format(move |out, message, record| {
             out.finish(format_args!(
-                "{}[{}][{}] {}",
-                chrono::Local::now().format("[%Y-%m-%d][%H:%M:%S]"),
-                record.target(),
-                record.level(),
+                "[{}][{}] {}",
+                chrono::Local::now().format("%H:%M:%S"),
+                colors.color(record.level()),
                 message
             ))
         })
-        .chain(fern::log_file("sabreur.log")?);
+        // The progress bar is hidden while a log line is written
+        .chain(fern::Output::call(|record| {
+            let print = || {
+                let _ = writeln!(io::stdout(), "{}", record.args());
+            };
+            match PROGRESS_BAR.lock().unwrap().as_ref() {
+                Some(bar) => bar.suspend(print),
+                None => print(),
+            }
+        }));
+
+    base_config = base_config.chain(stdout_config);
+
+    if syslog {
+        base_config = base_config.chain(syslog_config()?);
+    }
+
+    base_config.apply()?;
+
+    Ok(())
+}
+
+// Forward log records to the local syslog daemon
+#[cfg(all(feature = "syslog", not(windows)))]
+fn syslog_config() -> anyhow::Result<fern::Dispatch> {
+    let formatter = syslog::Formatter3164 {
+        facility: syslog::Facility::LOG_USER,
+        hostname: None,
+        process: "sabreur".into(),
+        pid: std::process::id(),
+    };
+    let logger =
+        syslog::unix(formatter).map_err(|e| anyhow!("Could not connect to syslog: {}", e))?;
+
+    Ok(fern::Dispatch::new().chain(logger))
+}
+
+#[cfg(not(all(feature = "syslog", not(windows))))]
+fn syslog_config() -> anyhow::Result<fern::Dispatch> {
+    Err(anyhow!(
+        "sabreur was built without syslog support, rebuild it with `--features syslog`"
+    ))
+}
+
+pub fn create_relpath_from(
+    basedir: &mut PathBuf,
+    filename: &str,
+    extension: niffler::send::compression::Format,
+) -> PathBuf {
+    let ext = to_compression_ext(extension);
+    let mut mstr = String::with_capacity(filename.len() + ext.len());
+    mstr.push_str(filename);
+    mstr.push_str(&ext);
+    basedir.push(mstr);
+
+    basedir.to_path_buf()
+}
+
+// Build a file name from `stem` reusing the extension of `model`,
+// e.g. ("pool", "sample1.fq") gives "pool.fq"
+pub fn with_ext_of(stem: &str, model: &str) -> String {
+    match Path::new(model).extension() {
+        Some(ext) => format!("{}.{}", stem, ext.to_string_lossy()),
+        None => stem.to_string(),
+    }
+}
+
+// Move the outputs of samples with less than `min_reads` records at the
+// end of the matching pool file (one pool per mate) and return the
+// barcodes of the pooled samples. Compressed outputs stay readable as
+// gz, bz2, xz and zst all accept concatenated streams.
+pub fn pool_low_count<'a>(
+    stats: &HashMap<&[u8], u32>,
+    samples: &[(&'a [u8], Vec<PathBuf>)],
+    min_reads: u32,
+    pools: &[PathBuf],
+) -> anyhow::Result<Vec<&'a [u8]>> {
+    let low = samples
+        .iter()
+        .filter(|(bc, _)| stats.get(bc).copied().unwrap_or(0) < min_reads)
+        .collect::<Vec<_>>();
+
+    for (mate, pool) in pools.iter().enumerate() {
+        if low.is_empty() {
+            break;
+        }
+        let mut pool_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(pool)?;
+        for (_, paths) in low.iter() {
+            io::copy(&mut File::open(&paths[mate])?, &mut pool_file)?;
+            fs::remove_file(&paths[mate])?;
+        }
+    }
+
+    Ok(low.iter().map(|(bc, _)| *bc).collect())
+}
+
+// Concatenate the files of `parts`, in order, into a new `dest` file.
+// Compressed parts of the same format concatenate to a valid stream.
+pub fn concat_files(parts: &[PathBuf], dest: &Path) -> io::Result<()> {
+    let mut out = File::create(dest)?;
+    for part in parts {
+        io::copy(&mut File::open(part)?, &mut out)?;
+    }
+
+    Ok(())
+}
+
+// Shuffle the records of the output files of a sample, one per mate with
+// its compression in `formats`. Every file gets the same permutation from
+// `seed`, so that mates stay in step. Records are shuffled in memory,
+// unless the largest file holds more than `max_memory` bytes: records are
+// then first spread at random over spill files under `tmpdir`, each small
+// enough to be shuffled in memory and written after the previous one.
+pub fn shuffle_outputs(
+    paths: &[PathBuf],
+    formats: &[niffler::send::compression::Format],
+    level: niffler::Level,
+    encoders: &Encoders,
+    seed: u64,
+    max_memory: Option<usize>,
+    tmpdir: Option<&Path>,
+) -> anyhow::Result<()> {
+    let nb_spills = match max_memory {
+        Some(max_memory) => {
+            let mut largest = 0;
+            for path in paths {
+                let (mut reader, _) = open_input(&path.to_string_lossy())?;
+                largest = largest.max(io::copy(&mut reader, &mut io::sink())?);
+            }
+            // Twice the minimum, as random spills are not evenly filled
+            match largest as usize > max_memory {
+                true => 2 * (largest as usize).div_ceil(max_memory.max(1)),
+                false => 1,
+            }
+        }
+        None => 1,
+    };
+
+    for (path, format) in paths.iter().zip(formats) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let records = read_records(path)?;
+        fs::remove_file(path)?;
+        let mut out = create_writer_with_capacity(
+            path,
+            *format,
+            level,
+            encoders,
+            &OutputBuffers::default(),
+            DEFAULT_BUFFER,
+        )?;
+
+        if nb_spills == 1 {
+            let mut records = records;
+            records.shuffle(&mut rng);
+            for record in records {
+                out.write_all(&record)?;
+            }
+            out.finish()?;
+            continue;
+        }
+
+        let spill = SpillDir::create(tmpdir)?;
+        let spill_paths = (0..nb_spills)
+            .map(|i| spill.path().join(format!("{}.fx", i)))
+            .collect::<Vec<_>>();
+        let mut spills = spill_paths
+            .iter()
+            .map(|path| File::create(path).map(io::BufWriter::new))
+            .collect::<io::Result<Vec<_>>>()?;
+        for record in records {
+            spills[rng.gen_range(0..nb_spills)].write_all(&record)?;
+        }
+        for spill in spills.iter_mut() {
+            spill.flush()?;
+        }
+        drop(spills);
+
+        for spill_path in spill_paths.iter() {
+            let mut records = read_records(spill_path)?;
+            records.shuffle(&mut rng);
+            for record in records {
+                out.write_all(&record)?;
+            }
+        }
+        out.finish()?;
+    }
+
+    Ok(())
+}
+
+// Records of a FASTA/FASTQ file, each as written to an output
+fn read_records(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    let (reader, _) = open_input(&path.to_string_lossy())?;
+    let mut reader = io::BufReader::new(reader);
+    if io::BufRead::fill_buf(&mut reader)?.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut records = Vec::new();
+    let mut parser = needletail::parse_fastx_reader(reader)?;
+    while let Some(record) = parser.next() {
+        let record = record?;
+        let mut bytes = Vec::new();
+        write_seqs(&mut bytes, &record, record.id(), 0, None, None)?;
+        records.push(bytes);
+    }
+
+    Ok(records)
+}
+
+// Temporary directory holding the per-sample spill files of a grouped
+// output, removed with its content when dropped
+pub struct SpillDir(PathBuf);
+
+impl SpillDir {
+    // Create a new spill directory under `parent`, the system temporary
+    // directory when None
+    pub fn create(parent: Option<&Path>) -> io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let parent = parent.map_or_else(std::env::temp_dir, Path::to_path_buf);
+        let path = parent.join(format!(
+            "sabreur-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(SpillDir(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// to_niffler_format function
+pub fn to_niffler_format(format: &str) -> anyhow::Result<niffler::send::compression::Format> {
+    match format {
+        "gz" => Ok(niffler::send::compression::Format::Gzip),
+        "bz2" => Ok(niffler::send::compression::Format::Bzip),
+        "xz" => Ok(niffler::send::compression::Format::Lzma),
+        "zst" => Ok(niffler::send::compression::Format::Zstd),
+        _ => Ok(niffler::send::compression::Format::No),
+    }
+}
+
+// Convert niffler compression format to a file extension
+pub fn to_compression_ext(compression: niffler::send::compression::Format) -> String {
+    match compression {
+        niffler::send::compression::Format::Gzip => ".gz".to_string(),
+        niffler::send::compression::Format::Bzip => ".bz2".to_string(),
+        niffler::send::compression::Format::Lzma => ".xz".to_string(),
+        niffler::send::compression::Format::Zstd => ".zst".to_string(),
+        niffler::send::compression::Format::No => "".to_string(),
+    }
+}
+
+// Convert an integer from 1 to 9 to a niffler::Level
+pub fn to_niffler_level(int_level: u8) -> anyhow::Result<niffler::Level> {
+    match int_level {
+        1 => Ok(niffler::Level::One),
+        2 => Ok(niffler::Level::Two),
+        3 => Ok(niffler::Level::Three),
+        4 => Ok(niffler::Level::Four),
+        5 => Ok(niffler::Level::Five),
+        6 => Ok(niffler::Level::Six),
+        7 => Ok(niffler::Level::Seven),
+        8 => Ok(niffler::Level::Eight),
+        9 => Ok(niffler::Level::Nine),
+        _ => Err(anyhow!(
+            "compression level {} is out of range, expected 1 to 9",
+            int_level
+        )),
+    }
+}
+
+// Split a &str at each \t, or at each comma if it has no tab
+pub fn split_by_tab(string: &str) -> anyhow::Result<Vec<Vec<&str>>> {
+    Ok(split_by_delimiter(string, None)?
+        .into_iter()
+        .map(|(_, fields)| fields)
+        .collect())
+}
+
+// Row of a barcode file: its 1-based line number in the file, and its
+// fields
+pub type Row<'a> = (usize, Vec<&'a str>);
+
+// Split a &str at each `delimiter`, detected as \t or else a comma when
+// None. Blank lines and lines starting with # are skipped, keeping the
+// line numbers of the others, and fields are trimmed of whitespace such
+// as the \r of CRLF line endings.
+pub fn split_by_delimiter(string: &str, delimiter: Option<char>) -> anyhow::Result<Vec<Row<'_>>> {
+    let delimiter = detect_delimiter(string, delimiter)?;
+
+    Ok(content_lines(string)
+        .map(|(line, text)| (line, text.split(delimiter).map(str::trim).collect()))
+        .collect())
+}
+
+// Delimiter split_by_delimiter uses on a &str
+pub fn detect_delimiter(string: &str, delimiter: Option<char>) -> anyhow::Result<char> {
+    let has = |c: char| content_lines(string).any(|(_, text)| text.contains(c));
+
+    match delimiter {
+        Some(delimiter) if has(delimiter) => Ok(delimiter),
+        Some('\t') => Err(anyhow!("string is not tab-delimited")),
+        Some(delimiter) => Err(anyhow!("string is not '{}'-delimited", delimiter)),
+        None if has('\t') => Ok('\t'),
+        None if has(',') => Ok(','),
+        None => Err(anyhow!("string is neither tab nor comma delimited")),
+    }
+}
+
+// Lines of a &str other than blank lines and # comments, with their
+// 1-based line number
+fn content_lines(string: &str) -> impl Iterator<Item = (usize, &str)> {
+    string
+        .lines()
+        .map(str::trim_end)
+        .enumerate()
+        .filter(|(_, text)| !text.is_empty() && !text.starts_with('#'))
+        .map(|(i, text)| (i + 1, text))
+}
+
+// Convert a --delimiter name to the character it stands for
+pub fn to_delimiter(name: &str) -> char {
+    match name {
+        "comma" => ',',
+        _ => '\t',
+    }
+}
+
+// Split comma-joined `R1,R2` output fields of a paired-end barcode file
+// into separate forward and reverse output names
+pub fn split_paired_outputs(rows: Vec<Row>) -> anyhow::Result<Vec<Row>> {
+    rows.into_iter()
+        .map(|(line, fields)| match fields.as_slice() {
+            [barcode, outputs] => match outputs.split_once(',') {
+                Some((r1, r2)) if !r1.is_empty() && !r2.is_empty() && !r2.contains(',') => {
+                    Ok((line, vec![*barcode, r1, r2]))
+                }
+                _ => Err(anyhow!(
+                    "line {}: barcode {} output field '{}' is not of the form R1,R2",
+                    line,
+                    barcode,
+                    outputs
+                )),
+            },
+            _ => Ok((line, fields)),
+        })
+        .collect()
+}
+
+// Remove the output files flagged as empty, unless empty outputs are
+// kept for downstream tools expecting every file to exist
+pub fn remove_empty_outputs(outputs: &[(&Path, bool)], keep_empty: bool) -> io::Result<()> {
+    if keep_empty {
+        return Ok(());
+    }
+    for (path, is_empty) in outputs {
+        if *is_empty {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Write the per-barcode read counts as a tab-separated table sorted by
+// decreasing count, with the unknown reads (`XXX`) on the last row
+pub fn write_stats_tsv<W: Write>(
+    mut out: W,
+    barcode_fields: &[Vec<&str>],
+    stats: &HashMap<&[u8], u32>,
+    unknown_files: &[&str],
+) -> io::Result<()> {
+    let count = |bc: &[u8]| stats.get(bc).copied().unwrap_or(0);
+    let total = total_records(stats.iter().map(|(bc, n)| (*bc, *n)));
+    let percent = |n: u32| percentage(n, total);
+
+    let mut rows = barcode_fields.iter().collect::<Vec<_>>();
+    rows.sort_by_key(|fields| std::cmp::Reverse(count(fields[0].as_bytes())));
+
+    writeln!(
+        out,
+        "barcode\tforward_file\treverse_file\tread_count\tpercent_of_total"
+    )?;
+    for fields in rows {
+        let n = count(fields[0].as_bytes());
+        writeln!(
+            out,
+            "{}\t{}\t{}\t{}\t{:.2}",
+            fields[0],
+            fields[1],
+            fields.get(2).unwrap_or(&""),
+            n,
+            percent(n)
+        )?;
+    }
+    let n = count(b"XXX");
+    writeln!(
+        out,
+        "XXX\t{}\t{}\t{}\t{:.2}",
+        unknown_files[0],
+        unknown_files.get(1).unwrap_or(&""),
+        n,
+        percent(n)
+    )?;
+
+    Ok(())
+}
+
+// Write the output files planned for each barcode, one line per file with
+// its compression, discarded barcodes having no file
+pub fn write_plan<W: Write>(
+    mut out: W,
+    barcode_fields: &[Vec<&str>],
+    outdir: &Path,
+    formats: &[niffler::send::compression::Format],
+    unknown_files: &[&str],
+) -> io::Result<()> {
+    let compression = |format| match to_compression_ext(format).trim_start_matches('.') {
+        "" => "none".to_string(),
+        ext => ext.to_string(),
+    };
+
+    let rows = barcode_fields
+        .iter()
+        .map(|fields| (fields[0], &fields[1..]))
+        .chain(std::iter::once(("XXX", unknown_files)));
+    for (barcode, names) in rows {
+        if names.iter().any(|name| is_discard(name)) {
+            writeln!(out, "{}\tDISCARD\t-", barcode)?;
+            continue;
+        }
+        for (name, format) in names.iter().zip(formats) {
+            let path = create_relpath_from(&mut outdir.to_path_buf(), name, *format);
+            writeln!(
+                out,
+                "{}\t{}\t{}",
+                barcode,
+                path.display(),
+                compression(*format)
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+// Is an output file name the sentinel for reads to count but not write?
+pub fn is_discard(filename: &str) -> bool {
+    filename == "DISCARD" || filename == "/dev/null"
+}
+
+// Check the rows of a barcode file, once its optional columns are taken
+// out: one barcode of A, C, G, T or N and one (single-end) or two
+// (paired-end) output files per row, no barcode or output file used twice.
+// `delimiter` separates the columns, None for barcodes not read from a file.
+pub fn validate_barcode_fields(
+    rows: &[Row],
+    is_pe: bool,
+    delimiter: Option<char>,
+) -> anyhow::Result<()> {
+    let nb_columns = if is_pe { 3 } else { 2 };
+    let mut barcodes = HashMap::new();
+    let mut outputs = HashMap::new();
+
+    for (line, f) in rows.iter() {
+        let line = *line;
+        if f.len() != nb_columns {
+            return Err(anyhow!(
+                "line {}: expected {} {}columns in {} mode, found {}",
+                line,
+                nb_columns,
+                match delimiter {
+                    Some('\t') => "tab-separated ".to_string(),
+                    Some(',') => "comma-separated ".to_string(),
+                    Some(delimiter) => format!("'{}'-separated ", delimiter),
+                    None => String::new(),
+                },
+                if is_pe { "paired-end" } else { "single-end" },
+                f.len()
+            ));
+        }
+
+        let barcode = f[0];
+        if barcode.is_empty()
+            || !barcode
+                .bytes()
+                .all(|b| matches!(b.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+        {
+            return Err(anyhow!(
+                "line {}: barcode '{}' should only contain A, C, G, T or N",
+                line,
+                barcode
+            ));
+        }
+        if let Some(first) = barcodes.insert(barcode, line) {
+            return Err(anyhow!(
+                "line {}: barcode {} is already given line {}",
+                line,
+                barcode,
+                first
+            ));
+        }
+
+        for name in f[1..].iter().filter(|name| !is_discard(name)) {
+            if let Some(first) = outputs.insert(*name, line) {
+                return Err(anyhow!(
+                    "line {}: output file '{}' is already used line {}",
+                    line,
+                    name,
+                    first
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Remove the optional per-barcode mismatch column found after the
+// `nb_outputs` output file names and return the thresholds it gives
+pub fn take_mismatch_column(
+    rows: &mut [Row],
+    nb_outputs: usize,
+) -> anyhow::Result<HashMap<Vec<u8>, u8>> {
+    let mut thresholds = HashMap::new();
+    for (line, f) in rows.iter_mut() {
+        if f.len() <= nb_outputs + 1 {
+            continue;
+        }
+        if f.len() > nb_outputs + 2 {
+            return Err(anyhow!(
+                "barcode file line {}: expected at most {} columns, found {}",
+                line,
+                nb_outputs + 2,
+                f.len()
+            ));
+        }
+        let value = f.pop().unwrap();
+        // A dash keeps the --mismatch value, for rows giving a quality column
+        if value.trim() == "-" {
+            continue;
+        }
+        let mismatch = value.trim().parse::<u8>().with_context(|| {
+            anyhow!(
+                "barcode file line {}: mismatch '{}' of barcode {} is not a number",
+                line,
+                value,
+                f[0]
+            )
+        })?;
+        thresholds.insert(f[0].as_bytes().to_vec(), mismatch);
+    }
+
+    Ok(thresholds)
+}
+
+// Remove the optional per-sample minimum quality column found after the
+// mismatch one and return the thresholds it gives. To be called before
+// take_mismatch_column.
+pub fn take_quality_column(
+    rows: &mut [Row],
+    nb_outputs: usize,
+) -> anyhow::Result<HashMap<Vec<u8>, u8>> {
+    let mut thresholds = HashMap::new();
+    for (line, f) in rows.iter_mut() {
+        if f.len() <= nb_outputs + 2 {
+            continue;
+        }
+        if f.len() > nb_outputs + 3 {
+            return Err(anyhow!(
+                "barcode file line {}: expected at most {} columns, found {}",
+                line,
+                nb_outputs + 3,
+                f.len()
+            ));
+        }
+        let value = f.pop().unwrap();
+        let quality = value.trim().parse::<u8>().with_context(|| {
+            anyhow!(
+                "barcode file line {}: minimum quality '{}' of barcode {} is not a number",
+                line,
+                value,
+                f[0]
+            )
+        })?;
+        thresholds.insert(f[0].as_bytes().to_vec(), quality);
+    }
+
+    Ok(thresholds)
+}
+
+// Does an output file name end in a FASTA/FASTQ extension, possibly
+// followed by a compression one?
+pub fn has_fastx_ext(filename: &str) -> bool {
+    let name = filename.to_ascii_lowercase();
+    let name = [".gz", ".bz2", ".xz", ".zst"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name);
+
+    [".fa", ".fasta", ".fq", ".fastq"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+// Tokens of a file name, without its directory nor its FASTA/FASTQ and
+// compression extensions, e.g. s1, l001, r1 and 001 for
+// S1_L001_R1_001.fastq.gz
+fn name_tokens(path: &str) -> Vec<String> {
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let name = [".gz", ".bz2", ".xz", ".zst"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name);
+    let name = [".fa", ".fasta", ".fq", ".fastq"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(name);
+
+    name.split(['_', '.', '-']).map(String::from).collect()
+}
+
+// Do a forward and a reverse file name the same sample and lane? Their
+// names may only differ by their mate token, 1 and 2 or R1 and R2.
+pub fn is_mate_pair(forward: &str, reverse: &str) -> bool {
+    let (forward, reverse) = (name_tokens(forward), name_tokens(reverse));
+    if forward.len() != reverse.len() {
+        return false;
+    }
+    let mut diff = forward.iter().zip(reverse.iter()).filter(|(f, r)| f != r);
+    match (diff.next(), diff.next()) {
+        (None, _) => true,
+        (Some((f, r)), None) => matches!((f.as_str(), r.as_str()), ("1", "2") | ("r1", "r2")),
+        _ => false,
+    }
+}
+
+// Compression format shared by the files demultiplexed into the same
+// outputs, sniffed for each of them as with `sniff_format`
+pub fn sniff_formats(
+    paths: &[String],
+    trust_ext: bool,
+    counter: Option<&ByteCounter>,
+    opened: &OpenedInputs,
+) -> anyhow::Result<niffler::send::compression::Format> {
+    let format = sniff_format(&paths[0], trust_ext, counter, opened)?;
+    for path in paths[1..].iter() {
+        let other = sniff_format(path, trust_ext, counter, opened)?;
+        if other != format {
+            let describe = |format| match to_compression_ext(format).as_str() {
+                "" => "uncompressed".to_string(),
+                ext => format!("{} compressed", &ext[1..]),
+            };
+            return Err(anyhow!(
+                "'{}' is {} but '{}' is {}, their outputs cannot share one compression",
+                paths[0],
+                describe(format),
+                path,
+                describe(other)
+            ));
+        }
+    }
+
+    Ok(format)
+}
+
+// Extension, fa or fq, of the FASTA/FASTQ file name, if it tells
+pub fn fastx_ext(filename: &str) -> Option<&'static str> {
+    let name = filename.to_ascii_lowercase();
+    let name = [".gz", ".bz2", ".xz", ".zst"]
+        .iter()
+        .find_map(|ext| name.strip_suffix(ext))
+        .unwrap_or(&name);
+
+    if name.ends_with(".fq") || name.ends_with(".fastq") {
+        Some("fq")
+    } else if name.ends_with(".fa") || name.ends_with(".fasta") {
+        Some("fa")
+    } else {
+        None
+    }
+}
+
+// Check that every output file of a barcode file has a FASTA/FASTQ
+// extension, catching swapped columns and stray values
+pub fn check_output_names(rows: &[Row]) -> anyhow::Result<()> {
+    for (line, f) in rows.iter() {
+        for name in f.iter().skip(1) {
+            if !is_discard(name) && !has_fastx_ext(name) {
+                return Err(anyhow!(
+                    "barcode file line {}: output file '{}' of barcode {} should end in \
+                    .fa, .fasta, .fq or .fastq, optionally followed by .gz, .bz2, .xz or .zst",
+                    line,
+                    name,
+                    f[0]
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Keep the samples of a barcode file named in `only`, by barcode, output
+// file name or output file name without its extensions. The other
+// samples are dropped from the panel, so that their reads are unknown,
+// or have their outputs discarded when `discard_others`.
+pub fn select_samples<'a>(
+    fields: Vec<Vec<&'a str>>,
+    only: &[String],
+    discard_others: bool,
+) -> anyhow::Result<Vec<Vec<&'a str>>> {
+    let is_named = |f: &[&str], name: &str| {
+        f[0] == name
+            || f[1..]
+                .iter()
+                .any(|out| *out == name || out.split('.').next() == Some(name))
+    };
+    if let Some(name) = only
+        .iter()
+        .find(|name| !fields.iter().any(|f| is_named(f, name)))
+    {
+        return Err(anyhow!("no sample of the barcode file is named '{}'", name));
+    }
+
+    Ok(fields
+        .into_iter()
+        .filter_map(|f| match only.iter().any(|name| is_named(&f, name)) {
+            true => Some(f),
+            false if discard_others => Some(
+                std::iter::once(f[0])
+                    .chain(f[1..].iter().map(|_| "DISCARD"))
+                    .collect(),
+            ),
+            false => None,
+        })
+        .collect())
+}
+
+// Barcodes added, removed or whose output files changed between two panels
+#[derive(Debug, Default, PartialEq)]
+pub struct PanelDiff<'a> {
+    pub added: Vec<(&'a str, &'a [&'a str])>,
+    pub removed: Vec<(&'a str, &'a [&'a str])>,
+    pub changed: Vec<(&'a str, &'a [&'a str], &'a [&'a str])>,
+}
+
+// Stable hash of a resolved panel, its barcodes and output names. Lines
+// are hashed sorted, as their order does not change the demultiplexing,
+// with FNV-1a which unlike the std hashers is fixed across releases.
+pub fn panel_hash(fields: &[Vec<&str>]) -> u64 {
+    let mut lines = fields
+        .iter()
+        .map(|line| line.join("\t"))
+        .collect::<Vec<_>>();
+    lines.sort();
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in lines.join("\n").bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+// Compare two panels as parsed by split_by_tab, `new` against `old`
+pub fn compare_panels<'a>(old: &'a [Vec<&'a str>], new: &'a [Vec<&'a str>]) -> PanelDiff<'a> {
+    let old_map: HashMap<&str, &[&str]> = old.iter().map(|f| (f[0], &f[1..])).collect();
+    let new_map: HashMap<&str, &[&str]> = new.iter().map(|f| (f[0], &f[1..])).collect();
+
+    let mut diff = PanelDiff::default();
+    for fields in new.iter() {
+        match old_map.get(fields[0]) {
+            None => diff.added.push((fields[0], &fields[1..])),
+            Some(old_files) if *old_files != &fields[1..] => {
+                diff.changed.push((fields[0], old_files, &fields[1..]))
+            }
+            Some(_) => {}
+        }
+    }
+    for fields in old.iter() {
+        if !new_map.contains_key(fields[0]) {
+            diff.removed.push((fields[0], &fields[1..]));
+        }
+    }
+
+    diff
+}
+
+// Does a sequence base match a barcode base, N in the barcode standing for
+// any base?
+fn base_match(bc_base: u8, base: u8) -> bool {
+    bc_base.eq_ignore_ascii_case(&b'N') || bc_base.eq_ignore_ascii_case(&base)
+}
+
+// Compare provided barcode with a sequence, allowing `mismatch` differing
+// bases as counted by hamming_distance
+pub fn bc_cmp(bc: &[u8], seq: &[u8], mismatch: u8) -> bool {
+    hamming_distance(bc, seq) <= mismatch as u32
+}
+
+// Number of differing bases between a barcode and a sequence. An N in the
+// barcode matches any base and case is ignored on both sides, so lowercase
+// (soft-masked) reads match too.
+pub fn hamming_distance(bc: &[u8], seq: &[u8]) -> u32 {
+    bc.iter()
+        .zip(seq.iter())
+        .map(|(a, b)| !base_match(*a, *b) as u32)
+        .sum()
+}
+
+// Longest stretch of consecutive barcode bases matching the start of a
+// sequence, position by position, with the same rules as hamming_distance
+pub fn longest_exact_run(bc: &[u8], seq: &[u8]) -> usize {
+    let (mut longest, mut run) = (0, 0);
+    for (a, b) in bc.iter().zip(seq.iter()) {
+        run = if base_match(*a, *b) { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+
+    longest
+}
+
+// Longest stretch of consecutive barcode bases found in a sequence at any
+// offset, e.g. on either side of an indel
+pub fn longest_common_run(bc: &[u8], seq: &[u8]) -> usize {
+    let mut longest = 0;
+    // Runs ending at the previous barcode base and each sequence position
+    let mut prev = vec![0; seq.len() + 1];
+    for a in bc {
+        let mut cur = vec![0; seq.len() + 1];
+        for (j, b) in seq.iter().enumerate() {
+            if base_match(*a, *b) {
+                cur[j + 1] = prev[j] + 1;
+                longest = longest.max(cur[j + 1]);
+            }
+        }
+        prev = cur;
+    }
+
+    longest
+}
+
+// Compare provided barcode with the start of a sequence allowing
+// insertions and deletions, up to `max_dist` edits
+pub fn bc_cmp_levenshtein(bc: &[u8], seq: &[u8], max_dist: u8) -> bool {
+    levenshtein_distance(bc, seq, max_dist) <= max_dist as u32
+}
+
+// Edit distance between a barcode and the start of a sequence. An indel
+// shifts the end of the barcode in the read, so the barcode is compared
+// with read prefixes of its length give or take `max_dist` bases,
+// reading past the barcode length, and the smallest distance is kept.
+// Bases are compared as in hamming_distance: an N in the barcode matches
+// any base and case is ignored.
+//
+// This is a small dynamic programming table rather than
+// triple_accel::levenshtein, which only compares whole strings byte for
+// byte: it could neither score every read prefix in one pass nor treat N
+// as a wildcard. Barcodes are short, so the table stays cheap.
+pub fn levenshtein_distance(bc: &[u8], seq: &[u8], max_dist: u8) -> u32 {
+    let shortest = bc.len().saturating_sub(max_dist as usize);
+    let longest = (bc.len() + max_dist as usize).min(seq.len());
+    if shortest > longest {
+        return u32::MAX;
+    }
+
+    // Distances between the barcode bases seen so far and each read prefix
+    let mut prev = (0..=longest as u32).collect::<Vec<_>>();
+    for (i, a) in bc.iter().enumerate() {
+        let mut cur = vec![i as u32 + 1; longest + 1];
+        for (j, b) in seq[..longest].iter().enumerate() {
+            let substitution = prev[j] + !base_match(*a, *b) as u32;
+            cur[j + 1] = substitution.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+
+    prev[shortest..].iter().copied().min().unwrap_or(u32::MAX)
+}
+
+// Input path standing for the standard input
+pub const STDIN: &str = "-";
+
+// Is the input a stream that can only be read once, stdin or a named pipe
+// such as the `<(zcat reads.fq.gz)` of a process substitution?
+pub fn is_stream(path: &str) -> bool {
+    path == STDIN
+        || fs::metadata(path)
+            .map(|m| !m.is_file() && !m.is_dir())
+            .unwrap_or(false)
+}
+
+// Reverse complement of a DNA sequence, in uppercase, bases other than A,
+// C, G and T becoming N
+pub fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base.to_ascii_uppercase() {
+            b'A' => b'T',
+            b'T' => b'A',
+            b'C' => b'G',
+            b'G' => b'C',
+            _ => b'N',
+        })
+        .collect()
+}
+
+// Compression format of an input file, sniffed as by sniff_format without
+// keeping the input open. Stdin and pipes are reported as uncompressed.
+pub fn which_format(filename: &str) -> niffler::send::compression::Format {
+    sniff_format(filename, false, None, &OpenedInputs::default()).expect("file should be readable")
+}
+
+// Compression format given by the file extension, None when the extension
+// does not tell (no extension, or not a known compression or fastx one)
+pub fn format_from_ext(filename: &str) -> Option<niffler::send::compression::Format> {
+    let ext = Path::new(filename)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "gz" => Some(niffler::send::compression::Format::Gzip),
+        "bz2" => Some(niffler::send::compression::Format::Bzip),
+        "xz" => Some(niffler::send::compression::Format::Lzma),
+        "zst" => Some(niffler::send::compression::Format::Zstd),
+        "fa" | "fasta" | "fq" | "fastq" => Some(niffler::send::compression::Format::No),
+        _ => None,
+    }
+}
+
+// Is the input given as an http(s) url?
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+// Is the input a glob pattern rather than a plain path?
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+// Expand a directory, to the FASTA/FASTQ files it holds, or a glob
+// pattern to the sorted list of matching files. Other inputs are kept
+// as they are.
+pub fn expand_inputs(path: &str) -> anyhow::Result<Vec<String>> {
+    let mut files = if is_url(path) || path == STDIN {
+        return Ok(vec![path.to_string()]);
+    } else if Path::new(path).is_dir() {
+        fs::read_dir(path)
+            .with_context(|| anyhow!("Could not read directory '{}'", path))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?
+            .into_iter()
+            .filter(|p| p.is_file() && has_fastx_ext(&p.to_string_lossy()))
+            .collect::<Vec<_>>()
+    } else if is_glob(path) {
+        glob::glob(path)
+            .with_context(|| anyhow!("Invalid glob pattern '{}'", path))?
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter(|p| p.is_file())
+            .collect::<Vec<_>>()
+    } else {
+        return Ok(vec![path.to_string()]);
+    };
+
+    if files.is_empty() {
+        return Err(anyhow!("No FASTA/FASTQ file found in '{}'", path));
+    }
+    files.sort();
+
+    Ok(files
+        .into_iter()
+        .map(|p| p.to_string_lossy().into_owned())
+        .collect())
+}
+
+// Open a local file, stdin or stream a remote one without decompressing it
+pub fn open_raw(path: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+    if path == STDIN {
+        Ok(Box::new(io::BufReader::new(io::stdin())))
+    } else if is_url(path) {
+        fetch_url(path)
+    } else {
+        let file = File::open(path).with_context(|| anyhow!("Could not open '{}'", path))?;
+        Ok(Box::new(io::BufReader::new(file)))
+    }
+}
+
+#[cfg(feature = "http")]
+fn fetch_url(url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+    let response = ureq::get(url)
+        .call()
+        .with_context(|| anyhow!("Could not fetch '{}'", url))?;
+
+    Ok(Box::new(io::BufReader::new(response.into_reader())))
+}
+
+#[cfg(not(feature = "http"))]
+fn fetch_url(url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+    Err(anyhow!(
+        "Could not fetch '{}': sabreur was built without http support, \
+        rebuild it with `--features http`",
+        url
+    ))
+}
+
+// Open a local or remote, possibly compressed, input and get its
+// compression format
+pub fn open_input(
+    path: &str,
+) -> anyhow::Result<(Box<dyn Read + Send>, niffler::send::compression::Format)> {
+    open_counted_input(path, None)
+}
+
+// Same as open_input, adding the bytes read from the file, before
+// decompression, to `counter`
+pub fn open_counted_input(
+    path: &str,
+    counter: Option<&ByteCounter>,
+) -> anyhow::Result<(Box<dyn Read + Send>, niffler::send::compression::Format)> {
+    let raw = open_raw(path)?;
+    let raw: Box<dyn Read + Send> = match counter {
+        Some(counter) => Box::new(CountingReader {
+            inner: raw,
+            counter: counter.clone(),
+        }),
+        None => raw,
+    };
+
+    Ok(niffler::send::get_reader(raw)?)
+}
+
+// Inputs opened ahead of their reading to sniff their compression, each
+// handed over to the first reader of its path instead of being opened again
+#[derive(Default)]
+pub struct OpenedInputs(Mutex<Vec<(String, Box<dyn Read + Send>)>>);
+
+impl OpenedInputs {
+    pub fn insert(&self, path: &str, reader: Box<dyn Read + Send>) {
+        self.0.lock().unwrap().push((path.to_string(), reader));
+    }
+
+    pub fn take(&self, path: &str) -> Option<Box<dyn Read + Send>> {
+        let mut opened = self.0.lock().unwrap();
+        let pos = opened.iter().position(|(p, _)| p == path)?;
+        Some(opened.remove(pos).1)
+    }
+}
+
+// Compression format of an input, from its extension without opening it
+// when `trust_ext` is set and the extension tells, sniffed otherwise.
+// Stdin and pipes cannot be read twice, they are reported as uncompressed
+// and are only sniffed when demultiplexed. When the input has to be
+// sniffed, it is opened once and kept in `opened` for its reading, with
+// its bytes added to `counter`.
+pub fn sniff_format(
+    path: &str,
+    trust_ext: bool,
+    counter: Option<&ByteCounter>,
+    opened: &OpenedInputs,
+) -> anyhow::Result<niffler::send::compression::Format> {
+    match format_from_ext(path) {
+        Some(format) if trust_ext => Ok(format),
+        _ if is_stream(path) => Ok(niffler::send::compression::Format::No),
+        _ => {
+            let (reader, format) = open_counted_input(path, counter)?;
+            opened.insert(path, reader);
+            Ok(format)
+        }
+    }
+}
+
+// Read a whole local or remote file, e.g. the barcode file
+pub fn read_to_string(path: &str) -> anyhow::Result<String> {
+    let mut content = String::new();
+    open_raw(path)?.read_to_string(&mut content)?;
+
+    Ok(content)
+}
+
+// A FASTA/FASTQ record that can be written out, whether still borrowed
+// from its parser or copied out of it
+pub trait FastxRecord {
+    fn id(&self) -> &[u8];
+    fn seq(&self) -> Cow<'_, [u8]>;
+    fn qual(&self) -> Option<&[u8]>;
+    fn format(&self) -> needletail::parser::Format;
+
+    // Record as found in the input, without its trailing line ending
+    fn raw(&self) -> Option<(&[u8], needletail::parser::LineEnding)> {
+        None
+    }
+}
+
+impl FastxRecord for needletail::parser::SequenceRecord<'_> {
+    fn id(&self) -> &[u8] {
+        needletail::parser::SequenceRecord::id(self)
+    }
+
+    fn seq(&self) -> Cow<'_, [u8]> {
+        needletail::parser::SequenceRecord::seq(self)
+    }
+
+    fn qual(&self) -> Option<&[u8]> {
+        needletail::parser::SequenceRecord::qual(self)
+    }
+
+    fn format(&self) -> needletail::parser::Format {
+        needletail::parser::SequenceRecord::format(self)
+    }
+
+    fn raw(&self) -> Option<(&[u8], needletail::parser::LineEnding)> {
+        Some((self.all(), self.line_ending()))
+    }
+}
+
+// Zstd compression settings beyond the niffler levels, which stop at 9
+// on the command line and lack long distance matching
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ZstdTuning {
+    pub level: i32,
+    pub long_distance: bool,
+}
+
+// Encoders of the output files used instead of the niffler ones: zstd
+// with its own tuning, gzip on several threads
+#[derive(Clone, Default)]
+pub struct Encoders {
+    pub zstd: Option<ZstdTuning>,
+    pub gzip: Option<crate::gzip::GzipPool>,
+}
+
+/// Record written to an output
+pub struct OutputRecord<'a> {
+    pub id: &'a [u8],
+    pub seq: &'a [u8],
+    pub qual: Option<&'a [u8]>,
+    pub format: needletail::parser::Format,
+    /// Text of the record in the input, copied as it is by text outputs
+    pub raw: Option<(&'a [u8], needletail::parser::LineEnding)>,
+}
+
+/// Writer of an output, whose end, e.g. a compression trailer, is written
+/// by `finish`. Unlike dropping the writer, `finish` reports the errors of
+/// these last writes; finishing again does nothing.
+pub trait Output: Write + Send {
+    fn finish(&mut self) -> io::Result<()>;
+
+    /// Write a record as fasta or fastq text. Outputs of another format,
+    /// e.g. uBAM, encode its fields instead.
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        let mut out = self;
+        if let Some((raw, line_ending)) = record.raw {
+            out.write_all(raw)?;
+            return out.write_all(match line_ending {
+                needletail::parser::LineEnding::Windows => b"\r\n",
+                needletail::parser::LineEnding::Unix => b"\n",
+            });
+        }
+        match record.format {
+            needletail::parser::Format::Fasta => needletail::parser::write_fasta(
+                record.id,
+                record.seq,
+                &mut out,
+                needletail::parser::LineEnding::Unix,
+            ),
+            needletail::parser::Format::Fastq => needletail::parser::write_fastq(
+                record.id,
+                record.seq,
+                record.qual,
+                &mut out,
+                needletail::parser::LineEnding::Unix,
+            ),
+        }
+        .map_err(io::Error::other)
+    }
+}
+
+impl<O: Output + ?Sized> Output for Box<O> {
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        (**self).write_record(record)
+    }
+}
+
+impl Output for Vec<u8> {
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output for File {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<W: Write + Send> Output for io::BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Output for crate::gzip::ParallelGzWriter<Box<dyn Write + Send>> {
+    fn finish(&mut self) -> io::Result<()> {
+        crate::gzip::ParallelGzWriter::finish(self)
+    }
+}
+
+impl Output for zstd::stream::write::Encoder<'static, Box<dyn Write + Send>> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.do_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+// Encoder ending its stream only when dropped, as those of niffler
+struct DropFinished(Option<Box<dyn Write + Send>>);
+
+impl Write for DropFinished {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Some(encoder) => encoder.write(buf),
+            None => Err(io::Error::other("write to a finished output")),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Some(encoder) => encoder.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Output for DropFinished {
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(mut encoder) = self.0.take() {
+            encoder.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// First error met by the destination of an output
+#[derive(Clone, Default)]
+struct SinkError(Arc<Mutex<Option<io::Error>>>);
+
+impl SinkError {
+    fn keep(&self, err: &io::Error) {
+        let mut slot = self.0.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(io::Error::new(err.kind(), err.to_string()));
+        }
+    }
+
+    fn take(&self) -> Option<io::Error> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+// Destination written by an encoder, keeping its errors for the output:
+// the encoders dropped at the end of a run ignore them. The destination
+// is finished when the sink is dropped after the output was finished,
+// and only dropped otherwise: an S3 upload must not be completed with the
+// reads of a failed run.
+struct Sink {
+    inner: Box<dyn Output>,
+    error: SinkError,
+    committed: Arc<AtomicBool>,
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner
+            .write(buf)
+            .inspect_err(|err| self.error.keep(err))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().inspect_err(|err| self.error.keep(err))
+    }
+}
+
+impl Drop for Sink {
+    fn drop(&mut self) {
+        if !self.committed.load(Ordering::Acquire) {
+            return;
+        }
+        if let Err(err) = self.inner.finish() {
+            self.error.keep(&err);
+        }
+    }
+}
+
+/// Output file written through an encoder. Its errors, including those of
+/// the last writes made by the encoder, name the file.
+pub struct OutputFile {
+    path: PathBuf,
+    encoder: Option<Box<dyn Output>>,
+    error: SinkError,
+    committed: Arc<AtomicBool>,
+}
+
+impl OutputFile {
+    /// Output to `dest` through the encoder that `encoder` builds on top of
+    /// the destination
+    pub fn new<F>(path: &Path, dest: Box<dyn Output>, encoder: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce(Box<dyn Write + Send>) -> anyhow::Result<Box<dyn Output>>,
+    {
+        let error = SinkError::default();
+        let committed = Arc::new(AtomicBool::new(false));
+        let sink = Sink {
+            inner: dest,
+            error: error.clone(),
+            committed: committed.clone(),
+        };
+
+        Ok(OutputFile {
+            path: path.to_path_buf(),
+            encoder: Some(encoder(Box::new(sink))?),
+            error,
+            committed,
+        })
+    }
+
+    fn context(&self, err: io::Error) -> io::Error {
+        io::Error::new(
+            err.kind(),
+            format!(
+                "Could not write output file '{}': {}",
+                self.path.display(),
+                err
+            ),
+        )
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.write(buf).map_err(|err| self.context(err)),
+            None => Err(self.context(io::Error::other("write after finish"))),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.write_all(buf).map_err(|err| self.context(err)),
+            None => Err(self.context(io::Error::other("write after finish"))),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder.flush().map_err(|err| self.context(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Output for OutputFile {
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        match &mut self.encoder {
+            Some(encoder) => encoder
+                .write_record(record)
+                .map_err(|err| self.context(err)),
+            None => Err(self.context(io::Error::other("write after finish"))),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.committed.store(true, Ordering::Release);
+        self.end()
+    }
+}
+
+impl OutputFile {
+    // End the encoder stream, then drop the sink, finishing the
+    // destination when the output was committed
+    fn end(&mut self) -> io::Result<()> {
+        let Some(mut encoder) = self.encoder.take() else {
+            return Ok(());
+        };
+        let finished = encoder.finish();
+        drop(encoder);
+        let kept = self.error.take();
+        finished.map_err(|err| self.context(err))?;
+        match kept {
+            Some(err) => Err(self.context(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+// An output dropped without being finished belongs to a failed run
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        let _ = self.end();
+    }
+}
+
+// Finish the writers of all outputs, before the output files are used
+pub fn finish_outputs<K>(outputs: HashMap<K, Vec<Box<dyn Output>>>) -> io::Result<()> {
+    for writers in outputs.into_values() {
+        for mut writer in writers {
+            writer.finish()?;
+        }
+    }
+
+    Ok(())
+}
+
+// Output to `dest` compressing with the given format. Zstd and gzip
+// outputs go through `encoders` when they have one.
+pub fn encode_output(
+    path: &Path,
+    dest: Box<dyn Output>,
+    compression: niffler::send::compression::Format,
+    level: niffler::Level,
+    encoders: &Encoders,
+) -> anyhow::Result<Box<dyn Output>> {
+    let output = OutputFile::new(path, dest, |sink| match (compression, encoders) {
+        (
+            niffler::send::compression::Format::Zstd,
+            Encoders {
+                zstd: Some(tuning), ..
+            },
+        ) => {
+            let mut encoder = zstd::stream::write::Encoder::new(sink, tuning.level)?;
+            encoder.long_distance_matching(tuning.long_distance)?;
+            Ok(Box::new(encoder))
+        }
+        (
+            niffler::send::compression::Format::Gzip,
+            Encoders {
+                gzip: Some(pool), ..
+            },
+        ) => Ok(Box::new(crate::gzip::ParallelGzWriter::new(
+            sink,
+            pool,
+            level.into(),
+        )?)),
+        _ => Ok(Box::new(DropFinished(Some(niffler::send::get_writer(
+            sink,
+            compression,
+            level,
+        )?)))),
+    })?;
+
+    Ok(Box::new(output))
+}
+
+// Open an output file in append mode behind a writer compressing with
+// the given format, meant to be kept for all the records of a file
+pub fn create_writer(
+    path: &Path,
+    compression: niffler::send::compression::Format,
+    level: niffler::Level,
+) -> anyhow::Result<Box<dyn Output>> {
+    create_writer_with_capacity(
+        path,
+        compression,
+        level,
+        &Encoders::default(),
+        &OutputBuffers::default(),
+        DEFAULT_BUFFER,
+    )
+}
+
+// Same as create_writer, buffering `capacity` bytes in `buffers` before
+// writing to the file. Zstd and gzip outputs go through `encoders` when
+// they have one.
+pub fn create_writer_with_capacity(
+    path: &Path,
+    compression: niffler::send::compression::Format,
+    level: niffler::Level,
+    encoders: &Encoders,
+    buffers: &OutputBuffers,
+    capacity: usize,
+) -> anyhow::Result<Box<dyn Output>> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| anyhow!("Could not open output file '{}'", path.display()))?;
+    let out = buffers.buffer(file, capacity);
+
+    Ok(buffers.flushed(encode_output(path, out, compression, level, encoders)?))
+}
+
+/// When the buffers of the output files are written to the files
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Once full
+    #[default]
+    Lazy,
+    /// After each record, through the compression encoders
+    Eager,
+    /// Once full, or the least recently written first once the buffers of
+    /// all the outputs hold more than a memory cap
+    Lru,
+}
+
+/// Buffers of the output files, written out as set by a flush policy
+#[derive(Clone, Default)]
+pub struct OutputBuffers {
+    policy: FlushPolicy,
+    pool: Arc<Mutex<BufferPool>>,
+}
+
+impl OutputBuffers {
+    /// Buffers following `policy`, those of the lru policy holding at most
+    /// `max_memory` bytes altogether
+    pub fn new(policy: FlushPolicy, max_memory: usize) -> Self {
+        OutputBuffers {
+            policy,
+            pool: Arc::new(Mutex::new(BufferPool {
+                max_memory,
+                ..Default::default()
+            })),
+        }
+    }
+
+    /// Buffer of `capacity` bytes before an output file
+    pub fn buffer(&self, file: File, capacity: usize) -> Box<dyn Output> {
+        match self.policy {
+            FlushPolicy::Lazy | FlushPolicy::Eager => {
+                Box::new(io::BufWriter::with_capacity(capacity, file))
+            }
+            FlushPolicy::Lru => {
+                let mut pool = self.pool.lock().unwrap();
+                pool.buffers.push(Some(PooledBuffer {
+                    file,
+                    data: Vec::new(),
+                    capacity,
+                    last_write: 0,
+                    error: None,
+                }));
+                Box::new(PoolWriter {
+                    pool: self.pool.clone(),
+                    slot: pool.buffers.len() - 1,
+                })
+            }
+        }
+    }
+
+    /// Output flushed as set by the policy, given the output on top of its
+    /// buffer
+    pub fn flushed(&self, output: Box<dyn Output>) -> Box<dyn Output> {
+        match self.policy {
+            FlushPolicy::Eager => Box::new(EagerOutput(output)),
+            FlushPolicy::Lazy | FlushPolicy::Lru => output,
+        }
+    }
+}
+
+// Output flushed after each record
+struct EagerOutput(Box<dyn Output>);
+
+impl Write for EagerOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Output for EagerOutput {
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.finish()
+    }
+
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        self.0.write_record(record)?;
+        self.0.flush()
+    }
+}
+
+// Buffers of the output files under the lru policy, with their total size
+#[derive(Default)]
+struct BufferPool {
+    max_memory: usize,
+    buffered: usize,
+    // Count of writes, dating the last write to each buffer
+    clock: u64,
+    // Buffers of the outputs, None once finished
+    buffers: Vec<Option<PooledBuffer>>,
+}
+
+struct PooledBuffer {
+    file: File,
+    data: Vec<u8>,
+    capacity: usize,
+    last_write: u64,
+    // Error met when the buffer was flushed for another output, reported
+    // by the next operation on its own output
+    error: Option<io::Error>,
+}
+
+impl BufferPool {
+    // Write the content of a buffer to its file, giving back its memory
+    fn flush_buffer(&mut self, slot: usize) -> io::Result<()> {
+        let Some(buffer) = self.buffers[slot].as_mut() else {
+            return Ok(());
+        };
+        let data = std::mem::take(&mut buffer.data);
+        self.buffered -= data.len();
+        buffer.file.write_all(&data)
+    }
+
+    // Flush the least recently written buffers until the pool holds at
+    // most its memory cap
+    fn make_room(&mut self) {
+        while self.buffered > self.max_memory {
+            let oldest = self
+                .buffers
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, buffer)| buffer.as_ref().map(|buffer| (slot, buffer)))
+                .filter(|(_, buffer)| !buffer.data.is_empty())
+                .min_by_key(|(_, buffer)| buffer.last_write)
+                .map(|(slot, _)| slot);
+            let Some(slot) = oldest else {
+                return;
+            };
+            if let Err(err) = self.flush_buffer(slot) {
+                let buffer = self.buffers[slot].as_mut().unwrap();
+                buffer.error.get_or_insert(err);
+            }
+        }
+    }
+
+    // Error left by a flush made for another output
+    fn take_error(&mut self, slot: usize) -> io::Result<()> {
+        match self.buffers[slot]
+            .as_mut()
+            .and_then(|buffer| buffer.error.take())
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+// Output file writing through its buffer in the pool
+struct PoolWriter {
+    pool: Arc<Mutex<BufferPool>>,
+    slot: usize,
+}
+
+impl Write for PoolWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut pool = self.pool.lock().unwrap();
+        pool.take_error(self.slot)?;
+        pool.clock += 1;
+        let clock = pool.clock;
+        let Some(buffer) = pool.buffers[self.slot].as_mut() else {
+            return Err(io::Error::other("write after finish"));
+        };
+        buffer.last_write = clock;
+        if buffer.data.len() + buf.len() > buffer.capacity {
+            pool.flush_buffer(self.slot)?;
+        }
+        let buffer = pool.buffers[self.slot].as_mut().unwrap();
+        if buf.len() >= buffer.capacity {
+            buffer.file.write_all(buf)?;
+        } else {
+            buffer.data.extend_from_slice(buf);
+            pool.buffered += buf.len();
+            pool.make_room();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut pool = self.pool.lock().unwrap();
+        pool.take_error(self.slot)?;
+        pool.flush_buffer(self.slot)?;
+        match pool.buffers[self.slot].as_mut() {
+            Some(buffer) => buffer.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Output for PoolWriter {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.pool.lock().unwrap().buffers[self.slot] = None;
+        Ok(())
+    }
+}
+
+impl Drop for PoolWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Buffer size of the output files, that of std::io::BufWriter
+pub const DEFAULT_BUFFER: usize = 8 * 1024;
+
+// Largest buffer of an output file, for a sample expected to get most reads
+pub const MAX_BUFFER: usize = 1 << 20;
+
+// Buffer memory shared among the output files according to their expected
+// read counts, and the default memory cap of the lru flush policy
+pub const BUFFER_POOL: usize = 16 << 20;
+
+// Read the expected number of reads of each barcode from a file with a
+// barcode and a count on each line, `XXX` standing for the unknown reads
+pub fn read_expected_counts(path: &str) -> anyhow::Result<HashMap<Vec<u8>, u64>> {
+    let content = read_to_string(path)?;
+    let mut counts = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (barcode, count) = line.trim_end().split_once('\t').ok_or_else(|| {
+            anyhow!(
+                "expected counts line {}: expected a barcode and a count separated by a tab",
+                i + 1
+            )
+        })?;
+        let count = count.trim().parse::<u64>().with_context(|| {
+            anyhow!(
+                "expected counts line {}: count '{}' of barcode {} is not a number",
+                i + 1,
+                count,
+                barcode
+            )
+        })?;
+        counts.insert(barcode.as_bytes().to_vec(), count);
+    }
+
+    Ok(counts)
+}
+
+// Read the allowed read count range of barcodes from a file with a
+// barcode, a minimum and a maximum on each line, `-` for no bound
+pub fn read_count_bounds(path: &str) -> anyhow::Result<HashMap<Vec<u8>, RangeInclusive<u64>>> {
+    let content = read_to_string(path)?;
+    let mut bounds = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split('\t').map(str::trim).collect::<Vec<_>>();
+        let (barcode, min, max) = match fields.as_slice() {
+            [barcode, min, max] => (*barcode, *min, *max),
+            _ => {
+                return Err(anyhow!(
+                    "count bounds line {}: expected a barcode, a minimum and a maximum \
+                    separated by tabs",
+                    i + 1
+                ))
+            }
+        };
+        let parse = |bound: &str, none: u64| match bound {
+            "-" => Ok(none),
+            _ => bound.parse::<u64>().with_context(|| {
+                anyhow!(
+                    "count bounds line {}: bound '{}' of barcode {} is not a number",
+                    i + 1,
+                    bound,
+                    barcode
+                )
+            }),
+        };
+        bounds.insert(
+            barcode.as_bytes().to_vec(),
+            parse(min, 0)?..=parse(max, u64::MAX)?,
+        );
+    }
+
+    Ok(bounds)
+}
+
+// Describe each barcode whose read count is out of its bounds, sorted by
+// barcode
+pub fn check_count_bounds(
+    bounds: &HashMap<Vec<u8>, RangeInclusive<u64>>,
+    counts: &HashMap<Vec<u8>, u32>,
+) -> Vec<String> {
+    let mut offenders = bounds
+        .iter()
+        .filter_map(|(barcode, range)| {
+            let count = u64::from(counts.get(barcode).copied().unwrap_or(0));
+            let name = String::from_utf8_lossy(barcode);
+            if count < *range.start() {
+                Some(format!("{}: {} reads < {}", name, count, range.start()))
+            } else if count > *range.end() {
+                Some(format!("{}: {} reads > {}", name, count, range.end()))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    offenders.sort();
+    offenders
+}
+
+// Buffer size of the output files of a barcode, its share of the buffer
+// pool from its expected count, between the default and the largest
+// buffer sizes. Barcodes without an expected count get the default size.
+pub fn buffer_capacity(expected: &HashMap<Vec<u8>, u64>, barcode: &[u8]) -> usize {
+    let total = expected.values().sum::<u64>();
+    match expected.get(barcode) {
+        Some(count) if total > 0 => {
+            let share = (BUFFER_POOL as f64 * *count as f64 / total as f64) as usize;
+            share.clamp(DEFAULT_BUFFER, MAX_BUFFER)
+        }
+        _ => DEFAULT_BUFFER,
+    }
+}
+
+// Number of bases ending the read with a phred+33 quality below min_qual
+pub fn low_quality_tail_len(qual: &[u8], min_qual: u8) -> usize {
+    qual.iter()
+        .rev()
+        .take_while(|q| q.saturating_sub(33) < min_qual)
+        .count()
+}
+
+// Mean phred+33 quality of a read, 0 for an empty one
+pub fn mean_quality(qual: &[u8]) -> f64 {
+    if qual.is_empty() {
+        return 0.0;
+    }
+    let sum: u64 = qual.iter().map(|q| u64::from(q.saturating_sub(33))).sum();
+    sum as f64 / qual.len() as f64
+}
+
+// Number of records read: those written under a barcode or as unknown,
+// plus the dropped ones. Percentages of reads are shares of it.
+pub fn total_records<'a>(stats: impl IntoIterator<Item = (&'a [u8], u32)>) -> u64 {
+    stats
+        .into_iter()
+        .filter(|(key, _)| {
+            !crate::demux::is_extra_count(key)
+                || *key == crate::demux::TOO_SHORT
+                || *key == crate::demux::LOW_QUALITY
+                || *key == crate::demux::LENGTH_ANOMALY
+        })
+        .map(|(_, n)| u64::from(n))
+        .sum()
+}
+
+// Share of `n` in `total` as a percentage, 0 for an empty total
+pub fn percentage(n: u32, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => f64::from(n) * 100.0 / total as f64,
+    }
+}
+
+// Summary of the reads, or pairs, read per second and, when the input size
+// is known, of the megabytes read per second
+pub fn throughput(records: u64, bytes: Option<u64>, elapsed: Duration) -> String {
+    // Walltime is logged to the millisecond
+    let seconds = elapsed.as_secs_f64().max(0.001);
+    let mut line = format!(
+        "Processed {} records: {:.0} records/s",
+        records,
+        records as f64 / seconds
+    );
+    if let Some(bytes) = bytes {
+        line.push_str(&format!(", {:.2} MB/s", bytes as f64 / 1e6 / seconds));
+    }
+
+    line
+}
+
+// Does the first record of an input carry qualities?
+pub fn has_qualities(path: &str) -> anyhow::Result<bool> {
+    let (reader, _compression) = open_input(path)?;
+    let mut reader = needletail::parse_fastx_reader(reader)?;
+    match reader.next() {
+        Some(record) => Ok(record?.format() == needletail::parser::Format::Fastq),
+        None => Ok(true),
+    }
+}
+
+// Length of the run of G ending the sequence
+pub fn polyg_tail_len(seq: &[u8]) -> usize {
+    seq.iter()
+        .rev()
+        .take_while(|base| base.eq_ignore_ascii_case(&b'G'))
+        .count()
+}
+
+// Write to provided data to a fasta file in append mode under `id`,
+// dropping the first `trim` bases (and qualities) of the record and, with
+// `polyg`, a trailing run of at least that many G. Records needing none of
+// these changes, nor a new id, are copied as they are in the input when
+// their text is the one written otherwise.
+pub fn write_seqs<R: FastxRecord + ?Sized>(
+    handle: &mut dyn Output,
+    record: &R,
+    id: &[u8],
+    trim: usize,
+    polyg: Option<usize>,
+    min_qual: Option<u8>,
+) -> anyhow::Result<()> {
+    let raw = match trim == 0 && polyg.is_none() && min_qual.is_none() && id == record.id() {
+        true => record
+            .raw()
+            .filter(|(raw, line_ending)| is_canonical(raw, *line_ending, record.format())),
+        false => None,
+    };
+    let seq = record.seq();
+    let Range { start: trim, end } = kept_range(record, trim, polyg, min_qual);
+
+    handle.write_record(&OutputRecord {
+        id,
+        seq: &seq[trim..end],
+        qual: record.qual().map(|qual| &qual[trim..end]),
+        format: record.format(),
+        raw,
+    })?;
+
+    Ok(())
+}
+
+// Is the text of a record the one written from its fields: sequence and
+// qualities on a single line, a bare + line and Unix line endings? Other
+// records, e.g. of wrapped fasta files, are written from their fields.
+fn is_canonical(
+    raw: &[u8],
+    line_ending: needletail::parser::LineEnding,
+    format: needletail::parser::Format,
+) -> bool {
+    if matches!(line_ending, needletail::parser::LineEnding::Windows) || raw.contains(&b'\r') {
+        return false;
+    }
+    let mut lines = raw.split(|c| *c == b'\n');
+    match format {
+        needletail::parser::Format::Fasta => lines.count() == 2,
+        needletail::parser::Format::Fastq => {
+            lines.nth(2) == Some(b"+") && lines.next().is_some() && lines.next().is_none()
+        }
+    }
+}
+
+// Bases of a record kept when written: after the `trim` first ones and
+// before a poly-G tail or low quality 3' bases
+pub fn kept_range<R: FastxRecord + ?Sized>(
+    record: &R,
+    trim: usize,
+    polyg: Option<usize>,
+    min_qual: Option<u8>,
+) -> Range<usize> {
+    let seq = record.seq();
+    let trim = trim.min(seq.len());
+    let mut end = match polyg {
+        Some(min_len) => {
+            let tail = polyg_tail_len(&seq[trim..]);
+            if tail >= min_len {
+                seq.len() - tail
+            } else {
+                seq.len()
+            }
+        }
+        None => seq.len(),
+    };
+    if let (Some(min_qual), Some(qual)) = (min_qual, record.qual()) {
+        end -= low_quality_tail_len(&qual[trim..end], min_qual);
+    }
+
+    trim..end
+}
+
+// Canonical read id shared by both mates: the read name without its
+// description (e.g. ` 1:N:0:3`) nor its `/1` or `/2` mate suffix
+pub fn normalize_mate_id(id: &[u8]) -> &[u8] {
+    let name = id
+        .split(|c| c.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+
+    match name {
+        [head @ .., b'/', b'1' | b'2'] => head,
+        _ => name,
+    }
+}
+
+// Bytes of records in flight between the reader and the writer of the
+// threaded pipeline, the reader waiting when over `max` until the writer
+// catches up
+pub struct MemoryBudget {
+    max: Option<usize>,
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+struct BudgetState {
+    used: usize,
+    peak: usize,
+    closed: bool,
+}
+
+impl MemoryBudget {
+    pub fn new(max: Option<usize>) -> Self {
+        MemoryBudget {
+            max,
+            state: Mutex::new(BudgetState {
+                used: 0,
+                peak: 0,
+                closed: false,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    // Reserve `bytes`, waiting for enough of them to be released. Reserving
+    // more than the whole budget waits for everything else to be released.
+    // Returns false once the budget is closed.
+    pub fn acquire(&self, bytes: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed
+            && state.used > 0
+            && self.max.is_some_and(|max| state.used + bytes > max)
+        {
+            state = self.released.wait(state).unwrap();
+        }
+        state.used += bytes;
+        state.peak = state.peak.max(state.used);
+        !state.closed
+    }
+
+    pub fn release(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.used = state.used.saturating_sub(bytes);
+        self.released.notify_all();
+    }
+
+    // Stop waiting for releases, e.g. when the writer failed
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.released.notify_all();
+    }
+
+    // Largest number of bytes reserved at once
+    pub fn peak(&self) -> usize {
+        self.state.lock().unwrap().peak
+    }
+}
+
+// Parse a size in bytes with an optional K, M or G (powers of 1024)
+// suffix, e.g. "512M"
+pub fn parse_size(size: &str) -> anyhow::Result<usize> {
+    let upper = size.trim().to_ascii_uppercase();
+    let upper = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, unit) = match upper.char_indices().last() {
+        Some((i, 'K')) => (&upper[..i], 1 << 10),
+        Some((i, 'M')) => (&upper[..i], 1 << 20),
+        Some((i, 'G')) => (&upper[..i], 1 << 30),
+        _ => (upper, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid size '{}', expected e.g. 1024, 512K, 64M or 2G",
+                size
+            )
+        })
+}
+
+// Parse an expected read length, N or N±TOL (also written N+-TOL), into
+// the range of accepted lengths
+pub fn parse_expected_length(length: &str) -> anyhow::Result<RangeInclusive<usize>> {
+    let invalid = || {
+        anyhow!(
+            "invalid read length '{}', expected e.g. 150 or 150±5",
+            length
+        )
+    };
+    let (len, tol) = match length.trim().split_once('±') {
+        Some(split) => split,
+        None => length
+            .trim()
+            .split_once("+-")
+            .unwrap_or((length.trim(), "0")),
+    };
+    let len = len.trim().parse::<usize>().map_err(|_| invalid())?;
+    let tol = tol.trim().parse::<usize>().map_err(|_| invalid())?;
+
+    Ok(len.saturating_sub(tol)..=len.saturating_add(tol))
+}
+
+// Number of bytes read so far, shared between the reading code and the
+// progress bar, which stays hidden until started
+#[derive(Clone)]
+pub struct ByteCounter(indicatif::ProgressBar);
+
+impl Default for ByteCounter {
+    fn default() -> Self {
+        ByteCounter(indicatif::ProgressBar::hidden())
+    }
+}
+
+impl ByteCounter {
+    pub fn get(&self) -> u64 {
+        self.0.position()
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    counter: ByteCounter,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counter.0.inc(n as u64);
+        Ok(n)
+    }
+}
+
+// Destination of a copy of everything read through its readers, shared
+// by the successive inputs
+#[derive(Clone)]
+pub struct TeeSink(Arc<Mutex<Box<dyn Output>>>);
+
+impl TeeSink {
+    pub fn new(sink: Box<dyn Output>) -> Self {
+        TeeSink(Arc::new(Mutex::new(sink)))
+    }
+
+    pub fn reader<R: Read>(&self, inner: R) -> TeeReader<R> {
+        TeeReader {
+            inner,
+            sink: self.clone(),
+        }
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// Writes go to the shared sink, so that clones of a TeeSink can stand
+// for several outputs of a single file
+impl Write for TeeSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+// Finishing any clone finishes the shared sink, the others then have
+// nothing left to do
+impl Output for TeeSink {
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().finish()
+    }
+
+    fn write_record(&mut self, record: &OutputRecord) -> io::Result<()> {
+        self.0.lock().unwrap().write_record(record)
+    }
+}
+
+pub struct TeeReader<R> {
+    inner: R,
+    sink: TeeSink,
+}
+
+impl<R: Read> Read for TeeReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sink.0.lock().unwrap().write_all(&buf[..n])?;
+        Ok(n)
+    }
+}
+
+// Progress bar on screen, for log lines to suspend it
+static PROGRESS_BAR: Mutex<Option<indicatif::ProgressBar>> = Mutex::new(None);
+
+// Progress bar on stderr of the bytes of `total` read, drawn until dropped
+pub struct ProgressBar(indicatif::ProgressBar);
+
+impl ProgressBar {
+    pub fn start(total: u64, counter: ByteCounter) -> Self {
+        let bar = counter.0;
+        bar.set_length(total);
+        bar.set_style(
+            indicatif::ProgressStyle::with_template(
+                "[{bar:40}] {percent:>3}% {binary_bytes}/{binary_total_bytes}",
+            )
+            .expect("progress bar template should be valid")
+            .progress_chars("## "),
+        );
+        bar.set_draw_target(indicatif::ProgressDrawTarget::stderr());
+        *PROGRESS_BAR.lock().unwrap() = Some(bar.clone());
+
+        ProgressBar(bar)
+    }
+}
+
+impl Drop for ProgressBar {
+    fn drop(&mut self) {
+        PROGRESS_BAR.lock().unwrap().take();
+        self.0.finish();
+    }
+}
+
+// Total size of local input files, None when one of them is stdin or
+// remote and its size is unknown
+pub fn inputs_size(paths: &[String]) -> Option<u64> {
+    paths
+        .iter()
+        .map(|path| {
+            if is_stream(path) || is_url(path) {
+                None
+            } else {
+                fs::metadata(path).ok().map(|m| m.len())
+            }
+        })
+        .sum()
+}
+
+// Periodically write the running per-barcode counts as NDJSON lines
+pub struct LiveStats {
+    every: u64,
+    processed: u64,
+    until_next: u64,
+    sink: Box<dyn Write>,
+}
+
+impl LiveStats {
+    pub fn new(every: u64, sink: Box<dyn Write>) -> Self {
+        LiveStats {
+            every,
+            processed: 0,
+            until_next: every,
+            sink,
+        }
+    }
+
+    // Account for one more processed record and emit a line every
+    // `every` records
+    pub fn tick(&mut self, counts: &HashMap<&[u8], u32>) -> io::Result<()> {
+        self.processed += 1;
+        self.until_next -= 1;
+        if self.until_next != 0 {
+            return Ok(());
+        }
+        self.until_next = self.every;
+
+        let mut sorted = counts.iter().collect::<Vec<_>>();
+        sorted.sort();
+        let fields = sorted
+            .iter()
+            .map(|(bc, count)| format!("\"{}\":{}", String::from_utf8_lossy(bc), count))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        writeln!(
+            self.sink,
+            "{{\"records\":{},\"counts\":{{{}}}}}",
+            self.processed, fields
+        )?;
+        self.sink.flush()
+    }
+}
+
+// Wall-clock time spent in each named phase of a run, time between two
+// checkpoints is added to the phase named at the second one
+pub struct PhaseTimings {
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl PhaseTimings {
+    pub fn new(start: Instant) -> Self {
+        PhaseTimings {
+            last: start,
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn checkpoint(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+}
+
+// Write a JSON summary of the run with the duration of each phase in
+// seconds
+pub fn write_run_info<W: Write>(mut out: W, timings: &PhaseTimings) -> io::Result<()> {
+    let phases = timings
+        .phases()
+        .iter()
+        .map(|(name, d)| format!("\"{}\":{}", name, d.as_secs_f64()))
+        .collect::<Vec<_>>()
+        .join(",");
+    let total = timings
+        .phases()
+        .iter()
+        .map(|(_, d)| *d)
+        .sum::<Duration>()
+        .as_secs_f64();
+
+    writeln!(
+        out,
+        "{{\"version\":\"{}\",\"phases\":{{{}}},\"total\":{}}}",
+        env!("CARGO_PKG_VERSION"),
+        phases,
+        total
+    )
+}
+
+// Quote a string as a JSON string
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+// Name, version and build features of sabreur as a JSON object, for the
+// provenance records of pipelines. The compression backends are those
+// niffler was built with, found by asking it for a writer of each format.
+pub fn version_json(version: &str) -> String {
+    let backends = [
+        (niffler::send::compression::Format::Gzip, "gz"),
+        (niffler::send::compression::Format::Bzip, "bz2"),
+        (niffler::send::compression::Format::Lzma, "xz"),
+        (niffler::send::compression::Format::Zstd, "zst"),
+    ];
+    let optional = [
+        (cfg!(feature = "http"), "http"),
+        (cfg!(feature = "syslog"), "syslog"),
+        (cfg!(feature = "ubam"), "ubam"),
+    ];
+    let features = backends
+        .iter()
+        .filter(|(format, _)| {
+            niffler::send::get_writer(Box::new(io::sink()), *format, niffler::Level::One).is_ok()
+        })
+        .map(|(_, name)| *name)
+        .chain(
+            optional
+                .iter()
+                .filter(|(enabled, _)| *enabled)
+                .map(|(_, name)| *name),
+        )
+        .map(json_string)
+        .collect::<Vec<_>>()
+        .join(",");
+
+    format!(
+        "{{\"name\":\"sabreur\",\"version\":{},\"features\":[{}]}}",
+        json_string(version),
+        features
+    )
+}
+
+// Version of the layout of the JSON report, to bump on breaking changes
+pub const REPORT_SCHEMA_VERSION: u32 = 1;
+
+// What a JSON report describes besides the read counts
+pub struct RunReport<'a> {
+    pub barcode_file: &'a str,
+    pub inputs: Vec<&'a str>,
+    pub outdir: &'a Path,
+    pub formats: &'a [niffler::send::compression::Format],
+    pub unknown_files: &'a [&'a str],
+}
+
+impl RunReport<'_> {
+    // JSON list of output files with their size in bytes, null for files
+    // which no longer exist (empty unknown files, pooled samples)
+    fn files_json(&self, names: &[&str]) -> String {
+        if names.iter().any(|name| is_discard(name)) {
+            return "[]".to_string();
+        }
+        let files = names
+            .iter()
+            .zip(self.formats)
+            .map(|(name, format)| {
+                let path = create_relpath_from(&mut self.outdir.to_path_buf(), name, *format);
+                let size = match fs::metadata(&path) {
+                    Ok(meta) => meta.len().to_string(),
+                    Err(_) => "null".to_string(),
+                };
+                format!(
+                    "{{\"path\":{},\"size\":{}}}",
+                    json_string(&path.to_string_lossy()),
+                    size
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("[{}]", files)
+    }
+
+    // Write the run metadata, per-sample counts and output files, and the
+    // unknown reads breakdown as a single JSON document
+    pub fn write_json<W: Write>(
+        &self,
+        mut out: W,
+        barcode_fields: &[Vec<&str>],
+        stats: &HashMap<Vec<u8>, u32>,
+        timings: &PhaseTimings,
+    ) -> io::Result<()> {
+        let count = |key: &[u8]| stats.get(key).copied().unwrap_or(0);
+        let total = total_records(stats.iter().map(|(key, n)| (key.as_slice(), *n)));
+        let percent = |n: u32| percentage(n, total);
+
+        let mut run_info = Vec::new();
+        write_run_info(&mut run_info, timings)?;
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|input| json_string(input))
+            .collect::<Vec<_>>()
+            .join(",");
+        let mode = if self.formats.len() == 2 {
+            "paired-end"
+        } else {
+            "single-end"
+        };
+
+        let samples = barcode_fields
+            .iter()
+            .map(|fields| {
+                let n = count(fields[0].as_bytes());
+                let files = self.files_json(&fields[1..]);
+                format!(
+                    "{{\"barcode\":{},\"reads\":{},\"percent\":{:.2},\"files\":{}}}",
+                    json_string(fields[0]),
+                    n,
+                    percent(n),
+                    files
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let unknown = count(b"XXX");
+        writeln!(
+            out,
+            "{{\"schema_version\":{},\
+            \"run\":{{\"mode\":\"{}\",\"barcode_file\":{},\"inputs\":[{}],\"output_dir\":{},\"info\":{}}},\
+            \"samples\":[{}],\
+            \"unknown\":{{\"reads\":{},\"percent\":{:.2},\"ambiguous\":{},\"files\":{}}},\
+            \"total_reads\":{}}}",
+            REPORT_SCHEMA_VERSION,
+            mode,
+            json_string(self.barcode_file),
+            inputs,
+            json_string(&self.outdir.to_string_lossy()),
+            String::from_utf8_lossy(&run_info).trim_end(),
+            samples,
+            unknown,
+            percent(unknown),
+            count(crate::demux::AMBIGUOUS),
+            self.files_json(self.unknown_files),
+            total
+        )
+    }
+
+    // Absolute paths of the output files which still exist, None for the
+    // others (discarded samples, empty unknown files, pooled samples)
+    fn existing_paths(&self, names: &[&str]) -> Vec<Option<PathBuf>> {
+        names
+            .iter()
+            .zip(self.formats)
+            .map(|(name, format)| {
+                if is_discard(name) {
+                    return None;
+                }
+                let path = create_relpath_from(&mut self.outdir.to_path_buf(), name, *format);
+                fs::canonicalize(path).ok()
+            })
+            .collect()
+    }
+
+    // Write the barcode, forward and reverse output paths of each sample
+    // as a TSV table, with the unknown reads (`XXX`) on the last row.
+    // Paths are absolute, samples left with no output file are skipped.
+    pub fn write_manifest<W: Write>(
+        &self,
+        mut out: W,
+        barcode_fields: &[Vec<&str>],
+    ) -> io::Result<()> {
+        writeln!(out, "barcode\tforward_path\treverse_path")?;
+        let rows = barcode_fields
+            .iter()
+            .map(|fields| (fields[0], &fields[1..]))
+            .chain(std::iter::once(("XXX", self.unknown_files)));
+        for (barcode, names) in rows {
+            let paths = self.existing_paths(names);
+            if paths.iter().all(Option::is_none) {
+                continue;
+            }
+            let field = |i: usize| {
+                paths
+                    .get(i)
+                    .and_then(|path| path.as_ref())
+                    .map_or_else(String::new, |path| path.to_string_lossy().into_owned())
+            };
+            writeln!(out, "{}\t{}\t{}", barcode, field(0), field(1))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Tests --------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "syslog"))]
+    #[test]
+    fn test_syslog_config_unsupported() {
+        assert!(syslog_config().is_err());
+    }
+
+    #[test]
+    fn test_create_relpath_from() {
+        assert_eq!(
+            create_relpath_from(
+                &mut PathBuf::from("path"),
+                "file",
+                niffler::send::compression::Format::Gzip
+            ),
+            PathBuf::from("path/file.gz")
+        );
+    }
+
+    #[test]
+    fn test_split_paired_outputs() {
+        let rows = split_by_delimiter(
+            "AAAA\tsampleA_R1.fq,sampleA_R2.fq\nCCCC\tc_R1.fq\tc_R2.fq",
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            split_paired_outputs(rows).unwrap(),
+            vec![
+                (1, vec!["AAAA", "sampleA_R1.fq", "sampleA_R2.fq"]),
+                (2, vec!["CCCC", "c_R1.fq", "c_R2.fq"])
+            ]
+        );
+
+        let rows = split_by_delimiter("AAAA\tsampleA_R1.fq", None).unwrap();
+        assert!(split_paired_outputs(rows).is_err());
+    }
+
+    #[test]
+    fn test_write_run_info() {
+        let mut timings = PhaseTimings::new(Instant::now());
+        for phase in [
+            "output_setup",
+            "panel_parsing",
+            "output_setup",
+            "demultiplexing",
+            "finalization",
+        ] {
+            timings.checkpoint(phase);
+        }
+
+        let mut out = Vec::new();
+        write_run_info(&mut out, &timings).unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let phases = info["phases"].as_object().unwrap();
+        assert_eq!(phases.len(), 4);
+        for phase in [
+            "panel_parsing",
+            "output_setup",
+            "demultiplexing",
+            "finalization",
+        ] {
+            assert!(phases[phase].as_f64().unwrap() >= 0.0);
+        }
+        assert!(info["total"].as_f64().unwrap() >= 0.0);
+    }
+
+    #[test]
+    fn test_remove_empty_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let empty = dir.path().join("unknown_R1.fa");
+        let full = dir.path().join("unknown_R2.fa");
+        File::create(&empty).unwrap();
+        fs::write(&full, ">r1\nACGT\n").unwrap();
+        let outputs = [(empty.as_path(), true), (full.as_path(), false)];
+
+        remove_empty_outputs(&outputs, true).unwrap();
+        assert!(empty.exists());
+        assert!(full.exists());
+
+        remove_empty_outputs(&outputs, false).unwrap();
+        assert!(!empty.exists());
+        assert!(full.exists());
+    }
+
+    #[test]
+    fn test_polyg_tail_len() {
+        assert_eq!(polyg_tail_len(b"ACGTGGGG"), 4);
+        assert_eq!(polyg_tail_len(b"ACGTggGG"), 4);
+        assert_eq!(polyg_tail_len(b"ACGTGGGA"), 0);
+        assert_eq!(polyg_tail_len(b""), 0);
+    }
+
+    #[test]
+    fn test_low_quality_tail_len() {
+        assert_eq!(low_quality_tail_len(b"IIII####", 20), 4);
+        assert_eq!(low_quality_tail_len(b"II#I#", 3), 1);
+        assert_eq!(low_quality_tail_len(b"II#I#", 2), 0);
+        assert_eq!(low_quality_tail_len(b"IIII", 20), 0);
+        assert_eq!(low_quality_tail_len(b"####", 20), 4);
+    }
+
+    #[test]
+    fn test_open_counted_input() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, ">r1\nACGT\n>r2\nTTTT\n").unwrap();
+
+        let counter = ByteCounter::default();
+        let path = file.path().to_str().unwrap();
+        let (mut reader, _) = open_counted_input(path, Some(&counter)).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(counter.get(), 18);
+    }
+
+    #[test]
+    fn test_inputs_size() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "ACGT").unwrap();
+        let path = file.path().to_str().unwrap().to_string();
+
+        assert_eq!(inputs_size(&[path.clone(), path.clone()]), Some(8));
+        assert_eq!(inputs_size(&[path, STDIN.to_string()]), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("512K").unwrap(), 512 << 10);
+        assert_eq!(parse_size("64m").unwrap(), 64 << 20);
+        assert_eq!(parse_size("2GB").unwrap(), 2 << 30);
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("12T").is_err());
+    }
+
+    #[test]
+    fn test_buffer_capacity() {
+        let expected: HashMap<Vec<u8>, u64> = vec![
+            (b"AAAA".to_vec(), 9699),
+            (b"CCCC".to_vec(), 300),
+            (b"GGGG".to_vec(), 1),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(buffer_capacity(&expected, b"AAAA"), MAX_BUFFER);
+        assert_eq!(
+            buffer_capacity(&expected, b"CCCC"),
+            BUFFER_POOL * 300 / 10000
+        );
+        assert_eq!(buffer_capacity(&expected, b"GGGG"), DEFAULT_BUFFER);
+        assert_eq!(buffer_capacity(&expected, b"TTTT"), DEFAULT_BUFFER);
+        assert_eq!(buffer_capacity(&HashMap::new(), b"AAAA"), DEFAULT_BUFFER);
+    }
+
+    #[test]
+    fn test_read_expected_counts() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "AAAA\t900\r\nXXX\t12\n\n").unwrap();
+        let counts = read_expected_counts(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(counts.get(&b"AAAA"[..]), Some(&900));
+        assert_eq!(counts.get(&b"XXX"[..]), Some(&12));
+
+        writeln!(file, "CCCC\tmany").unwrap();
+        assert!(read_expected_counts(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_count_bounds() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(file, "AAAA\t10\t-\nCCCC\t-\t5\r\nGGGG\t1\t100\n").unwrap();
+        let bounds = read_count_bounds(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(bounds[&b"AAAA"[..]], 10..=u64::MAX);
+        assert_eq!(bounds[&b"CCCC"[..]], 0..=5);
+
+        let counts: HashMap<Vec<u8>, u32> = vec![(b"AAAA".to_vec(), 3), (b"CCCC".to_vec(), 8)]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            check_count_bounds(&bounds, &counts),
+            [
+                "AAAA: 3 reads < 10",
+                "CCCC: 8 reads > 5",
+                "GGGG: 0 reads < 1"
+            ]
+        );
+
+        writeln!(file, "TTTT\t1").unwrap();
+        assert!(read_count_bounds(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_expected_length() {
+        assert_eq!(parse_expected_length("150").unwrap(), 150..=150);
+        assert_eq!(parse_expected_length("150±5").unwrap(), 145..=155);
+        assert_eq!(parse_expected_length("150+-5").unwrap(), 145..=155);
+        assert_eq!(parse_expected_length("3±5").unwrap(), 0..=8);
+        assert!(parse_expected_length("150±").is_err());
+        assert!(parse_expected_length("long").is_err());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(Some(100));
+        assert!(budget.acquire(60));
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| budget.acquire(60));
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiting.is_finished());
+            budget.release(60);
+            assert!(waiting.join().unwrap());
+        });
+        assert_eq!(budget.peak(), 60);
+
+        // more than the whole budget goes through alone
+        budget.release(60);
+        assert!(budget.acquire(500));
+        assert_eq!(budget.peak(), 500);
+
+        // closing wakes up a waiting reservation
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| budget.acquire(10));
+            std::thread::sleep(Duration::from_millis(50));
+            budget.close();
+            assert!(!waiting.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_longest_exact_run() {
+        assert_eq!(longest_exact_run(b"ACGTACGT", b"ACGTACGT"), 8);
+        assert_eq!(longest_exact_run(b"ACGTACGT", b"TCGTACGA"), 6);
+        assert_eq!(longest_exact_run(b"ACGTACGT", b"ATGAAAGC"), 1);
+        assert_eq!(longest_exact_run(b"ACNT", b"acgt"), 4);
+    }
+
+    #[test]
+    fn test_longest_common_run() {
+        // A deletion shifts the end of the barcode in the read
+        assert_eq!(longest_common_run(b"AACCGGTT", b"AACGGTTA"), 5);
+        assert_eq!(longest_exact_run(b"AACCGGTT", b"AACGGTTA"), 3);
+        assert_eq!(longest_common_run(b"AAAA", b"CCCC"), 0);
+    }
+
+    #[test]
+    fn test_percentage() {
+        assert_eq!(percentage(1, 4), 25.0);
+        assert_eq!(percentage(3, 3), 100.0);
+        assert_eq!(percentage(0, 0), 0.0);
+    }
+
+    #[test]
+    fn test_mean_quality() {
+        assert_eq!(mean_quality(b"IIII"), 40.0);
+        assert_eq!(mean_quality(b"I#"), 21.0);
+        assert_eq!(mean_quality(b"!!!5"), 5.0);
+        assert_eq!(mean_quality(b""), 0.0);
+    }
+
+    #[test]
+    fn test_has_qualities() {
+        let mut fastq = tempfile::NamedTempFile::new().unwrap();
+        writeln!(fastq, "@r1\nACGT\n+\nIIII").unwrap();
+        let mut fasta = tempfile::NamedTempFile::new().unwrap();
+        writeln!(fasta, ">r1\nACGT").unwrap();
+
+        assert!(has_qualities(fastq.path().to_str().unwrap()).unwrap());
+        assert!(!has_qualities(fasta.path().to_str().unwrap()).unwrap());
+    }
+
+    #[test]
+    fn test_write_seqs_trim_polyg() {
+        let data = b"@r1\nACGTACGTGGGGGGGGGGGG\n+\nIIIIIIIIHHHHHHHHHHHH\n";
+        let mut reader = needletail::parse_fastx_reader(&data[..]).unwrap();
+        let record = reader.next().unwrap().unwrap();
+
+        let mut out = Vec::new();
+        write_seqs(&mut out, &record, record.id(), 2, Some(10), None).unwrap();
+        assert_eq!(out, b"@r1\nGTACGT\n+\nIIIIII\n");
+
+        // the tail is shorter than the minimum length
+        let mut out = Vec::new();
+        write_seqs(&mut out, &record, record.id(), 0, Some(13), None).unwrap();
+        assert_eq!(out, &data[..]);
+    }
+
+    #[test]
+    fn test_write_seqs_canonical() {
+        let write = |data: &[u8]| {
+            let mut reader = needletail::parse_fastx_reader(data).unwrap();
+            let mut out = Vec::new();
+            while let Some(record) = reader.next() {
+                let record = record.unwrap();
+                write_seqs(&mut out, &record, record.id(), 0, None, None).unwrap();
+            }
+            out
+        };
+
+        assert_eq!(write(b">r1 desc\nACGT\n"), b">r1 desc\nACGT\n");
+        assert_eq!(write(b"@r1\nACGT\n+\nIIII\n"), b"@r1\nACGT\n+\nIIII\n");
+        // wrapped sequences, CRLF line endings and repeated ids are not
+        // copied but written as usual
+        assert_eq!(write(b">r1\nAC\nGT\n>r2\nA\n"), b">r1\nACGT\n>r2\nA\n");
+        assert_eq!(write(b">r1\r\nACGT\r\n"), b">r1\nACGT\n");
+        assert_eq!(write(b"@r1\r\nAC\r\n+\r\nII\r\n"), b"@r1\nAC\n+\nII\n");
+        assert_eq!(write(b"@r1\nAC\n+r1\nII\n"), b"@r1\nAC\n+\nII\n");
+    }
+
+    #[test]
+    fn test_create_writer_zstd_tuning() {
+        let data = b">r1\nACGT\n>r2\nTTTT\n";
+        let out = tempfile::NamedTempFile::new().unwrap();
+        let tuning = ZstdTuning {
+            level: 22,
+            long_distance: true,
+        };
+
+        let mut writer = create_writer_with_capacity(
+            out.path(),
+            niffler::send::compression::Format::Zstd,
+            niffler::Level::One,
+            &Encoders {
+                zstd: Some(tuning),
+                gzip: None,
+            },
+            &OutputBuffers::default(),
+            DEFAULT_BUFFER,
+        )
+        .unwrap();
+        writer.write_all(data).unwrap();
+        drop(writer);
+
+        let compressed = fs::read(out.path()).unwrap();
+        let (mut decoder, format) = niffler::send::get_reader(Box::new(&compressed[..])).unwrap();
+        assert_eq!(format, niffler::send::compression::Format::Zstd);
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content).unwrap();
+        assert_eq!(content, &data[..]);
+    }
+
+    // Destination buffering the writes, then failing to store them as a
+    // full disk
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::other("no space left on device"))
+        }
+    }
+
+    impl Output for FullDisk {
+        fn finish(&mut self) -> io::Result<()> {
+            self.flush()
+        }
+    }
+
+    #[test]
+    fn test_output_finish_error() {
+        let pool = crate::gzip::GzipPool::new(2);
+        let encoders = [
+            Encoders::default(),
+            Encoders {
+                zstd: Some(ZstdTuning {
+                    level: 3,
+                    long_distance: false,
+                }),
+                gzip: None,
+            },
+            Encoders {
+                zstd: None,
+                gzip: Some(pool),
+            },
+        ];
+        for (format, encoders) in [
+            (niffler::send::compression::Format::Gzip, &encoders[0]),
+            (niffler::send::compression::Format::Zstd, &encoders[1]),
+            (niffler::send::compression::Format::Gzip, &encoders[2]),
+        ] {
+            let mut out = encode_output(
+                Path::new("sample1.fq.gz"),
+                Box::new(FullDisk),
+                format,
+                niffler::Level::One,
+                encoders,
+            )
+            .unwrap();
+            out.write_all(b">r1\nACGT\n").unwrap();
+
+            let err = out.finish().unwrap_err();
+            assert!(err.to_string().contains("'sample1.fq.gz'"));
+            assert!(err.to_string().contains("no space left on device"));
+            assert!(out.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_output_buffers_lru() {
+        let dir = tempfile::tempdir().unwrap();
+        let buffers = OutputBuffers::new(FlushPolicy::Lru, 100);
+        let paths = (0..3)
+            .map(|i| dir.path().join(format!("sample{}.fa", i)))
+            .collect::<Vec<_>>();
+        let mut outputs = paths
+            .iter()
+            .map(|path| {
+                create_writer_with_capacity(
+                    path,
+                    niffler::send::compression::Format::No,
+                    niffler::Level::One,
+                    &Encoders::default(),
+                    &buffers,
+                    DEFAULT_BUFFER,
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let mut expected = vec![Vec::new(); 3];
+        for i in 0..60 {
+            let record = format!(">r{}\nACGTACGTAC\n", i);
+            outputs[i % 3].write_all(record.as_bytes()).unwrap();
+            expected[i % 3].extend_from_slice(record.as_bytes());
+            assert!(buffers.pool.lock().unwrap().buffered <= 100);
+        }
+        // the least recently written buffers went to their files first
+        assert!(fs::metadata(&paths[0]).unwrap().len() > 0);
+        for output in outputs.iter_mut() {
+            output.finish().unwrap();
+        }
+
+        assert_eq!(buffers.pool.lock().unwrap().buffered, 0);
+        for (path, expected) in paths.iter().zip(expected) {
+            assert_eq!(fs::read(path).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_output_buffers_eager() {
+        let out = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = create_writer_with_capacity(
+            out.path(),
+            niffler::send::compression::Format::Gzip,
+            niffler::Level::One,
+            &Encoders::default(),
+            &OutputBuffers::new(FlushPolicy::Eager, 0),
+            DEFAULT_BUFFER,
+        )
+        .unwrap();
+        let data = b">r1\nACGT\n";
+        let mut reader = needletail::parse_fastx_reader(&data[..]).unwrap();
+        let record = reader.next().unwrap().unwrap();
+        write_seqs(&mut writer, &record, record.id(), 0, None, None).unwrap();
+
+        // the record reached the file before the end of the gzip stream
+        let (mut decoder, _) =
+            niffler::send::get_reader(Box::new(fs::File::open(out.path()).unwrap())).unwrap();
+        let mut content = vec![0; data.len()];
+        decoder.read_exact(&mut content).unwrap();
+        assert_eq!(content, &data[..]);
+    }
+
+    #[test]
+    fn test_output_finish_uncompressed() {
+        let out = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = create_writer(
+            out.path(),
+            niffler::send::compression::Format::No,
+            niffler::Level::One,
+        )
+        .unwrap();
+        writer.write_all(b">r1\nACGT\n").unwrap();
+        writer.finish().unwrap();
+
+        assert_eq!(fs::read(out.path()).unwrap(), b">r1\nACGT\n");
+        assert!(writer.write_all(b">r2\nACGT\n").is_err());
+    }
+
+    #[test]
+    fn test_create_writer_single_stream() {
+        let data = b">r1\nACGT\n>r2\nTTTT\n";
+        let mut reader = needletail::parse_fastx_reader(&data[..]).unwrap();
+        let out = tempfile::NamedTempFile::new().unwrap();
+
+        let mut writer = create_writer(
+            out.path(),
+            niffler::send::compression::Format::Gzip,
+            niffler::Level::One,
+        )
+        .unwrap();
+        while let Some(record) = reader.next() {
+            let record = record.unwrap();
+            write_seqs(&mut writer, &record, record.id(), 0, None, None).unwrap();
+        }
+        drop(writer);
+
+        // a single gzip member holds both records
+        let compressed = fs::read(out.path()).unwrap();
+        assert_eq!(
+            compressed
+                .windows(3)
+                .filter(|w| *w == [0x1f, 0x8b, 0x08])
+                .count(),
+            1
+        );
+        let (mut decoder, _) = niffler::send::get_reader(Box::new(&compressed[..])).unwrap();
+        let mut content = Vec::new();
+        decoder.read_to_end(&mut content).unwrap();
+        assert_eq!(content, &data[..]);
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["b.fq", "a.fa.gz", "notes.txt"] {
+            File::create(dir.path().join(name)).unwrap();
+        }
+        let path = |name: &str| dir.path().join(name).to_string_lossy().into_owned();
 
-    let stdout_config = fern::Dispatch::new()
-        .format(move |out, message, record| {
-            out.finish(format_args!(
-                "[{}][{}] {}",
-                chrono::Local::now().format("%H:%M:%S"),
-                colors.color(record.level()),
-                message
-            ))
-        })
-        .chain(io::stdout());
+        let files = expand_inputs(dir.path().to_str().unwrap()).unwrap();
+        assert_eq!(files, vec![path("a.fa.gz"), path("b.fq")]);
 
-    base_config
-        .chain(file_config)
-        .chain(stdout_config)
-        .apply()?;
+        let files = expand_inputs(&path("*.fq")).unwrap();
+        assert_eq!(files, vec![path("b.fq")]);
 
-    Ok(())
-}
+        assert!(expand_inputs(&path("*.fastq")).is_err());
+        assert_eq!(
+            expand_inputs("tests/test.fq").unwrap(),
+            vec!["tests/test.fq"]
+        );
+    }
 
-pub fn create_relpath_from(
-    basedir: &mut PathBuf,
-    filename: &str,
-    extension: niffler::send::compression::Format,
-) -> PathBuf {
-    let ext = to_compression_ext(extension);
-    let mut mstr = String::with_capacity(filename.len() + ext.len());
-    mstr.push_str(filename);
-    mstr.push_str(&ext);
-    basedir.push(mstr);
+    #[test]
+    fn test_is_mate_pair() {
+        assert!(is_mate_pair(
+            "S1_L001_R1_001.fastq.gz",
+            "S1_L001_R2_001.fastq.gz"
+        ));
+        assert!(is_mate_pair("fwd/reads_1.fa", "rev/reads_2.fa.bz2"));
+        assert!(is_mate_pair("fwd/reads.fa", "rev/reads.fa"));
+        assert!(!is_mate_pair("S1_L001_R1.fq", "S1_L002_R2.fq"));
+        assert!(!is_mate_pair("S1_L001_R1.fq", "S1_L001_R1.fq.gz.fq"));
+        assert!(!is_mate_pair("S1_R1.fq", "S2_R2.fq"));
+        assert!(!is_mate_pair("S1_R2.fq", "S1_R1.fq"));
+    }
 
-    basedir.to_path_buf()
-}
+    #[test]
+    fn test_sniff_formats() {
+        let opened = OpenedInputs::default();
+        let paths = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            sniff_formats(
+                &paths(&["tests/reads_1.fa.gz", "tests/reads_2.fa.gz"]),
+                false,
+                None,
+                &opened
+            )
+            .unwrap(),
+            niffler::send::compression::Format::Gzip
+        );
+        let err = sniff_formats(
+            &paths(&["tests/reads_1.fa.gz", "tests/reads_2.fa"]),
+            false,
+            None,
+            &opened,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'tests/reads_1.fa.gz' is gz compressed but 'tests/reads_2.fa' is uncompressed, \
+            their outputs cannot share one compression"
+        );
+    }
 
-// to_niffler_format function
-pub fn to_niffler_format(format: &str) -> anyhow::Result<niffler::send::compression::Format> {
-    match format {
-        "gz" => Ok(niffler::send::compression::Format::Gzip),
-        "bz2" => Ok(niffler::send::compression::Format::Bzip),
-        "xz" => Ok(niffler::send::compression::Format::Lzma),
-        "zst" => Ok(niffler::send::compression::Format::Zstd),
-        _ => Ok(niffler::send::compression::Format::No),
+    #[test]
+    fn test_validate_barcode_fields() {
+        let fields = split_by_delimiter("ACGT\ta.fq\nNNGG\tDISCARD\nTTTT\tDISCARD", None).unwrap();
+        assert!(validate_barcode_fields(&fields, false, Some('\t')).is_ok());
+
+        let fields = split_by_delimiter("ACGT\ta_R1.fq\ta_R2.fq", None).unwrap();
+        assert!(validate_barcode_fields(&fields, true, Some('\t')).is_ok());
+
+        let err = |data: &str, is_pe: bool| {
+            let fields = split_by_delimiter(data, None).unwrap();
+            validate_barcode_fields(&fields, is_pe, detect_delimiter(data, None).ok())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            err("ACGT\ta.fq\nCCCC\tc.fq", true),
+            "line 1: expected 3 tab-separated columns in paired-end mode, found 2"
+        );
+        // the delimiter named is the one of the file
+        assert_eq!(
+            err("ACGT,a.fq\nCCCC,c.fq", true),
+            "line 1: expected 3 comma-separated columns in paired-end mode, found 2"
+        );
+        let fields = vec![(1, vec!["ACGT", "a.fq"])];
+        assert_eq!(
+            validate_barcode_fields(&fields, true, None)
+                .unwrap_err()
+                .to_string(),
+            "line 1: expected 3 columns in paired-end mode, found 2"
+        );
+        assert_eq!(
+            err("ACGT\ta.fq\nCCXC\tc.fq", false),
+            "line 2: barcode 'CCXC' should only contain A, C, G, T or N"
+        );
+        assert_eq!(
+            err("ACGT\ta.fq\nCCCC\tc.fq\nACGT\tg.fq", false),
+            "line 3: barcode ACGT is already given line 1"
+        );
+        assert_eq!(
+            err("ACGT\ta.fq\nCCCC\ta.fq", false),
+            "line 2: output file 'a.fq' is already used line 1"
+        );
+        // lines are those of the file, comments and blank lines included
+        assert_eq!(
+            err("#barcode\toutput\nACGT\ta.fq\n\nCCXC\tc.fq", false),
+            "line 4: barcode 'CCXC' should only contain A, C, G, T or N"
+        );
     }
-}
 
-// Convert niffler compression format to a file extension
-pub fn to_compression_ext(compression: niffler::send::compression::Format) -> String {
-    match compression {
-        niffler::send::compression::Format::Gzip => ".gz".to_string(),
-        niffler::send::compression::Format::Bzip => ".bz2".to_string(),
-        niffler::send::compression::Format::Lzma => ".xz".to_string(),
-        niffler::send::compression::Format::Zstd => ".zst".to_string(),
-        niffler::send::compression::Format::No => "".to_string(),
+    #[test]
+    fn test_take_mismatch_column() {
+        let mut fields = split_by_delimiter("AAAA\ta.fq\t2\nCCCC\tc.fq", None).unwrap();
+        let thresholds = take_mismatch_column(&mut fields, 1).unwrap();
+        assert_eq!(
+            fields,
+            vec![(1, vec!["AAAA", "a.fq"]), (2, vec!["CCCC", "c.fq"])]
+        );
+        assert_eq!(thresholds.len(), 1);
+        assert_eq!(thresholds.get(&b"AAAA"[..]), Some(&2));
+
+        let mut fields = split_by_delimiter("AAAA\ta_R1.fq\ta_R2.fq\t1", None).unwrap();
+        let thresholds = take_mismatch_column(&mut fields, 2).unwrap();
+        assert_eq!(fields, vec![(1, vec!["AAAA", "a_R1.fq", "a_R2.fq"])]);
+        assert_eq!(thresholds.get(&b"AAAA"[..]), Some(&1));
+
+        let mut fields = split_by_delimiter("AAAA\ta.fq\tb.fq", None).unwrap();
+        assert!(take_mismatch_column(&mut fields, 1).is_err());
+
+        let mut fields = split_by_delimiter("# panel\nAAAA\ta.fq\tx", None).unwrap();
+        let err = take_mismatch_column(&mut fields, 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "barcode file line 2: mismatch 'x' of barcode AAAA is not a number"
+        );
     }
-}
 
-// Convert an integer to a niffler::Level
-pub fn to_niffler_level(int_level: u8) -> niffler::Level {
-    match int_level {
-        1 => niffler::Level::One,
-        2 => niffler::Level::Two,
-        3 => niffler::Level::Three,
-        4 => niffler::Level::Four,
-        5 => niffler::Level::Five,
-        6 => niffler::Level::Six,
-        7 => niffler::Level::Seven,
-        8 => niffler::Level::Eight,
-        9 => niffler::Level::Nine,
-        _ => niffler::Level::One,
+    #[test]
+    fn test_take_quality_column() {
+        let mut fields =
+            split_by_delimiter("AAAA\ta.fq\t-\t20\nCCCC\tc.fq\t1\nGGGG\tg.fq", None).unwrap();
+        let qualities = take_quality_column(&mut fields, 1).unwrap();
+        let mismatches = take_mismatch_column(&mut fields, 1).unwrap();
+        assert_eq!(
+            fields,
+            vec![
+                (1, vec!["AAAA", "a.fq"]),
+                (2, vec!["CCCC", "c.fq"]),
+                (3, vec!["GGGG", "g.fq"])
+            ]
+        );
+        assert_eq!(qualities.len(), 1);
+        assert_eq!(qualities.get(&b"AAAA"[..]), Some(&20));
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!(mismatches.get(&b"CCCC"[..]), Some(&1));
+
+        let mut fields = split_by_delimiter("AAAA\ta.fq\t1\tQ20", None).unwrap();
+        assert!(take_quality_column(&mut fields, 1).is_err());
+        let mut fields = split_by_delimiter("AAAA\ta.fq\t1\t20\t3", None).unwrap();
+        assert!(take_quality_column(&mut fields, 1).is_err());
     }
-}
 
-// Split a &str at each \t
-pub fn split_by_tab(string: &str) -> anyhow::Result<Vec<Vec<&str>>> {
-    if string.contains('\t') {
-        Ok(string
-            .lines()
-            .map(|line| line.split('\t').collect())
-            .collect())
-    } else {
-        Err(anyhow!("string is not tab-delimited"))
+    #[test]
+    fn test_has_fastx_ext() {
+        assert!(has_fastx_ext("sampleA.fa"));
+        assert!(has_fastx_ext("sampleA.FASTQ"));
+        assert!(has_fastx_ext("sampleA.fq.gz"));
+        assert!(has_fastx_ext("sampleA.fasta.zst"));
+        assert!(!has_fastx_ext("sampleA.txt"));
+        assert!(!has_fastx_ext("sampleA.gz"));
+        assert!(!has_fastx_ext("ACCGTA"));
     }
-}
 
-// Compare provided barcode with a sequence
-pub fn bc_cmp(bc: &[u8], seq: &[u8], mismatch: u8) -> bool {
-    // This wonderful line below compute the number of
-    // character mismatch between two strings
-    bc.iter()
-        .zip(seq.iter())
-        .map(|(a, b)| (a != b) as u8)
-        .sum::<u8>()
-        <= mismatch
-}
+    #[test]
+    fn test_check_output_names() {
+        let fields =
+            split_by_delimiter("AAAA\ta_R1.fq\ta_R2.fq\nCCCC\tDISCARD\tDISCARD", None).unwrap();
+        assert!(check_output_names(&fields).is_ok());
 
-pub fn which_format(filename: &str) -> niffler::send::compression::Format {
-    let raw_in = Box::new(io::BufReader::new(
-        File::open(filename).expect("file should be readable"),
-    ));
+        let fields = split_by_delimiter("AAAA\ta.fq\nCCCC\tsampleA.txt", None).unwrap();
+        let err = check_output_names(&fields).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("barcode file line 2: output file 'sampleA.txt' of barcode CCCC"));
 
-    let (_, compression) = niffler::send::sniff(raw_in).expect("cannot");
+        let fields = split_by_delimiter("#barcode\toutput\nAAAA\ta.fq\nCCCC\tc.txt", None).unwrap();
+        let err = check_output_names(&fields).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("barcode file line 3: output file 'c.txt' of barcode CCCC"));
+    }
 
-    compression
-}
+    #[test]
+    fn test_write_stats_tsv() {
+        let fields = split_by_tab("AAAA\ta_R1.fq\ta_R2.fq\nCCCC\tc_R1.fq\tc_R2.fq").unwrap();
+        let mut stats: HashMap<&[u8], u32> = HashMap::new();
+        stats.insert(b"AAAA", 1);
+        stats.insert(b"CCCC", 6);
+        stats.insert(b"XXX", 3);
+        stats.insert(crate::demux::AMBIGUOUS, 2);
+        stats.insert(crate::demux::REVCOMP, 4);
+        stats.insert(crate::demux::TOO_SHORT, 10);
 
-// Write to provided data to a fasta file in append mode
-pub fn write_seqs<'a>(
-    file: &'a std::fs::File,
-    compression: niffler::send::compression::Format,
-    record: &'a needletail::parser::SequenceRecord,
-    level: niffler::Level,
-) -> anyhow::Result<()> {
-    let mut handle = niffler::send::get_writer(Box::new(file), compression, level)?;
-
-    match record.format() {
-        needletail::parser::Format::Fasta => needletail::parser::write_fasta(
-            record.id(),
-            &record.seq(),
-            &mut handle,
-            needletail::parser::LineEnding::Unix,
-        )?,
-        needletail::parser::Format::Fastq => needletail::parser::write_fastq(
-            record.id(),
-            &record.seq(),
-            record.qual(),
-            &mut handle,
-            needletail::parser::LineEnding::Unix,
-        )?,
+        let mut out = Vec::new();
+        write_stats_tsv(
+            &mut out,
+            &fields,
+            &stats,
+            &["unknown_R1.fa", "unknown_R2.fa"],
+        )
+        .unwrap();
+        // dropped reads are part of the total, as in the log
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "barcode\tforward_file\treverse_file\tread_count\tpercent_of_total\n\
+            CCCC\tc_R1.fq\tc_R2.fq\t6\t30.00\n\
+            AAAA\ta_R1.fq\ta_R2.fq\t1\t5.00\n\
+            XXX\tunknown_R1.fa\tunknown_R2.fa\t3\t15.00\n"
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_revcomp() {
+        assert_eq!(revcomp(b"ACCGTN"), b"NACGGT");
+        assert_eq!(revcomp(b"AAXT"), b"ANTT");
+        assert_eq!(revcomp(b""), b"");
+        assert_eq!(revcomp(b"accgtn"), b"NACGGT");
+    }
 
-// Tests --------------------------------------------------------------------
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a.fq"), "\"a.fq\"");
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
 
     #[test]
-    fn test_create_relpath_from() {
+    fn test_write_report_json() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.fq"), "@r1\nACGT\n+\nIIII\n").unwrap();
+        fs::write(dir.path().join("unknown.fa"), ">r2\nTTTT\n").unwrap();
+        let fields = split_by_tab("AAAA\ta.fq\nCCCC\tc.fq\nGGGG\tDISCARD").unwrap();
+        let mut stats: HashMap<Vec<u8>, u32> = HashMap::new();
+        stats.insert(b"AAAA".to_vec(), 3);
+        stats.insert(b"GGGG".to_vec(), 1);
+        stats.insert(b"XXX".to_vec(), 4);
+        stats.insert(crate::demux::AMBIGUOUS.to_vec(), 2);
+
+        let report = RunReport {
+            barcode_file: "barcode.txt",
+            inputs: vec!["reads.fq"],
+            outdir: dir.path(),
+            formats: &[niffler::send::compression::Format::No],
+            unknown_files: &["unknown.fa"],
+        };
+        let mut out = Vec::new();
+        report
+            .write_json(
+                &mut out,
+                &fields,
+                &stats,
+                &PhaseTimings::new(Instant::now()),
+            )
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+
+        let mut keys = json.as_object().unwrap().keys().collect::<Vec<_>>();
+        keys.sort();
         assert_eq!(
-            create_relpath_from(
-                &mut PathBuf::from("path"),
-                "file",
-                niffler::send::compression::Format::Gzip
-            ),
-            PathBuf::from("path/file.gz")
+            keys,
+            ["run", "samples", "schema_version", "total_reads", "unknown"]
+        );
+        assert_eq!(json["schema_version"], REPORT_SCHEMA_VERSION);
+        assert_eq!(json["run"]["mode"], "single-end");
+        assert_eq!(json["run"]["inputs"][0], "reads.fq");
+        assert_eq!(json["run"]["info"]["version"], env!("CARGO_PKG_VERSION"));
+
+        let samples = json["samples"].as_array().unwrap();
+        assert_eq!(samples.len(), 3);
+        assert_eq!(samples[0]["barcode"], "AAAA");
+        assert_eq!(samples[0]["reads"], 3);
+        assert_eq!(samples[0]["percent"], 37.5);
+        assert_eq!(samples[0]["files"][0]["size"], 16);
+        assert!(samples[0]["files"][0]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("a.fq"));
+        assert_eq!(samples[1]["reads"], 0);
+        assert!(samples[1]["files"][0]["size"].is_null());
+        assert_eq!(samples[2]["files"].as_array().unwrap().len(), 0);
+
+        assert_eq!(json["unknown"]["reads"], 4);
+        assert_eq!(json["unknown"]["ambiguous"], 2);
+        assert_eq!(json["unknown"]["files"][0]["size"], 9);
+
+        // sample and unknown reads add up to the total
+        let reads = samples
+            .iter()
+            .map(|sample| sample["reads"].as_u64().unwrap())
+            .sum::<u64>();
+        assert_eq!(reads + 4, json["total_reads"].as_u64().unwrap());
+    }
+
+    #[test]
+    fn test_write_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["a_R1.fq", "a_R2.fq", "unknown_R1.fq"].iter() {
+            fs::write(dir.path().join(name), "@r1\nACGT\n+\nIIII\n").unwrap();
+        }
+        let fields =
+            split_by_tab("AAAA\ta_R1.fq\ta_R2.fq\nCCCC\tc_R1.fq\tc_R2.fq\nGGGG\tDISCARD\tDISCARD")
+                .unwrap();
+        let formats = [niffler::send::compression::Format::No; 2];
+        let report = RunReport {
+            barcode_file: "barcode.txt",
+            inputs: vec!["r1.fq", "r2.fq"],
+            outdir: dir.path(),
+            formats: &formats,
+            unknown_files: &["unknown_R1.fq", "unknown_R2.fq"],
+        };
+        let mut out = Vec::new();
+        report.write_manifest(&mut out, &fields).unwrap();
+
+        let outdir = fs::canonicalize(dir.path()).unwrap();
+        let path = |name: &str| outdir.join(name).to_string_lossy().into_owned();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "barcode\tforward_path\treverse_path\n\
+                AAAA\t{}\t{}\n\
+                XXX\t{}\t\n",
+                path("a_R1.fq"),
+                path("a_R2.fq"),
+                path("unknown_R1.fq")
+            )
+        );
+    }
+
+    #[test]
+    fn test_write_plan() {
+        let fields = split_by_tab("AAAA\ta_R1.fq\ta_R2.fq\nCCCC\tDISCARD\tDISCARD").unwrap();
+        let mut out = Vec::new();
+        write_plan(
+            &mut out,
+            &fields,
+            Path::new("out"),
+            &[
+                niffler::send::compression::Format::Gzip,
+                niffler::send::compression::Format::No,
+            ],
+            &["unknown_R1.fa", "unknown_R2.fa"],
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "AAAA\tout/a_R1.fq.gz\tgz\n\
+            AAAA\tout/a_R2.fq\tnone\n\
+            CCCC\tDISCARD\t-\n\
+            XXX\tout/unknown_R1.fa.gz\tgz\n\
+            XXX\tout/unknown_R2.fa\tnone\n"
         );
     }
 
+    #[test]
+    fn test_with_ext_of() {
+        assert_eq!(with_ext_of("pool", "sample1.fq"), "pool.fq");
+        assert_eq!(with_ext_of("pool", "sample1"), "pool");
+    }
+
+    #[test]
+    fn test_pool_low_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let rich = dir.path().join("rich.fa");
+        let poor = dir.path().join("poor.fa");
+        fs::write(&rich, ">r1\nACGT\n>r2\nACGT\n").unwrap();
+        fs::write(&poor, ">p1\nTTTT\n").unwrap();
+
+        let mut stats: HashMap<&[u8], u32> = HashMap::new();
+        stats.insert(b"AAAA", 10);
+        stats.insert(b"CCCC", 1);
+        let samples: Vec<(&[u8], Vec<PathBuf>)> =
+            vec![(b"AAAA", vec![rich.clone()]), (b"CCCC", vec![poor.clone()])];
+
+        let pools = [dir.path().join("low_count_pool.fa")];
+        let pooled = pool_low_count(&stats, &samples, 5, &pools).unwrap();
+        assert_eq!(pooled, vec![&b"CCCC"[..]]);
+        assert!(rich.exists());
+        assert!(!poor.exists());
+        assert_eq!(fs::read_to_string(&pools[0]).unwrap(), ">p1\nTTTT\n");
+    }
+
+    #[test]
+    fn test_total_records() {
+        let stats: HashMap<&[u8], u32> = [
+            (&b"ACCGTA"[..], 6),
+            (b"XXX", 3),
+            (crate::demux::TOO_SHORT, 1),
+            (crate::demux::AMBIGUOUS, 2),
+            (crate::demux::LENGTH_ANOMALY, 5),
+            (crate::demux::REVCOMP, 4),
+            (b"unknown_len0-19", 3),
+        ]
+        .iter()
+        .copied()
+        .collect();
+        assert_eq!(total_records(stats.iter().map(|(key, n)| (*key, *n))), 15);
+    }
+
+    #[test]
+    fn test_hamming_distance() {
+        assert_eq!(hamming_distance(b"ATCG", b"ATCGATCG"), 0);
+        assert_eq!(hamming_distance(b"AACG", b"ATCGATCG"), 1);
+        assert_eq!(hamming_distance(b"ANNG", b"atcg"), 0);
+        assert_eq!(hamming_distance(b"TGCA", b"ATCG"), 3);
+        // long barcodes do not overflow
+        assert_eq!(hamming_distance(&[b'A'; 300], &[b'C'; 300]), 300);
+        assert!(!bc_cmp(&[b'A'; 300], &[b'C'; 300], 255));
+    }
+
     #[test]
     fn test_bc_cmp_ok() {
         let seq = b"ATCGATCGATCG";
@@ -217,6 +3596,78 @@ mod tests {
         assert!(!bc_cmp(bc, seq, 0));
     }
 
+    #[test]
+    fn test_hamming_distance_n_wildcard() {
+        let seq = b"ATCGATCGATCG";
+
+        // middle, start and end of the barcode
+        assert_eq!(hamming_distance(b"ATNG", seq), 0);
+        assert_eq!(hamming_distance(b"NTCG", seq), 0);
+        assert_eq!(hamming_distance(b"ATCN", seq), 0);
+        assert_eq!(hamming_distance(b"ANNN", seq), 0);
+        assert_eq!(hamming_distance(b"atng", seq), 0);
+        // an N in the read is not a wildcard
+        assert_eq!(hamming_distance(b"ATCG", b"ANCGATCG"), 1);
+        // N positions are not counted as mismatches
+        assert_eq!(hamming_distance(b"NACG", seq), 1);
+        assert_eq!(hamming_distance(b"NAAG", seq), 2);
+        assert_eq!(hamming_distance(b"NNNN", seq), 0);
+    }
+
+    #[test]
+    fn test_hamming_distance_mixed_case() {
+        assert_eq!(hamming_distance(b"ATCG", b"atcgatcg"), 0);
+        assert_eq!(hamming_distance(b"atcg", b"ATCGATCG"), 0);
+        assert_eq!(hamming_distance(b"aTcG", b"AtCgATCG"), 0);
+        // case does not change the mismatch count
+        assert_eq!(hamming_distance(b"aacg", b"ATCGATCG"), 1);
+        assert_eq!(hamming_distance(b"aacc", b"atcgatcg"), 2);
+        assert_eq!(hamming_distance(b"ATCG", b"atcAatcg"), 1);
+        assert_eq!(hamming_distance(b"tgca", b"ATCGATCG"), 3);
+    }
+
+    #[test]
+    fn test_levenshtein_distance_mixed_case() {
+        assert_eq!(levenshtein_distance(b"atcgat", b"ATCGATGGG", 1), 0);
+        assert_eq!(levenshtein_distance(b"ATCGAT", b"acgatgggg", 1), 1);
+        assert!(levenshtein_distance(b"atcgat", b"aggccatggg", 1) > 1);
+    }
+
+    #[test]
+    fn test_is_discard() {
+        assert!(is_discard("DISCARD"));
+        assert!(is_discard("/dev/null"));
+        assert!(!is_discard("discard.fq"));
+    }
+
+    #[test]
+    fn test_levenshtein_distance() {
+        // deletion of the second base of the barcode in the read
+        assert_eq!(levenshtein_distance(b"ATCGAT", b"ACGATGGGG", 1), 1);
+        assert!(hamming_distance(b"ATCGAT", b"ACGATGGGG") > 1);
+        // insertion in the read
+        assert_eq!(levenshtein_distance(b"ATCGAT", b"ATTCGATGGG", 1), 1);
+        assert!(levenshtein_distance(b"ATCGAT", b"AGGCCATGGG", 1) > 1);
+        // read too short to hold the barcode
+        assert_eq!(levenshtein_distance(b"ATCGAT", b"ATC", 1), u32::MAX);
+    }
+
+    #[test]
+    fn test_bc_cmp_levenshtein() {
+        assert!(bc_cmp_levenshtein(b"ATCGAT", b"ACGATGGGG", 1));
+        assert!(!bc_cmp_levenshtein(b"ATCGAT", b"ATC", 1));
+    }
+
+    #[test]
+    fn test_levenshtein_distance_n_wildcard() {
+        assert_eq!(levenshtein_distance(b"ACNGTA", b"ACTGTAGG", 1), 0);
+        assert_eq!(levenshtein_distance(b"NNNNNN", b"ACTGTAGG", 1), 0);
+        // deletion next to the N
+        assert_eq!(levenshtein_distance(b"ACNGTA", b"ACGTAGGG", 1), 1);
+        // an N in the read is not a wildcard
+        assert_eq!(levenshtein_distance(b"ACTGTA", b"ACNGTAGG", 1), 1);
+    }
+
     #[test]
     fn test_split_by_tab() {
         let mystring = "Hello\tWorld\tEarth\nBrian\twas\tthere";
@@ -227,23 +3678,201 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_by_tab_comma() {
+        let fields = split_by_tab("AAAA,a_R1.fq,a_R2.fq\nCCCC,c_R1.fq,c_R2.fq").unwrap();
+        assert_eq!(
+            fields,
+            [
+                ["AAAA", "a_R1.fq", "a_R2.fq"],
+                ["CCCC", "c_R1.fq", "c_R2.fq"]
+            ]
+        );
+
+        // tabs win over commas, which can be part of a field
+        let fields = split_by_tab("AAAA\ta_R1.fq,a_R2.fq").unwrap();
+        assert_eq!(fields, [["AAAA", "a_R1.fq,a_R2.fq"]]);
+    }
+
+    #[test]
+    fn test_split_by_delimiter() {
+        let fields = split_by_delimiter("AAAA,a.fq", Some(',')).unwrap();
+        assert_eq!(fields, [(1, vec!["AAAA", "a.fq"])]);
+        assert!(split_by_delimiter("AAAA,a.fq", Some('\t')).is_err());
+        assert!(split_by_delimiter("AAAA\ta.fq", Some(',')).is_err());
+        assert_eq!(to_delimiter("comma"), ',');
+        assert_eq!(to_delimiter("tab"), '\t');
+    }
+
+    #[test]
+    fn test_panel_hash() {
+        let panel = split_by_tab("AAAA\ta.fq\nCCCC\tc.fq").unwrap();
+        let reordered = split_by_tab("CCCC\tc.fq\nAAAA\ta.fq").unwrap();
+        let renamed = split_by_tab("AAAA\ta.fq\nCCCC\tc2.fq").unwrap();
+        let moved = split_by_tab("AAAA\tc.fq\nCCCC\ta.fq").unwrap();
+
+        assert_eq!(panel_hash(&panel), 0x880e_9dbd_2426_3bb7);
+        assert_eq!(panel_hash(&panel), panel_hash(&reordered));
+        assert_ne!(panel_hash(&panel), panel_hash(&renamed));
+        assert_ne!(panel_hash(&panel), panel_hash(&moved));
+    }
+
+    #[test]
+    fn test_split_by_tab_crlf() {
+        let fields = split_by_tab("AAAA\ta.fq\r\nCCCC\tc.fq\r\n\r\n").unwrap();
+        assert_eq!(fields, [["AAAA", "a.fq"], ["CCCC", "c.fq"]]);
+    }
+
+    #[test]
+    fn test_split_by_tab_crlf_fields() {
+        let fields = split_by_tab("bc\tfile.fq\r\n").unwrap();
+        assert_eq!(fields, [["bc", "file.fq"]]);
+
+        // a \r inside a line and spaces around CSV fields
+        let fields = split_by_tab("AAAA\r\ta.fq").unwrap();
+        assert_eq!(fields, [["AAAA", "a.fq"]]);
+        let fields = split_by_tab("AAAA, a.fq\r\nCCCC ,c.fq").unwrap();
+        assert_eq!(fields, [["AAAA", "a.fq"], ["CCCC", "c.fq"]]);
+    }
+
+    #[test]
+    fn test_split_by_tab_comments() {
+        let fields =
+            split_by_tab("#barcode\toutput\nAAAA\ta.fq\n\n# controls\nCCCC\tc.fq\n\n").unwrap();
+        assert_eq!(fields, [["AAAA", "a.fq"], ["CCCC", "c.fq"]]);
+
+        // a tab in a comment alone does not make a file tab-delimited
+        let fields = split_by_tab("#barcode\toutput\nAAAA,a.fq").unwrap();
+        assert_eq!(fields, [["AAAA", "a.fq"]]);
+    }
+
+    #[test]
+    fn test_select_samples() {
+        let fields = || split_by_tab("AAAA\ta.fq\nCCCC\tc.fq.gz\nGGGG\tg.fq").unwrap();
+        let only = vec!["c".to_string(), "GGGG".to_string()];
+
+        let selected = select_samples(fields(), &only, false).unwrap();
+        assert_eq!(selected, [["CCCC", "c.fq.gz"], ["GGGG", "g.fq"]]);
+
+        let selected = select_samples(fields(), &only, true).unwrap();
+        assert_eq!(
+            selected,
+            [["AAAA", "DISCARD"], ["CCCC", "c.fq.gz"], ["GGGG", "g.fq"]]
+        );
+
+        assert!(select_samples(fields(), &["t.fq".to_string()], false).is_err());
+    }
+
+    #[test]
+    fn test_fastx_ext() {
+        assert_eq!(fastx_ext("reads.fastq.gz"), Some("fq"));
+        assert_eq!(fastx_ext("dir/reads.FQ"), Some("fq"));
+        assert_eq!(fastx_ext("reads.fasta.zst"), Some("fa"));
+        assert_eq!(fastx_ext("reads.fa"), Some("fa"));
+        assert_eq!(fastx_ext("reads.txt.gz"), None);
+        assert_eq!(fastx_ext("-"), None);
+    }
+
+    #[test]
+    fn test_shuffle_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let fasta = |name: &str, mate: u8| {
+            let path = dir.path().join(name);
+            let mut file = File::create(&path).unwrap();
+            for i in 0..200 {
+                writeln!(file, ">r{}/{}\nACGT", i, mate).unwrap();
+            }
+            path
+        };
+        let ids = |path: &Path| {
+            fs::read_to_string(path)
+                .unwrap()
+                .lines()
+                .filter(|line| line.starts_with('>'))
+                .map(|line| line.split('/').next().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let no = niffler::send::compression::Format::No;
+        let shuffled = |name: &str, seed: u64, max_memory: Option<usize>| {
+            let paths = [
+                fasta(&format!("{}_1.fa", name), 1),
+                fasta(&format!("{}_2.fa", name), 2),
+            ];
+            shuffle_outputs(
+                &paths,
+                &[no, no],
+                niffler::Level::One,
+                &Encoders::default(),
+                seed,
+                max_memory,
+                Some(dir.path()),
+            )
+            .unwrap();
+            (ids(&paths[0]), ids(&paths[1]))
+        };
+
+        let (first, mates) = shuffled("a", 7, None);
+        assert_eq!(first, mates);
+        assert_eq!(shuffled("b", 7, None).0, first);
+        assert_ne!(shuffled("c", 8, None).0, first);
+        let mut sorted = first.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 200);
+
+        // spilled to temporary files, mates still in step
+        let (spilled, mates) = shuffled("d", 7, Some(500));
+        assert_eq!(spilled, mates);
+        assert_eq!(shuffled("e", 7, Some(500)).0, spilled);
+        assert_eq!(spilled.len(), 200);
+        assert_eq!(
+            fs::read_dir(dir.path()).unwrap().count(),
+            10,
+            "spill directories are removed"
+        );
+    }
+
     #[test]
     fn test_split_by_tab_not_ok() {
         let mystring = "HelloWorldEarth\nBrianwasthere";
-        assert_eq!(split_by_tab(mystring).is_err(), true);
+        assert!(split_by_tab(mystring).is_err());
+    }
+
+    #[test]
+    fn test_compare_panels() {
+        let old = split_by_tab("AAAA\ta.fq\nCCCC\tc.fq\nGGGG\tg.fq").unwrap();
+        let new = split_by_tab("AAAA\ta.fq\nCCCC\tc2.fq\nTTTT\tt.fq").unwrap();
+        let diff = compare_panels(&old, &new);
+
+        assert_eq!(diff.added, vec![("TTTT", &["t.fq"][..])]);
+        assert_eq!(diff.removed, vec![("GGGG", &["g.fq"][..])]);
+        assert_eq!(diff.changed, vec![("CCCC", &["c.fq"][..], &["c2.fq"][..])]);
+    }
+
+    #[test]
+    fn test_normalize_mate_id() {
+        assert_eq!(normalize_mate_id(b"read1/1"), b"read1");
+        assert_eq!(normalize_mate_id(b"read1/2"), b"read1");
+        assert_eq!(
+            normalize_mate_id(b"M02542:42:1:1114:5753:11440 2:N:0:3"),
+            b"M02542:42:1:1114:5753:11440"
+        );
+        assert_eq!(normalize_mate_id(b"read1/3"), b"read1/3");
     }
 
     #[test]
     fn test_to_niffler_level() {
-        assert_eq!(to_niffler_level(1), niffler::Level::One);
-        assert_eq!(to_niffler_level(2), niffler::Level::Two);
-        assert_eq!(to_niffler_level(3), niffler::Level::Three);
-        assert_eq!(to_niffler_level(4), niffler::Level::Four);
-        assert_eq!(to_niffler_level(5), niffler::Level::Five);
-        assert_eq!(to_niffler_level(6), niffler::Level::Six);
-        assert_eq!(to_niffler_level(7), niffler::Level::Seven);
-        assert_eq!(to_niffler_level(8), niffler::Level::Eight);
-        assert_eq!(to_niffler_level(9), niffler::Level::Nine);
+        assert_eq!(to_niffler_level(1).unwrap(), niffler::Level::One);
+        assert_eq!(to_niffler_level(2).unwrap(), niffler::Level::Two);
+        assert_eq!(to_niffler_level(3).unwrap(), niffler::Level::Three);
+        assert_eq!(to_niffler_level(4).unwrap(), niffler::Level::Four);
+        assert_eq!(to_niffler_level(5).unwrap(), niffler::Level::Five);
+        assert_eq!(to_niffler_level(6).unwrap(), niffler::Level::Six);
+        assert_eq!(to_niffler_level(7).unwrap(), niffler::Level::Seven);
+        assert_eq!(to_niffler_level(8).unwrap(), niffler::Level::Eight);
+        assert_eq!(to_niffler_level(9).unwrap(), niffler::Level::Nine);
+        assert!(to_niffler_level(0).is_err());
+        assert!(to_niffler_level(12).is_err());
     }
 
     #[test]
@@ -294,6 +3923,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_format_from_ext() {
+        assert_eq!(
+            format_from_ext("missing/reads.fa.zst"),
+            Some(niffler::send::compression::Format::Zstd)
+        );
+        assert_eq!(format_from_ext("tests/reads.txt"), None);
+        assert_eq!(format_from_ext("reads"), None);
+        assert_eq!(
+            format_from_ext("reads.FQ"),
+            Some(niffler::send::compression::Format::No)
+        );
+    }
+
+    #[test]
+    fn test_version_json() {
+        let json: serde_json::Value = serde_json::from_str(&version_json("0.6.0")).unwrap();
+        assert_eq!(json["name"], "sabreur");
+        assert_eq!(json["version"], "0.6.0");
+        let features = json["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|f| f.as_str().unwrap())
+            .collect::<Vec<_>>();
+        // niffler default backends
+        assert_eq!(features[..4], ["gz", "bz2", "xz", "zst"]);
+        assert_eq!(features.contains(&"ubam"), cfg!(feature = "ubam"));
+    }
+
+    #[test]
+    fn test_throughput() {
+        assert_eq!(
+            throughput(3000, Some(5_000_000), Duration::from_secs(2)),
+            "Processed 3000 records: 1500 records/s, 2.50 MB/s"
+        );
+        assert_eq!(
+            throughput(3, None, Duration::ZERO),
+            "Processed 3 records: 3000 records/s"
+        );
+    }
+
+    #[test]
+    fn test_sniff_format() {
+        let opened = OpenedInputs::default();
+        assert_eq!(
+            sniff_format("missing/reads.fa.gz", true, None, &opened).unwrap(),
+            niffler::send::compression::Format::Gzip
+        );
+        assert!(opened.take("missing/reads.fa.gz").is_none());
+        assert!(sniff_format("missing/reads.fa.gz", false, None, &opened).is_err());
+
+        // the sniffed input is kept open, its sniffed bytes included
+        let counter = ByteCounter::default();
+        assert_eq!(
+            sniff_format("tests/test.fa.gz", false, Some(&counter), &opened).unwrap(),
+            niffler::send::compression::Format::Gzip
+        );
+        let mut content = String::new();
+        opened
+            .take("tests/test.fa.gz")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert!(content.starts_with('>'));
+        assert_eq!(
+            counter.get(),
+            fs::metadata("tests/test.fa.gz").unwrap().len()
+        );
+        assert!(opened.take("tests/test.fa.gz").is_none());
+    }
+
     #[test]
     fn test_which_format() {
         assert_eq!(
@@ -312,6 +4013,7 @@ mod tests {
             which_format("tests/test.fq.zst"),
             niffler::send::compression::Format::Zstd
         );
+        assert_eq!(which_format(STDIN), niffler::send::compression::Format::No);
     }
 
     /*